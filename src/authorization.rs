@@ -1,7 +1,53 @@
-use crate::types::{AuthorizedTransaction, Output, Transaction};
-use sdk_authorization_ed25519_dalek::{authorize, Keypair};
+use crate::hashes::{hash, Hash, Key};
+use crate::types::{AuthorizedTransaction, Body, Output, Transaction};
+use crate::validation::{BitNamesError, BitNamesState, Error};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{PublicKey, Signature, Signer as _, Verifier as _};
+use sdk_authorization_ed25519_dalek::{authorize, get_address, verify_authorizations, Keypair};
 use sdk_types::{Address, GetAddress as _};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+/// Address that outputs authorizable by the holder of `pubkey` are paid to.
+pub fn address_from_pubkey(pubkey: &PublicKey) -> Address {
+    get_address(pubkey)
+}
+
+/// Signed claim that the holder of `public_key` controls `key`, checkable
+/// against the chain without registering or disclosing anything. A proof
+/// stays valid for as long as the key doesn't move.
+#[derive(Debug, Clone)]
+pub struct ControlProof {
+    pub key: Key,
+    pub public_key: PublicKey,
+    pub signature: Signature,
+}
+
+/// Domain separated so a control proof can't double as a transaction
+/// signature.
+fn control_message(key: &Key) -> Hash {
+    hash(&("bitnames-control", key))
+}
+
+pub fn prove_control(keypair: &Keypair, key: Key) -> ControlProof {
+    ControlProof {
+        key,
+        public_key: keypair.public,
+        signature: keypair.sign(&control_message(&key)),
+    }
+}
+
+/// Check that `proof` is signed by the address currently holding its key.
+pub fn verify_control(state: &BitNamesState, proof: &ControlProof) -> Result<(), Error> {
+    let key = proof.key;
+    proof
+        .public_key
+        .verify(&control_message(&key), &proof.signature)
+        .map_err(|_| BitNamesError::InvalidControlProof { key })?;
+    if state.owner_of(&key)? != Some(address_from_pubkey(&proof.public_key)) {
+        Err(BitNamesError::NotController { key })?;
+    }
+    Ok(())
+}
 
 pub fn authorize_transaction(
     keypairs: &HashMap<Address, Keypair>,
@@ -17,3 +63,260 @@ pub fn authorize_transaction(
         .collect();
     authorize(&addresses_keypairs, transaction).unwrap()
 }
+
+/// Same verdict as `verify_authorizations`, but checks all signatures of the
+/// body in one batch. Only a failed batch falls back to checking them one by
+/// one, which finds the culprit and reports the usual error.
+///
+/// The batch equation and a single `verify` only agree for points in the
+/// prime order subgroup: a torsion component in a public key or a
+/// signature's R can make the batch accept what `verify` rejects. Bodies
+/// with such points, or whose authorizations don't line up with the
+/// inputs, are checked one by one instead, so the verdict never depends on
+/// the verifier a node runs.
+pub fn verify_authorizations_batch(
+    body: &Body,
+) -> Result<(), sdk_authorization_ed25519_dalek::Error> {
+    let mut messages = vec![];
+    let mut signatures = vec![];
+    let mut public_keys = vec![];
+    let mut checked_keys = HashSet::new();
+    let mut authorizations = body.authorizations.iter();
+    for transaction in &body.transactions {
+        let message = sdk_types::hash(transaction);
+        for _ in &transaction.inputs {
+            let Some(authorization) = authorizations.next() else {
+                return verify_authorizations(body);
+            };
+            let signature: &[u8] = authorization.signature.as_ref();
+            let key_ok = !checked_keys.insert(*authorization.public_key.as_bytes())
+                || is_torsion_free(authorization.public_key.as_bytes());
+            if !key_ok || !is_torsion_free(&signature[..32]) {
+                return verify_authorizations(body);
+            }
+            messages.push(message);
+            signatures.push(authorization.signature);
+            public_keys.push(authorization.public_key);
+        }
+    }
+    if authorizations.next().is_some() {
+        return verify_authorizations(body);
+    }
+    let messages: Vec<&[u8]> = messages.iter().map(|message| message.as_slice()).collect();
+    if ed25519_dalek::verify_batch(&messages, &signatures, &public_keys).is_ok() {
+        return Ok(());
+    }
+    verify_authorizations(body)
+}
+
+/// Whether `point` decompresses to a point of the prime order subgroup.
+/// Points that don't decompress at all are left to `verify` to reject.
+fn is_torsion_free(point: &[u8]) -> bool {
+    match CompressedEdwardsY::from_slice(point).decompress() {
+        Some(point) => point.is_torsion_free(),
+        None => true,
+    }
+}
+
+/// Same verdict as `verify_authorizations`, with every transaction's
+/// authorizations verified on its own rayon task. The error reported is the
+/// one of the first failing transaction, as with the serial path.
+#[cfg(feature = "parallel")]
+pub fn verify_authorizations_parallel(
+    body: &Body,
+) -> Result<(), sdk_authorization_ed25519_dalek::Error> {
+    use rayon::prelude::*;
+    let mut transactions = vec![];
+    let mut authorizations = body.authorizations.iter();
+    for transaction in &body.transactions {
+        let authorizations: Vec<_> = authorizations
+            .by_ref()
+            .take(transaction.inputs.len())
+            .cloned()
+            .collect();
+        if authorizations.len() != transaction.inputs.len() {
+            return verify_authorizations(body);
+        }
+        transactions.push(AuthorizedTransaction {
+            transaction: transaction.clone(),
+            authorizations,
+        });
+    }
+    if authorizations.next().is_some() {
+        return verify_authorizations(body);
+    }
+    let results: Vec<_> = transactions
+        .into_par_iter()
+        .map(|transaction| verify_authorizations(&Body::new(vec![transaction], vec![])))
+        .collect();
+    results.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+    use crate::types::{BitNamesOutput, Content, OutPoint};
+    use std::time::Instant;
+
+    /// Body of `count` signed transactions, each paying a deposit on to
+    /// another address.
+    fn signed_body(chain: &mut TestChain, count: usize) -> Body {
+        let transactions = (0..count)
+            .map(|_| {
+                let deposit: OutPoint = chain.deposit(chain.addresses[0], 10);
+                let output = Output {
+                    address: chain.addresses[1],
+                    content: Content::Value(10),
+                };
+                chain.sign(vec![deposit], vec![output])
+            })
+            .collect();
+        Body::new(transactions, vec![])
+    }
+
+    /// Every verifier's verdict on `body`, the reference one first.
+    fn verdicts(body: &Body) -> Vec<Result<(), String>> {
+        let verdict = |result: Result<(), sdk_authorization_ed25519_dalek::Error>| {
+            result.map_err(|err| format!("{err:?}"))
+        };
+        let mut verdicts = vec![
+            verdict(verify_authorizations(body)),
+            verdict(verify_authorizations_batch(body)),
+        ];
+        #[cfg(feature = "parallel")]
+        verdicts.push(verdict(verify_authorizations_parallel(body)));
+        verdicts
+    }
+
+    #[test]
+    fn verifiers_agree() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let mut body = signed_body(&mut chain, 4);
+        let accepted = verdicts(&body);
+        assert!(accepted.iter().all(|verdict| *verdict == Ok(())));
+
+        // Changing an output after signing invalidates the transaction's
+        // authorizations.
+        body.transactions[2].outputs[0].content = Content::Value(9);
+        let rejected = verdicts(&body);
+        assert!(rejected[0].is_err());
+        assert!(rejected.iter().all(|verdict| *verdict == rejected[0]));
+        body.transactions[2].outputs[0].content = Content::Value(10);
+
+        // So does handing one transaction's authorizations to another.
+        body.authorizations.swap(0, 1);
+        let rejected = verdicts(&body);
+        assert!(rejected[0].is_err());
+        assert!(rejected.iter().all(|verdict| *verdict == rejected[0]));
+        body.authorizations.swap(0, 1);
+
+        // Authorizations beyond the last input are left to the serial
+        // verifier too.
+        let extra = body.authorizations[0].clone();
+        body.authorizations.push(extra);
+        let leftover = verdicts(&body);
+        assert!(leftover.iter().all(|verdict| *verdict == leftover[0]));
+        body.authorizations.pop();
+    }
+
+    #[test]
+    fn verifiers_agree_on_torsion_points() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let mut body = signed_body(&mut chain, 2);
+        // The identity as public key and the point of order 2 as R, with
+        // s = 0. `verify` rejects it, while the batch equation holds for
+        // every other choice of its random coefficients.
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        let mut order_two = [0xff; 32];
+        order_two[0] = 0xec;
+        order_two[31] = 0x7f;
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&order_two);
+        let authorization = &mut body.authorizations[1];
+        authorization.public_key = PublicKey::from_bytes(&identity).unwrap();
+        authorization.signature = Signature::try_from(&signature[..]).unwrap();
+        for _ in 0..16 {
+            let verdicts = verdicts(&body);
+            assert!(verdicts[0].is_err());
+            assert!(verdicts.iter().all(|verdict| *verdict == verdicts[0]));
+        }
+    }
+
+    #[test]
+    fn authorizations_are_checked_against_address_from_pubkey() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let body = signed_body(&mut chain, 1);
+        let authorization = &body.authorizations[0];
+        assert_eq!(address_from_pubkey(&authorization.public_key), alice);
+        verify_authorizations(&body).unwrap();
+
+        // Bob's keypair filed under Alice's address signs for an address
+        // that isn't the one the spent utxo is paid to.
+        let bob_keypair = &chain.keypairs[&bob];
+        assert_ne!(address_from_pubkey(&bob_keypair.public), alice);
+        let impostor = Keypair::from_bytes(&bob_keypair.to_bytes()).unwrap();
+        let keypairs = HashMap::from([(alice, impostor)]);
+        let deposit = chain.deposit(alice, 10);
+        let spent_utxos = vec![chain.state.get_utxo(&deposit).unwrap().unwrap()];
+        let transaction = Transaction {
+            inputs: vec![deposit],
+            outputs: vec![Output {
+                address: bob,
+                content: Content::Value(10),
+            }],
+        };
+        let forged = authorize_transaction(&keypairs, &spent_utxos, transaction);
+        assert!(verify_authorizations(&Body::new(vec![forged], vec![])).is_err());
+    }
+
+    #[test]
+    fn control_proofs_follow_the_holder() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let key = key_of("held.com");
+        let name = chain.register(alice, "held.com", 1).unwrap();
+        let proof = prove_control(&chain.keypairs[&alice], key);
+        verify_control(&chain.state, &proof).unwrap();
+        assert!(matches!(
+            verify_control(&chain.state, &prove_control(&chain.keypairs[&bob], key)),
+            Err(Error::BitNames(BitNamesError::NotController { .. }))
+        ));
+        let mut forged = proof.clone();
+        forged.key = key_of("other.com");
+        assert!(matches!(
+            verify_control(&chain.state, &forged),
+            Err(Error::BitNames(BitNamesError::InvalidControlProof { .. }))
+        ));
+
+        let transfer = chain.spend_tx(name, bob, BitNamesOutput::Transfer { key, value: None });
+        chain.connect(vec![transfer]).unwrap();
+        assert!(matches!(
+            verify_control(&chain.state, &proof),
+            Err(Error::BitNames(BitNamesError::NotController { .. }))
+        ));
+    }
+
+    /// Not a real benchmark harness, the crate is a binary, but enough to
+    /// compare the verifiers. Run with `--release -- --ignored bench`.
+    #[test]
+    #[ignore]
+    fn bench_verifiers() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let body = signed_body(&mut chain, 1000);
+        let time = |name: &str, verify: &dyn Fn(&Body) -> bool| {
+            let start = Instant::now();
+            assert!(verify(&body));
+            println!("{name}: {:?}", start.elapsed());
+        };
+        time("serial", &|body| verify_authorizations(body).is_ok());
+        time("batch", &|body| verify_authorizations_batch(body).is_ok());
+        #[cfg(feature = "parallel")]
+        time("parallel", &|body| {
+            verify_authorizations_parallel(body).is_ok()
+        });
+    }
+}
@@ -0,0 +1,302 @@
+use crate::authorization::authorize_transaction;
+use crate::hashes::*;
+use crate::types::*;
+use sdk_authorization_ed25519_dalek::Keypair;
+use sdk_types::GetValue as _;
+use std::collections::HashMap;
+
+/// Pair `commit_output` with a change output paying everything but `fee`
+/// back to `change_address`. The change is always vout 0 and the commitment
+/// vout 1.
+pub fn commit_with_change(
+    commit_output: Output,
+    change_address: Address,
+    inputs: Vec<OutPoint>,
+    spent_utxos: &[Output],
+    fee: u64,
+) -> Result<Transaction, BuilderError> {
+    let value_in: u64 = spent_utxos.iter().map(|utxo| utxo.get_value()).sum();
+    let value_out = commit_output.get_value() + fee;
+    let change = value_in
+        .checked_sub(value_out)
+        .ok_or(BuilderError::InsufficientFunds {
+            value_in,
+            value_out,
+        })?;
+    let outputs = vec![
+        Output {
+            address: change_address,
+            content: Content::Value(change),
+        },
+        commit_output,
+    ];
+    Ok(Transaction { inputs, outputs })
+}
+
+/// Collects inputs and outputs of a transaction, works out the change and
+/// signs it. Change goes to the last output, and is left out if there is
+/// none.
+#[derive(Debug, Clone)]
+pub struct TransactionBuilder {
+    inputs: Vec<OutPoint>,
+    spent_utxos: Vec<Output>,
+    outputs: Vec<Output>,
+    change_address: Address,
+    fee: u64,
+}
+
+impl TransactionBuilder {
+    pub fn new(change_address: Address) -> Self {
+        Self {
+            inputs: vec![],
+            spent_utxos: vec![],
+            outputs: vec![],
+            change_address,
+            fee: 0,
+        }
+    }
+
+    pub fn add_input(mut self, outpoint: OutPoint, output: Output) -> Self {
+        self.inputs.push(outpoint);
+        self.spent_utxos.push(output);
+        self
+    }
+
+    pub fn fee(mut self, fee: u64) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn pay_to(self, address: Address, value: u64) -> Self {
+        self.output(address, Content::Value(value))
+    }
+
+    /// Commit to `key` under `salt`, which the reveal has to repeat.
+    pub fn commit_name(self, address: Address, key: Key, salt: u64, fee: u64) -> Self {
+        let commitment = blake2b_hmac(&key, salt);
+        self.output(
+            address,
+            Content::Custom(BitNamesOutput::Commitment { commitment, fee }),
+        )
+    }
+
+    /// Bid `amount` for `key` in its auction, the reveal of the bid has to
+    /// repeat `salt`.
+    pub fn bid(self, address: Address, key: Key, salt: u64, amount: u64) -> Self {
+        let commitment = blake2b_hmac(&key, salt);
+        self.output(
+            address,
+            Content::Custom(BitNamesOutput::Bid {
+                key,
+                commitment,
+                amount,
+            }),
+        )
+    }
+
+    /// Reveal `key`, optionally with its first value, the transaction needs
+    /// the commitment as an input.
+    pub fn reveal_name(
+        self,
+        address: Address,
+        salt: u64,
+        key: Key,
+        name: Option<String>,
+        value: Option<Value>,
+    ) -> Self {
+        self.output(
+            address,
+            Content::Custom(BitNamesOutput::Reveal {
+                salt,
+                key,
+                name,
+                path: vec![],
+                value,
+            }),
+        )
+    }
+
+    pub fn set_value(self, address: Address, key: Key, value: Option<Value>) -> Self {
+        self.output(
+            address,
+            Content::Custom(BitNamesOutput::KeyValue { key, value }),
+        )
+    }
+
+    fn output(mut self, address: Address, content: Content<BitNamesOutput>) -> Self {
+        self.outputs.push(Output { address, content });
+        self
+    }
+
+    pub fn build(self) -> Result<(Transaction, Vec<Output>), BuilderError> {
+        let value_in: u64 = self.spent_utxos.iter().map(|utxo| utxo.get_value()).sum();
+        let value_out = self
+            .outputs
+            .iter()
+            .map(|output| output.get_value())
+            .sum::<u64>()
+            + self.fee;
+        let change = value_in
+            .checked_sub(value_out)
+            .ok_or(BuilderError::InsufficientFunds {
+                value_in,
+                value_out,
+            })?;
+        let mut outputs = self.outputs;
+        if change > 0 {
+            outputs.push(Output {
+                address: self.change_address,
+                content: Content::Value(change),
+            });
+        }
+        let transaction = Transaction {
+            inputs: self.inputs,
+            outputs,
+        };
+        Ok((transaction, self.spent_utxos))
+    }
+
+    /// `build`, then authorize every input with the keypair of the address
+    /// it is paid to, all of which have to be in `keypairs`.
+    pub fn build_and_sign(
+        self,
+        keypairs: &HashMap<Address, Keypair>,
+    ) -> Result<AuthorizedTransaction, BuilderError> {
+        let (transaction, spent_utxos) = self.build()?;
+        if let Some(utxo) = spent_utxos
+            .iter()
+            .find(|utxo| !keypairs.contains_key(&utxo.address))
+        {
+            return Err(BuilderError::MissingKeypair {
+                address: utxo.address,
+            });
+        }
+        Ok(authorize_transaction(keypairs, &spent_utxos, transaction))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BuilderError {
+    #[error("inputs worth {value_in} can't cover outputs and fee worth {value_out}")]
+    InsufficientFunds { value_in: u64, value_out: u64 },
+    #[error("no keypair for input address {address:?}")]
+    MissingKeypair { address: Address },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+
+    fn commit_output(chain: &TestChain, fee: u64) -> Output {
+        custom(
+            chain.addresses[0],
+            BitNamesOutput::Commitment {
+                commitment: blake2b_hmac(&key_of("change.com"), 1),
+                fee,
+            },
+        )
+    }
+
+    #[test]
+    fn commit_with_change_pays_back_the_rest() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (owner, change) = (chain.addresses[0], chain.addresses[1]);
+        let funding = chain.deposit(owner, 100);
+        let spent = vec![chain.state.get_utxo(&funding).unwrap().unwrap()];
+        let transaction =
+            commit_with_change(commit_output(&chain, 10), change, vec![funding], &spent, 5)
+                .unwrap();
+        assert_eq!(transaction.inputs, vec![funding]);
+        assert_eq!(transaction.outputs.len(), 2);
+        assert_eq!(transaction.outputs[0].address, change);
+        assert!(matches!(transaction.outputs[0].content, Content::Value(85)));
+        assert!(matches!(
+            transaction.outputs[1].content,
+            Content::Custom(BitNamesOutput::Commitment { fee: 10, .. })
+        ));
+        let signed = authorize_transaction(&chain.keypairs, &spent, transaction);
+        chain
+            .state
+            .validate_transaction(&signed.transaction)
+            .unwrap();
+    }
+
+    #[test]
+    fn commit_with_change_rejects_insufficient_funds() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let funding = chain.deposit(owner, 10);
+        let spent = vec![chain.state.get_utxo(&funding).unwrap().unwrap()];
+        assert!(matches!(
+            commit_with_change(commit_output(&chain, 10), owner, vec![funding], &spent, 1),
+            Err(BuilderError::InsufficientFunds {
+                value_in: 10,
+                value_out: 11
+            })
+        ));
+    }
+
+    #[test]
+    fn builder_registers_a_name() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (owner, change) = (chain.addresses[0], chain.addresses[1]);
+        let key = key_of("built.com");
+        let funding = chain.deposit(owner, 10);
+        let utxo = chain.state.get_utxo(&funding).unwrap().unwrap();
+        let commit = TransactionBuilder::new(change)
+            .add_input(funding, utxo)
+            .commit_name(owner, key, 1, 0)
+            .fee(2)
+            .build_and_sign(&chain.keypairs)
+            .unwrap();
+        let outputs = &commit.transaction.outputs;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[1].address, change);
+        assert!(matches!(outputs[1].content, Content::Value(8)));
+        let commitment = OutPoint::Regular {
+            txid: commit.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![commit]).unwrap();
+
+        let commitment_utxo = chain.state.get_utxo(&commitment).unwrap().unwrap();
+        let value = Some(hash(&"value").into());
+        let reveal = TransactionBuilder::new(change)
+            .add_input(commitment, commitment_utxo)
+            .reveal_name(owner, 1, key, Some("built.com".into()), value)
+            .build_and_sign(&chain.keypairs)
+            .unwrap();
+        assert_eq!(reveal.transaction.outputs.len(), 1);
+        chain.connect(vec![reveal]).unwrap();
+        assert_eq!(chain.state.get_value(&key).unwrap(), Some(value));
+    }
+
+    #[test]
+    fn builder_rejects_unfunded_or_unsignable_transactions() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let funding = chain.deposit(owner, 10);
+        let utxo = chain.state.get_utxo(&funding).unwrap().unwrap();
+        let builder = || {
+            TransactionBuilder::new(owner)
+                .add_input(funding, utxo.clone())
+                .pay_to(chain.addresses[1], 9)
+        };
+        assert!(matches!(
+            builder().fee(2).build(),
+            Err(BuilderError::InsufficientFunds {
+                value_in: 10,
+                value_out: 11
+            })
+        ));
+        assert!(matches!(
+            builder().build_and_sign(&HashMap::new()),
+            Err(BuilderError::MissingKeypair { address }) if address == owner
+        ));
+        let (transaction, spent_utxos) = builder().fee(1).build().unwrap();
+        assert_eq!(transaction.outputs.len(), 1);
+        assert_eq!(spent_utxos.len(), 1);
+    }
+}
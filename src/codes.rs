@@ -0,0 +1,241 @@
+use crate::validation::{BitNamesError, Error};
+
+/// Stable machine readable error codes for API and RPC clients. Codes are
+/// never reused or renumbered, new errors get new codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ErrorCode {
+    Authorization = 1,
+    Sdk = 2,
+    Heed = 3,
+    Config = 4,
+    Codec = 5,
+    InvalidNameCommitment = 1001,
+    KeyAlreadyRegistered = 1002,
+    CommitmentNotFound = 1003,
+    KeyNotFound = 1004,
+    RevealTooLate = 1005,
+    RevealTooEarly = 1006,
+    InvalidWeight = 1007,
+    DuplicateReveal = 1008,
+    InsufficientDeposit = 1009,
+    CoinbaseClaimsDeposits = 1010,
+    DuplicateTransaction = 1011,
+    // 1012 was `BatchTooLarge`, retired once progress batches no longer
+    // had to be disconnected on failure.
+    ContextMismatch = 1013,
+    DuplicateInput = 1014,
+    DuplicateCommitment = 1015,
+    InvalidKey = 1016,
+    InvalidName = 1017,
+    InlineNameMismatch = 1018,
+    MissingInlineName = 1019,
+    ReservedSuffix = 1020,
+    AuthorizationCountMismatch = 1021,
+    ZeroCommitment = 1022,
+    UndoNotFound = 1023,
+    NotTipBody = 1024,
+    InvariantViolation = 1025,
+    NotDelegate = 1026,
+    NonCanonicalOutputOrder = 1027,
+    CommitmentFromFuture = 1028,
+    UtxoNotFound = 1029,
+    NameReserved = 1030,
+    SelfTransfer = 1031,
+    TransferValueMismatch = 1032,
+    DuplicateKeyInTransaction = 1033,
+    InvalidControlProof = 1034,
+    NotController = 1035,
+    SnapshotVersionMismatch = 1036,
+    SnapshotDatabaseMismatch = 1037,
+    Proof = 1038,
+    TransferDropsKey = 1039,
+    CommitmentFeeTooLow = 1040,
+    BurnTooLow = 1041,
+    BurnOfRegisteredKey = 1042,
+    CommitmentKeyMismatch = 1043,
+    InvalidChainBody = 1044,
+    AuctionsDisabled = 1045,
+    BidForRegisteredKey = 1046,
+    BidWindowClosed = 1047,
+    BidLocked = 1048,
+    RevealOutsideAuctionWindow = 1049,
+    NotTopBid = 1050,
+    AuctionInProgress = 1051,
+    ChainEnvNotEmpty = 1052,
+    BlockIdNotFound = 1053,
+}
+
+impl ErrorCode {
+    pub fn as_u32(self) -> u32 {
+        self as u32
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Authorization => "AUTHORIZATION",
+            Self::Sdk => "SDK",
+            Self::Heed => "HEED",
+            Self::Config => "CONFIG",
+            Self::Codec => "CODEC",
+            Self::InvalidNameCommitment => "INVALID_NAME_COMMITMENT",
+            Self::KeyAlreadyRegistered => "KEY_ALREADY_REGISTERED",
+            Self::CommitmentNotFound => "COMMITMENT_NOT_FOUND",
+            Self::KeyNotFound => "KEY_NOT_FOUND",
+            Self::RevealTooLate => "REVEAL_TOO_LATE",
+            Self::RevealTooEarly => "REVEAL_TOO_EARLY",
+            Self::InvalidWeight => "INVALID_WEIGHT",
+            Self::DuplicateReveal => "DUPLICATE_REVEAL",
+            Self::InsufficientDeposit => "INSUFFICIENT_DEPOSIT",
+            Self::CoinbaseClaimsDeposits => "COINBASE_CLAIMS_DEPOSITS",
+            Self::DuplicateTransaction => "DUPLICATE_TRANSACTION",
+            Self::ContextMismatch => "CONTEXT_MISMATCH",
+            Self::DuplicateInput => "DUPLICATE_INPUT",
+            Self::DuplicateCommitment => "DUPLICATE_COMMITMENT",
+            Self::InvalidKey => "INVALID_KEY",
+            Self::InvalidName => "INVALID_NAME",
+            Self::InlineNameMismatch => "INLINE_NAME_MISMATCH",
+            Self::MissingInlineName => "MISSING_INLINE_NAME",
+            Self::ReservedSuffix => "RESERVED_SUFFIX",
+            Self::AuthorizationCountMismatch => "AUTHORIZATION_COUNT_MISMATCH",
+            Self::ZeroCommitment => "ZERO_COMMITMENT",
+            Self::UndoNotFound => "UNDO_NOT_FOUND",
+            Self::NotTipBody => "NOT_TIP_BODY",
+            Self::InvariantViolation => "INVARIANT_VIOLATION",
+            Self::NotDelegate => "NOT_DELEGATE",
+            Self::NonCanonicalOutputOrder => "NON_CANONICAL_OUTPUT_ORDER",
+            Self::CommitmentFromFuture => "COMMITMENT_FROM_FUTURE",
+            Self::UtxoNotFound => "UTXO_NOT_FOUND",
+            Self::NameReserved => "NAME_RESERVED",
+            Self::SelfTransfer => "SELF_TRANSFER",
+            Self::TransferValueMismatch => "TRANSFER_VALUE_MISMATCH",
+            Self::DuplicateKeyInTransaction => "DUPLICATE_KEY_IN_TRANSACTION",
+            Self::InvalidControlProof => "INVALID_CONTROL_PROOF",
+            Self::NotController => "NOT_CONTROLLER",
+            Self::SnapshotVersionMismatch => "SNAPSHOT_VERSION_MISMATCH",
+            Self::SnapshotDatabaseMismatch => "SNAPSHOT_DATABASE_MISMATCH",
+            Self::Proof => "PROOF",
+            Self::TransferDropsKey => "TRANSFER_DROPS_KEY",
+            Self::CommitmentFeeTooLow => "COMMITMENT_FEE_TOO_LOW",
+            Self::BurnTooLow => "BURN_TOO_LOW",
+            Self::BurnOfRegisteredKey => "BURN_OF_REGISTERED_KEY",
+            Self::CommitmentKeyMismatch => "COMMITMENT_KEY_MISMATCH",
+            Self::InvalidChainBody => "INVALID_CHAIN_BODY",
+            Self::AuctionsDisabled => "AUCTIONS_DISABLED",
+            Self::BidForRegisteredKey => "BID_FOR_REGISTERED_KEY",
+            Self::BidWindowClosed => "BID_WINDOW_CLOSED",
+            Self::BidLocked => "BID_LOCKED",
+            Self::RevealOutsideAuctionWindow => "REVEAL_OUTSIDE_AUCTION_WINDOW",
+            Self::NotTopBid => "NOT_TOP_BID",
+            Self::AuctionInProgress => "AUCTION_IN_PROGRESS",
+            Self::ChainEnvNotEmpty => "CHAIN_ENV_NOT_EMPTY",
+            Self::BlockIdNotFound => "BLOCK_ID_NOT_FOUND",
+        }
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Authorization(_) => ErrorCode::Authorization,
+            Self::Sdk(_) => ErrorCode::Sdk,
+            Self::Heed(_) => ErrorCode::Heed,
+            Self::Config(_) => ErrorCode::Config,
+            Self::Codec(_) => ErrorCode::Codec,
+            Self::BitNames(err) => err.code(),
+        }
+    }
+}
+
+impl BitNamesError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidNameCommitment { .. } => ErrorCode::InvalidNameCommitment,
+            Self::KeyAlreadyRegistered { .. } => ErrorCode::KeyAlreadyRegistered,
+            Self::CommitmentNotFound { .. } => ErrorCode::CommitmentNotFound,
+            Self::KeyNotFound { .. } => ErrorCode::KeyNotFound,
+            Self::RevealTooLate { .. } => ErrorCode::RevealTooLate,
+            Self::RevealTooEarly { .. } => ErrorCode::RevealTooEarly,
+            Self::InvalidWeight { .. } => ErrorCode::InvalidWeight,
+            Self::DuplicateReveal { .. } => ErrorCode::DuplicateReveal,
+            Self::InsufficientDeposit { .. } => ErrorCode::InsufficientDeposit,
+            Self::CoinbaseClaimsDeposits { .. } => ErrorCode::CoinbaseClaimsDeposits,
+            Self::DuplicateTransaction { .. } => ErrorCode::DuplicateTransaction,
+            Self::ContextMismatch { .. } => ErrorCode::ContextMismatch,
+            Self::DuplicateInput { .. } => ErrorCode::DuplicateInput,
+            Self::DuplicateCommitment { .. } => ErrorCode::DuplicateCommitment,
+            Self::InvalidKey { .. } => ErrorCode::InvalidKey,
+            Self::InvalidName(_) => ErrorCode::InvalidName,
+            Self::InlineNameMismatch { .. } => ErrorCode::InlineNameMismatch,
+            Self::MissingInlineName { .. } => ErrorCode::MissingInlineName,
+            Self::ReservedSuffix { .. } => ErrorCode::ReservedSuffix,
+            Self::AuthorizationCountMismatch { .. } => ErrorCode::AuthorizationCountMismatch,
+            Self::ZeroCommitment => ErrorCode::ZeroCommitment,
+            Self::UndoNotFound { .. } => ErrorCode::UndoNotFound,
+            Self::NotTipBody { .. } => ErrorCode::NotTipBody,
+            Self::InvariantViolation { .. } => ErrorCode::InvariantViolation,
+            Self::NotDelegate { .. } => ErrorCode::NotDelegate,
+            Self::NonCanonicalOutputOrder { .. } => ErrorCode::NonCanonicalOutputOrder,
+            Self::CommitmentFromFuture { .. } => ErrorCode::CommitmentFromFuture,
+            Self::UtxoNotFound { .. } => ErrorCode::UtxoNotFound,
+            Self::NameReserved { .. } => ErrorCode::NameReserved,
+            Self::SelfTransfer { .. } => ErrorCode::SelfTransfer,
+            Self::TransferValueMismatch { .. } => ErrorCode::TransferValueMismatch,
+            Self::DuplicateKeyInTransaction { .. } => ErrorCode::DuplicateKeyInTransaction,
+            Self::InvalidControlProof { .. } => ErrorCode::InvalidControlProof,
+            Self::NotController { .. } => ErrorCode::NotController,
+            Self::SnapshotVersionMismatch { .. } => ErrorCode::SnapshotVersionMismatch,
+            Self::SnapshotDatabaseMismatch { .. } => ErrorCode::SnapshotDatabaseMismatch,
+            Self::Proof(_) => ErrorCode::Proof,
+            Self::TransferDropsKey { .. } => ErrorCode::TransferDropsKey,
+            Self::CommitmentFeeTooLow { .. } => ErrorCode::CommitmentFeeTooLow,
+            Self::BurnTooLow { .. } => ErrorCode::BurnTooLow,
+            Self::BurnOfRegisteredKey { .. } => ErrorCode::BurnOfRegisteredKey,
+            Self::CommitmentKeyMismatch { .. } => ErrorCode::CommitmentKeyMismatch,
+            Self::InvalidChainBody { .. } => ErrorCode::InvalidChainBody,
+            Self::AuctionsDisabled { .. } => ErrorCode::AuctionsDisabled,
+            Self::BidForRegisteredKey { .. } => ErrorCode::BidForRegisteredKey,
+            Self::BidWindowClosed { .. } => ErrorCode::BidWindowClosed,
+            Self::BidLocked { .. } => ErrorCode::BidLocked,
+            Self::RevealOutsideAuctionWindow { .. } => ErrorCode::RevealOutsideAuctionWindow,
+            Self::NotTopBid { .. } => ErrorCode::NotTopBid,
+            Self::AuctionInProgress { .. } => ErrorCode::AuctionInProgress,
+            Self::ChainEnvNotEmpty => ErrorCode::ChainEnvNotEmpty,
+            Self::BlockIdNotFound { .. } => ErrorCode::BlockIdNotFound,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::{BitNamesParams, ConfigError};
+    use crate::testing::*;
+
+    #[test]
+    fn errors_map_to_stable_codes() {
+        let mut chain = TestChain::new(BitNamesParams {
+            commitment_max_age: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        chain.register(owner, "taken.com", 1).unwrap();
+        let commitment = chain.commit(owner, "taken.com", 2).unwrap();
+        let err = chain.reveal(commitment, owner, "taken.com", 2).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::BitNames(BitNamesError::KeyAlreadyRegistered { .. })
+        ));
+        assert_eq!(err.code(), ErrorCode::KeyAlreadyRegistered);
+        assert_eq!(err.code().as_u32(), 1002);
+        assert_eq!(err.code().as_str(), "KEY_ALREADY_REGISTERED");
+
+        let err = Error::Config(ConfigError::ZeroEpochLength);
+        assert_eq!(err.code(), ErrorCode::Config);
+        assert_eq!(err.code().as_u32(), 4);
+        assert_eq!(err.code().as_str(), "CONFIG");
+        let err = Error::from(BitNamesError::ChainEnvNotEmpty);
+        assert_eq!(err.code().as_u32(), 1052);
+        assert_eq!(err.code().as_str(), "CHAIN_ENV_NOT_EMPTY");
+    }
+}
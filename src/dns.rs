@@ -0,0 +1,197 @@
+use crate::doh;
+use crate::nameserver::NameServer;
+use crate::validation::BitNamesState;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::Duration;
+
+/// Largest UDP response plain DNS allows without EDNS, clients retry over
+/// TCP when they see the truncation bit.
+const MAX_UDP_RESPONSE: usize = 512;
+/// Offset of the flags byte holding the truncation bit in a DNS header.
+const FLAGS_OFFSET: usize = 2;
+const TRUNCATED: u8 = 0x02;
+const HEADER_LEN: usize = 12;
+/// How long a TCP client can take to send its next query or read a
+/// response before the connection is dropped.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// TCP connections served at once, later ones wait for a slot.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Serve plain DNS on `addr`, typically port 53, over UDP and TCP at once.
+/// Queries are answered like DoH ones, see `doh::resolve`.
+pub fn serve(
+    addr: SocketAddr,
+    nameserver: &NameServer,
+    state: &BitNamesState,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+    let listener = TcpListener::bind(addr)?;
+    std::thread::scope(|scope| {
+        let udp = scope.spawn(|| serve_udp(&socket, nameserver, state));
+        let tcp = serve_tcp(listener, nameserver, state);
+        udp.join().unwrap().and(tcp)
+    })
+}
+
+pub fn serve_udp(
+    socket: &UdpSocket,
+    nameserver: &NameServer,
+    state: &BitNamesState,
+) -> std::io::Result<()> {
+    let mut buf = [0; 65535];
+    loop {
+        // Errors here are about a single datagram, e.g. an ICMP unreachable
+        // from an earlier peer, and shouldn't stop the others being served.
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) => {
+                log::warn!("dns receive failed: {err}");
+                continue;
+            }
+        };
+        // Malformed queries are dropped, there is no id to answer them with.
+        let Ok(response) = doh::resolve(nameserver, state, &buf[..len]) else {
+            continue;
+        };
+        if let Err(err) = socket.send_to(&truncate(response), peer) {
+            log::warn!("dns response to {peer} failed: {err}");
+        }
+    }
+}
+
+/// Serve every connection on a thread of its own, up to `MAX_CONNECTIONS`
+/// at once, so a slow client can't hold up the others.
+pub fn serve_tcp(
+    listener: TcpListener,
+    nameserver: &NameServer,
+    state: &BitNamesState,
+) -> std::io::Result<()> {
+    // Each connection holds a slot until it closes.
+    let (slots, freed) = crossbeam_channel::bounded(MAX_CONNECTIONS);
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            // Accepting fails for reasons like a client resetting before it
+            // was accepted, which only concern that one connection.
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("dns accept failed: {err}");
+                    continue;
+                }
+            };
+            slots.send(()).unwrap();
+            let freed = &freed;
+            scope.spawn(move || {
+                // A misbehaving client shouldn't take the resolver down.
+                if let Err(err) = serve_connection(stream, nameserver, state) {
+                    log::warn!("dns connection failed: {err}");
+                }
+                freed.recv().unwrap();
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Answer every query on the connection until the client closes it or goes
+/// idle for `CONNECTION_TIMEOUT`. Over TCP each message is prefixed with its
+/// length as a big endian u16.
+fn serve_connection(
+    mut stream: TcpStream,
+    nameserver: &NameServer,
+    state: &BitNamesState,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    loop {
+        let mut len = [0; 2];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            // Timeouts show up as either kind depending on the platform.
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        }
+        let mut query = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut query)?;
+        let response = doh::resolve(nameserver, state, &query)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        // Responses can't outgrow the length prefix, the answers are bounded
+        // by the records a single name holds.
+        stream.write_all(&(response.len() as u16).to_be_bytes())?;
+        stream.write_all(&response)?;
+    }
+}
+
+/// Cut a response that doesn't fit a UDP datagram down to its header and
+/// set the truncation bit. The question section is dropped too, which
+/// resolvers accept as they retry over TCP anyway.
+fn truncate(mut response: Vec<u8>) -> Vec<u8> {
+    if response.len() <= MAX_UDP_RESPONSE {
+        return response;
+    }
+    response.truncate(HEADER_LEN);
+    response[FLAGS_OFFSET] |= TRUNCATED;
+    // Zero the question, answer, authority and additional counts.
+    response[4..HEADER_LEN].fill(0);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+    use std::net::Ipv4Addr;
+    use trust_dns_proto::op::{Message, Query, ResponseCode};
+    use trust_dns_proto::rr::{Name, RData, RecordType};
+
+    #[test]
+    fn answers_stored_record_over_tcp() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let mut nameserver = NameServer::open(&chain.state.env).unwrap();
+        register_with_a_record(&mut chain, &mut nameserver, "tcp.com", "192.0.2.7");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut query = Message::new();
+        query.set_id(7).add_query(Query::query(
+            Name::from_utf8("tcp.com").unwrap(),
+            RecordType::A,
+        ));
+        let query = query.to_vec().unwrap();
+        let response = std::thread::scope(|scope| {
+            let served = scope.spawn(|| serve_connection(server, &nameserver, &chain.state));
+            client
+                .write_all(&(query.len() as u16).to_be_bytes())
+                .unwrap();
+            client.write_all(&query).unwrap();
+            let mut len = [0; 2];
+            client.read_exact(&mut len).unwrap();
+            let mut response = vec![0; u16::from_be_bytes(len) as usize];
+            client.read_exact(&mut response).unwrap();
+            // Closing the connection ends `serve_connection`.
+            drop(client);
+            served.join().unwrap().unwrap();
+            Message::from_vec(&response).unwrap()
+        });
+
+        assert_eq!(response.id(), 7);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        let answers: Vec<_> = response
+            .answers()
+            .iter()
+            .map(|answer| answer.data())
+            .collect();
+        assert_eq!(answers, vec![Some(&RData::A(Ipv4Addr::new(192, 0, 2, 7)))]);
+    }
+}
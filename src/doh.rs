@@ -0,0 +1,339 @@
+use crate::hashes::RecordType;
+use crate::name::Name as BitName;
+use crate::nameserver::{NameServer, NameServerError};
+use crate::validation::BitNamesState;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::time::Duration;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_proto::rr::rdata::{MX, TXT};
+use trust_dns_proto::rr::{Name, RData, Record, RecordType as DnsRecordType};
+
+const CONTENT_TYPE: &str = "application/dns-message";
+const DOH_PATH: &str = "/dns-query";
+const TTL: u32 = 300;
+/// RFC 8484 queries are a single DNS message, which never exceeds 64KiB.
+const MAX_BODY: usize = 65535;
+/// How long a client can take to send its request or read the response
+/// before the connection is dropped.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections served at once, later ones wait for a slot.
+const MAX_CONNECTIONS: usize = 64;
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn error(status: u16, reason: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: reason.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Answer a wireformat DNS query out of `nameserver`.
+///
+/// Unregistered names get NXDOMAIN. Registered names with nothing to serve
+/// for the question, because they have no value, are waiting for a new
+/// owner's value or lack a stored record of its type, get an empty NOERROR
+/// answer. SERVFAIL is kept for failures to read the state or the store.
+pub fn resolve(
+    nameserver: &NameServer,
+    state: &BitNamesState,
+    query: &[u8],
+) -> Result<Vec<u8>, ProtoError> {
+    let query = Message::from_vec(query)?;
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(query.op_code())
+        .set_recursion_desired(query.recursion_desired())
+        .set_authoritative(true)
+        .add_queries(query.queries().to_vec());
+    for question in query.queries() {
+        let Ok(name) = BitName::parse(&question.name().to_utf8()) else {
+            response.set_response_code(ResponseCode::FormErr);
+            continue;
+        };
+        let key = name.key();
+        match state.get_value(&key) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                response.set_response_code(ResponseCode::NXDomain);
+                continue;
+            }
+            Err(_) => {
+                response.set_response_code(ResponseCode::ServFail);
+                continue;
+            }
+        }
+        let Some(record_type) = record_type(question.query_type()) else {
+            continue;
+        };
+        let value = match nameserver.lookup_record(state, &name, record_type) {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(NameServerError::NotRegistered { .. }) => {
+                response.set_response_code(ResponseCode::NXDomain);
+                continue;
+            }
+            Err(NameServerError::State(_) | NameServerError::Heed(_)) => {
+                response.set_response_code(ResponseCode::ServFail);
+                continue;
+            }
+            // Lookup misses, e.g. `NoValue`, `AwaitingNewOwner` or
+            // `MissingLocalRecord`.
+            Err(_) => continue,
+        };
+        match rdata(record_type, &value) {
+            Some(rdata) => {
+                response.add_answer(Record::from_rdata(question.name().clone(), TTL, rdata));
+            }
+            None => {
+                response.set_response_code(ResponseCode::ServFail);
+            }
+        }
+    }
+    response.to_vec()
+}
+
+/// Record type a question asks for, `None` for types names can't hold.
+fn record_type(record_type: DnsRecordType) -> Option<RecordType> {
+    let record_type = match record_type {
+        DnsRecordType::A => RecordType::A,
+        DnsRecordType::AAAA => RecordType::Aaaa,
+        DnsRecordType::CNAME => RecordType::Cname,
+        DnsRecordType::MX => RecordType::Mx,
+        DnsRecordType::TXT => RecordType::Txt,
+        _ => return None,
+    };
+    Some(record_type)
+}
+
+/// MX values are stored as `<preference> <exchange>`.
+fn rdata(record_type: RecordType, value: &str) -> Option<RData> {
+    let rdata = match record_type {
+        RecordType::A => RData::A(value.parse::<Ipv4Addr>().ok()?),
+        RecordType::Aaaa => RData::AAAA(value.parse::<Ipv6Addr>().ok()?),
+        RecordType::Cname => RData::CNAME(Name::from_utf8(value).ok()?),
+        RecordType::Mx => {
+            let (preference, exchange) = value.split_once(' ')?;
+            RData::MX(MX::new(
+                preference.parse().ok()?,
+                Name::from_utf8(exchange).ok()?,
+            ))
+        }
+        RecordType::Txt => RData::TXT(TXT::new(vec![value.to_string()])),
+    };
+    Some(rdata)
+}
+
+/// Handle an RFC 8484 request. `target` is the request target including the
+/// query string, `body` is only used for POST.
+pub fn handle_request(
+    nameserver: &NameServer,
+    state: &BitNamesState,
+    method: &str,
+    target: &str,
+    body: &[u8],
+) -> HttpResponse {
+    let (path, query_string) = target.split_once('?').unwrap_or((target, ""));
+    if path != DOH_PATH {
+        return HttpResponse::error(404, "not found");
+    }
+    let query = match method {
+        "GET" => {
+            let Some(dns) = query_string
+                .split('&')
+                .find_map(|param| param.strip_prefix("dns="))
+            else {
+                return HttpResponse::error(400, "missing dns parameter");
+            };
+            match URL_SAFE_NO_PAD.decode(dns) {
+                Ok(query) => query,
+                Err(_) => return HttpResponse::error(400, "invalid base64url"),
+            }
+        }
+        "POST" => body.to_vec(),
+        _ => return HttpResponse::error(405, "method not allowed"),
+    };
+    match resolve(nameserver, state, &query) {
+        Ok(body) => HttpResponse {
+            status: 200,
+            content_type: CONTENT_TYPE,
+            body,
+        },
+        Err(_) => HttpResponse::error(400, "malformed dns message"),
+    }
+}
+
+/// Serve DoH over plain HTTP, every connection on a thread of its own and up
+/// to `MAX_CONNECTIONS` at once. TLS is expected to be terminated in front of
+/// this.
+pub fn serve(
+    listener: TcpListener,
+    nameserver: &NameServer,
+    state: &BitNamesState,
+) -> std::io::Result<()> {
+    // Each connection holds a slot until it closes.
+    let (slots, freed) = crossbeam_channel::bounded(MAX_CONNECTIONS);
+    std::thread::scope(|scope| {
+        for stream in listener.incoming() {
+            // Accepting fails for reasons like a client resetting before it
+            // was accepted, which only concern that one connection.
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("doh accept failed: {err}");
+                    continue;
+                }
+            };
+            slots.send(()).unwrap();
+            let freed = &freed;
+            scope.spawn(move || {
+                // A misbehaving client shouldn't take the resolver down.
+                if let Err(err) = serve_connection(stream, nameserver, state) {
+                    log::warn!("doh connection failed: {err}");
+                }
+                freed.recv().unwrap();
+            });
+        }
+        Ok(())
+    })
+}
+
+fn serve_connection(
+    stream: TcpStream,
+    nameserver: &NameServer,
+    state: &BitNamesState,
+) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return write_response(stream, HttpResponse::error(400, "bad request"));
+    };
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return write_response(stream, HttpResponse::error(413, "payload too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let response = handle_request(nameserver, state, method, target, &body);
+    write_response(stream, response)
+}
+
+fn write_response(mut stream: TcpStream, response: HttpResponse) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} \r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashes::hash;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+    use crate::types::BitNamesOutput;
+    use trust_dns_proto::op::Query;
+
+    fn query(name: &str) -> Vec<u8> {
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_utf8(name).unwrap(),
+            DnsRecordType::A,
+        ));
+        query.to_vec().unwrap()
+    }
+
+    fn resolve_name(nameserver: &NameServer, state: &BitNamesState, name: &str) -> Message {
+        Message::from_vec(&resolve(nameserver, state, &query(name)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn lookup_misses_get_empty_noerror() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let nameserver = NameServer::open(&chain.state.env).unwrap();
+        chain.register(owner, "novalue.com", 1).unwrap();
+        let name = chain.register(owner, "unstored.com", 2).unwrap();
+        let set_value = chain.spend_tx(
+            name,
+            owner,
+            BitNamesOutput::KeyValue {
+                key: key_of("unstored.com"),
+                value: Some(hash(&"unstored").into()),
+            },
+        );
+        chain.connect(vec![set_value]).unwrap();
+
+        for name in ["novalue.com", "unstored.com"] {
+            let response = resolve_name(&nameserver, &chain.state, name);
+            assert_eq!(response.response_code(), ResponseCode::NoError, "{name}");
+            assert!(response.answers().is_empty(), "{name}");
+        }
+        let response = resolve_name(&nameserver, &chain.state, "unregistered.com");
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[test]
+    fn get_and_post_answer_stored_record() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let mut nameserver = NameServer::open(&chain.state.env).unwrap();
+        register_with_a_record(&mut chain, &mut nameserver, "doh.com", "192.0.2.8");
+
+        let query = query("doh.com");
+        let get = format!("{DOH_PATH}?dns={}", URL_SAFE_NO_PAD.encode(&query));
+        for (method, target, body) in [
+            ("GET", get.as_str(), &[][..]),
+            ("POST", DOH_PATH, &query[..]),
+        ] {
+            let response = handle_request(&nameserver, &chain.state, method, target, body);
+            assert_eq!(response.status, 200, "{method}");
+            assert_eq!(response.content_type, CONTENT_TYPE);
+            let message = Message::from_vec(&response.body).unwrap();
+            assert_eq!(message.response_code(), ResponseCode::NoError);
+            let answers: Vec<_> = message
+                .answers()
+                .iter()
+                .map(|answer| answer.data())
+                .collect();
+            assert_eq!(
+                answers,
+                vec![Some(&RData::A(Ipv4Addr::new(192, 0, 2, 8)))],
+                "{method}"
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::hashes::*;
+use crate::types::Address;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    NameCommitted {
+        commitment: Commitment,
+        height: u32,
+    },
+    NameRegistered {
+        key: Key,
+        height: u32,
+    },
+    ValueUpdated {
+        key: Key,
+        value: Option<Value>,
+        height: u32,
+    },
+    CommitmentExpired {
+        commitment: Commitment,
+        height: u32,
+    },
+    NameTransferred {
+        key: Key,
+        to: Address,
+        height: u32,
+    },
+}
+
+/// What to do with a new event when the channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued event to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Wait up to the given duration for a subscriber to make room, then
+    /// drop the new event.
+    Block(Duration),
+}
+
+/// Receiving end of one subscriber's channel, deref to the channel's
+/// `Receiver`. Dropping it unsubscribes.
+pub struct Subscription {
+    receiver: Receiver<Event>,
+    _alive: Arc<()>,
+}
+
+impl std::ops::Deref for Subscription {
+    type Target = Receiver<Event>;
+
+    fn deref(&self) -> &Receiver<Event> {
+        &self.receiver
+    }
+}
+
+struct Subscriber {
+    sender: Sender<Event>,
+    // Held so that `DropOldest` can evict from the front of the queue.
+    receiver: Receiver<Event>,
+    alive: Weak<()>,
+}
+
+/// Bounded event channels, one per subscriber, that never block block
+/// processing indefinitely. Every subscriber gets every event, and a slow
+/// one only loses events of its own, each counted in `dropped`.
+pub struct EventSink {
+    capacity: usize,
+    policy: OverflowPolicy,
+    subscribers: Mutex<Vec<Subscriber>>,
+    dropped: AtomicU64,
+}
+
+impl EventSink {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            capacity,
+            policy,
+            subscribers: Mutex::new(vec![]),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Channel of the events emitted from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let (sender, receiver) = crossbeam_channel::bounded(self.capacity);
+        let alive = Arc::new(());
+        self.subscribers.lock().unwrap().push(Subscriber {
+            sender,
+            receiver: receiver.clone(),
+            alive: Arc::downgrade(&alive),
+        });
+        Subscription {
+            receiver,
+            _alive: alive,
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.alive.strong_count() > 0);
+        for subscriber in subscribers.iter() {
+            self.send(subscriber, event.clone());
+        }
+    }
+
+    fn send(&self, subscriber: &Subscriber, event: Event) {
+        match self.policy {
+            OverflowPolicy::DropOldest => {
+                let mut event = event;
+                loop {
+                    match subscriber.sender.try_send(event) {
+                        Ok(()) => return,
+                        Err(TrySendError::Full(rejected)) => {
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                            // Nothing to evict means the channel has no
+                            // capacity at all, so the new event is dropped.
+                            if subscriber.receiver.try_recv().is_err() {
+                                return;
+                            }
+                            event = rejected;
+                        }
+                        Err(TrySendError::Disconnected(_)) => unreachable!(),
+                    }
+                }
+            }
+            OverflowPolicy::Block(timeout) => {
+                if subscriber.sender.send_timeout(event, timeout).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered(height: u32) -> Event {
+        Event::NameRegistered {
+            key: hash(&height).into(),
+            height,
+        }
+    }
+
+    #[test]
+    fn slow_subscriber_only_loses_its_own_events() {
+        let sink = EventSink::new(1, OverflowPolicy::DropOldest);
+        let fast = sink.subscribe();
+        let slow = sink.subscribe();
+        for height in 0..3 {
+            sink.emit(registered(height));
+            assert_eq!(fast.try_recv().unwrap(), registered(height));
+        }
+        assert_eq!(sink.dropped(), 2);
+        assert_eq!(slow.try_recv().unwrap(), registered(2));
+        assert!(slow.try_recv().is_err());
+    }
+
+    #[test]
+    fn dropped_subscription_is_unsubscribed() {
+        let sink = EventSink::new(1, OverflowPolicy::Block(Duration::from_millis(1)));
+        let subscription = sink.subscribe();
+        drop(subscription);
+        for height in 0..3 {
+            sink.emit(registered(height));
+        }
+        assert_eq!(sink.dropped(), 0);
+        assert!(sink.subscribers.lock().unwrap().is_empty());
+    }
+}
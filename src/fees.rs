@@ -0,0 +1,125 @@
+use crate::hashes::*;
+use crate::name::{Name, NameError};
+use crate::params::BitNamesParams;
+use crate::types::*;
+
+// Approximate serialized sizes of the parts of a transaction that don't
+// depend on its BitNames content.
+const TRANSACTION_OVERHEAD: u64 = 16;
+/// Outpoint plus its authorization, a 32 byte public key and 64 byte
+/// signature.
+const INPUT_SIZE: u64 = 40 + 96;
+/// Address and content tag.
+const OUTPUT_OVERHEAD: u64 = 24;
+const VALUE_OUTPUT_SIZE: u64 = OUTPUT_OVERHEAD + 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegistrationCost {
+    /// Commitment transaction, which also pays back change.
+    pub commitment_fee: u64,
+    pub reveal_fee: u64,
+    /// Transaction setting the first value.
+    pub value_fee: u64,
+    /// Length based premium for short names.
+    pub premium: u64,
+    /// Refunded once the first value is set, but it has to be paid up front.
+    pub deposit: u64,
+    /// Locked in the commitment and paid by the reveal.
+    pub locked_fee: u64,
+}
+
+impl RegistrationCost {
+    pub fn total(&self) -> u64 {
+        self.commitment_fee
+            + self.reveal_fee
+            + self.value_fee
+            + self.premium
+            + self.deposit
+            + self.locked_fee
+    }
+}
+
+/// Estimate what registering `name` and setting its value costs at
+/// `fee_rate` per byte, assuming single input transactions.
+pub fn estimate_registration_cost(
+    name: &str,
+    fee_rate: u64,
+    params: &BitNamesParams,
+) -> Result<RegistrationCost, NameError> {
+    let name = Name::parse(name)?;
+    let key = name.key();
+    let commitment = BitNamesOutput::Commitment {
+        commitment: blake2b_hmac(&key, 0),
+        fee: params.min_commitment_fee,
+    };
+    let reveal = BitNamesOutput::Reveal {
+        salt: 0,
+        key,
+        name: Some(name.to_string()),
+        path: vec![],
+        value: None,
+    };
+    let key_value = BitNamesOutput::KeyValue {
+        key,
+        value: Some(Hash::from(key).into()),
+    };
+    let transaction_size = |outputs: &[&BitNamesOutput], value_outputs: u64| {
+        let content: u64 = outputs
+            .iter()
+            .map(|output| OUTPUT_OVERHEAD + bincode::serialized_size(output).unwrap())
+            .sum();
+        TRANSACTION_OVERHEAD + INPUT_SIZE + content + value_outputs * VALUE_OUTPUT_SIZE
+    };
+    Ok(RegistrationCost {
+        commitment_fee: fee_rate * transaction_size(&[&commitment], 1),
+        reveal_fee: fee_rate * transaction_size(&[&reveal], 0),
+        value_fee: fee_rate * transaction_size(&[&key_value], 0),
+        premium: params.name_premium(name.as_str()),
+        deposit: params.registration_deposit,
+        locked_fee: params.min_commitment_fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_adds_up_fees_premium_and_deposit() {
+        let params = BitNamesParams {
+            premium_name_length: 5,
+            premium_per_char: 10,
+            registration_deposit: 1000,
+            min_commitment_fee: 7,
+            ..Default::default()
+        };
+        let free = estimate_registration_cost("ab", 0, &params).unwrap();
+        assert_eq!(
+            free,
+            RegistrationCost {
+                premium: 30,
+                deposit: 1000,
+                locked_fee: 7,
+                ..Default::default()
+            }
+        );
+        let paid = estimate_registration_cost("ab", 2, &params).unwrap();
+        let once = estimate_registration_cost("ab", 1, &params).unwrap();
+        assert_eq!(paid.reveal_fee, 2 * once.reveal_fee);
+        assert!(paid.commitment_fee > 0 && paid.value_fee > 0);
+        assert_eq!(
+            paid.total(),
+            paid.commitment_fee + paid.reveal_fee + paid.value_fee + 1037
+        );
+        let long = estimate_registration_cost("longer.com", 0, &params).unwrap();
+        assert_eq!(long.premium, 0);
+    }
+
+    #[test]
+    fn estimate_rejects_invalid_name() {
+        assert_eq!(
+            estimate_registration_cost("", 1, &BitNamesParams::default()),
+            Err(NameError::Empty)
+        );
+    }
+}
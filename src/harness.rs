@@ -0,0 +1,283 @@
+use crate::authorization::{address_from_pubkey, authorize_transaction};
+use crate::hashes::*;
+use crate::params::BitNamesParams;
+use crate::types::*;
+use crate::validation::{BitNamesState, Error};
+use bitcoin::hashes::Hash as _;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sdk_authorization_ed25519_dalek::Keypair;
+use sdk_types::GetValue as _;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+
+const NUM_KEYPAIRS: usize = 10;
+const NUM_DEPOSITS: usize = 10;
+const DEPOSIT_VALUE: u64 = 100;
+
+/// Drives a `BitNamesState` through a random but valid sequence of bodies
+/// that is fully determined by `seed`, so a failing run can be replayed.
+pub struct Harness {
+    rng: StdRng,
+    state: BitNamesState,
+    keypairs: HashMap<Address, Keypair>,
+    addresses: Vec<Address>,
+    /// Kept as a `Vec` in database order rather than a map so that the
+    /// generated sequence doesn't depend on hasher state.
+    utxos: Vec<(OutPoint, Output)>,
+    /// Connected bodies that can still be disconnected, tip last.
+    connected: Vec<Body>,
+    /// Salt and name behind every commitment the harness has made.
+    secrets: HashMap<Commitment, (u64, String)>,
+}
+
+impl Harness {
+    pub fn new(env: &heed::Env, seed: u64) -> Result<Self, Error> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let state = BitNamesState::new(env, BitNamesParams::default())?;
+        let mut keypairs = HashMap::new();
+        let mut addresses = vec![];
+        for _ in 0..NUM_KEYPAIRS {
+            let keypair = Keypair::generate(&mut rng);
+            let address = address_from_pubkey(&keypair.public);
+            addresses.push(address);
+            keypairs.insert(address, keypair);
+        }
+        let utxos: Vec<(OutPoint, Output)> = (0..NUM_DEPOSITS)
+            .map(|index| {
+                let txid = bitcoin::Txid::from_inner(rng.gen());
+                let outpoint = OutPoint::Deposit(bitcoin::OutPoint { txid, vout: 0 });
+                let output = Output {
+                    address: addresses[index % addresses.len()],
+                    content: Content::Value(DEPOSIT_VALUE),
+                };
+                (outpoint, output)
+            })
+            .collect();
+        state.connect_deposits(&utxos.iter().cloned().collect())?;
+        Ok(Self {
+            rng,
+            state,
+            keypairs,
+            addresses,
+            utxos,
+            connected: vec![],
+            secrets: HashMap::new(),
+        })
+    }
+
+    /// Connect a randomly generated body or disconnect the tip, and check
+    /// invariants afterwards.
+    pub fn step(&mut self) -> Result<(), Error> {
+        let transaction = match self.rng.gen_range(0, 5) {
+            0 => self.commit(),
+            1 => self.reveal(),
+            2 => self.set_value(),
+            3 => None,
+            _ => return self.disconnect(),
+        };
+        let body = Body::new(transaction.into_iter().collect(), vec![]);
+        self.state.connect_body(&body)?;
+        self.connected.push(body);
+        if self.connected.len() > self.state.params.max_reorg_depth as usize {
+            self.connected.remove(0);
+        }
+        self.finish_step()
+    }
+
+    fn disconnect(&mut self) -> Result<(), Error> {
+        if let Some(body) = self.connected.pop() {
+            self.state.disconnect_body(&body)?;
+        }
+        self.finish_step()
+    }
+
+    fn finish_step(&mut self) -> Result<(), Error> {
+        self.sync_utxos()?;
+        if let Err(err) = self.state.check_invariants() {
+            panic!("{err}");
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Option<AuthorizedTransaction> {
+        let Some((outpoint, output)) = pick(&mut self.rng, &self.utxos, |output| {
+            matches!(output.content, Content::Value(_))
+        }) else {
+            return None;
+        };
+        let name = format!("{:08x}.com", self.rng.gen::<u32>());
+        let salt: u64 = self.rng.gen();
+        let key: Key = hash(&name).into();
+        let commitment = blake2b_hmac(&key, salt);
+        self.secrets.insert(commitment, (salt, name));
+        let outputs = vec![
+            Output {
+                address: self.random_address(),
+                content: Content::Value(output.get_value()),
+            },
+            Output {
+                address: self.random_address(),
+                content: Content::Custom(BitNamesOutput::Commitment { commitment, fee: 0 }),
+            },
+        ];
+        self.authorize(vec![(outpoint, output)], outputs)
+    }
+
+    fn reveal(&mut self) -> Option<AuthorizedTransaction> {
+        let Some((outpoint, output)) =
+            pick(&mut self.rng, &self.utxos, |output| match &output.content {
+                Content::Custom(BitNamesOutput::Commitment { commitment, .. }) => {
+                    self.secrets.contains_key(commitment)
+                }
+                _ => false,
+            })
+        else {
+            return None;
+        };
+        let Content::Custom(BitNamesOutput::Commitment { commitment, .. }) = output.content else {
+            unreachable!();
+        };
+        let (salt, name) = self.secrets[&commitment].clone();
+        let outputs = vec![Output {
+            address: self.random_address(),
+            content: Content::Custom(BitNamesOutput::Reveal {
+                salt,
+                key: hash(&name).into(),
+                name: Some(name),
+                path: vec![],
+                value: None,
+            }),
+        }];
+        self.authorize(vec![(outpoint, output)], outputs)
+    }
+
+    fn set_value(&mut self) -> Option<AuthorizedTransaction> {
+        let key_of = |output: &Output| match &output.content {
+            Content::Custom(output) => output.key(),
+            _ => None,
+        };
+        let (outpoint, output) = pick(&mut self.rng, &self.utxos, |output| {
+            key_of(output).is_some()
+        })?;
+        let key = key_of(&output)?;
+        let value: Value = hash(&self.rng.gen::<u32>()).into();
+        let outputs = vec![Output {
+            address: self.random_address(),
+            content: Content::Custom(BitNamesOutput::KeyValue {
+                key,
+                value: Some(value),
+            }),
+        }];
+        self.authorize(vec![(outpoint, output)], outputs)
+    }
+
+    fn random_address(&mut self) -> Address {
+        self.addresses[self.rng.gen_range(0, self.addresses.len())]
+    }
+
+    /// Sign the transaction if it would be valid in the next block, so the
+    /// harness only ever generates valid sequences.
+    fn authorize(
+        &self,
+        inputs: Vec<(OutPoint, Output)>,
+        outputs: Vec<Output>,
+    ) -> Option<AuthorizedTransaction> {
+        let (inputs, spent_utxos): (Vec<OutPoint>, Vec<Output>) = inputs.into_iter().unzip();
+        let transaction = Transaction { inputs, outputs };
+        self.state.validate_transaction(&transaction).ok()?;
+        Some(authorize_transaction(
+            &self.keypairs,
+            &spent_utxos,
+            transaction,
+        ))
+    }
+
+    fn sync_utxos(&mut self) -> Result<(), Error> {
+        let rtxn = self.state.env.read_txn()?;
+        self.utxos.clear();
+        for item in self.state.utxos.iter(&rtxn)? {
+            self.utxos.push(item?);
+        }
+        Ok(())
+    }
+}
+
+fn pick(
+    rng: &mut StdRng,
+    utxos: &[(OutPoint, Output)],
+    filter: impl Fn(&Output) -> bool,
+) -> Option<(OutPoint, Output)> {
+    let candidates: Vec<&(OutPoint, Output)> =
+        utxos.iter().filter(|(_, output)| filter(output)).collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    let index = rng.gen_range(0, candidates.len());
+    Some(candidates[index].clone())
+}
+
+/// Run `steps` random steps under `seed`, reporting the seed and step on
+/// failure so the exact sequence can be replayed.
+pub fn replay(env: &heed::Env, seed: u64, steps: usize) -> Result<(), Error> {
+    let mut harness = Harness::new(env, seed)?;
+    for step in 0..steps {
+        match panic::catch_unwind(AssertUnwindSafe(|| harness.step())) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                eprintln!("replay failed at step {step} of {steps} with seed {seed}: {err}");
+                return Err(err);
+            }
+            Err(payload) => {
+                eprintln!("replay panicked at step {step} of {steps} with seed {seed}");
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_env;
+    use crate::validation::BitNamesError;
+
+    #[test]
+    fn same_seed_replays_to_same_state() {
+        let roots: Vec<Hash> = (0..2)
+            .map(|_| {
+                let (env, _dir) = temp_env();
+                let mut harness = Harness::new(&env, 7).unwrap();
+                for _ in 0..50 {
+                    harness.step().unwrap();
+                }
+                harness.state.compute_state_root().unwrap()
+            })
+            .collect();
+        assert_eq!(roots[0], roots[1]);
+        let (env, _dir) = temp_env();
+        replay(&env, 8, 50).unwrap();
+    }
+
+    #[test]
+    fn invariant_check_catches_corrupted_tip() {
+        let (env, _dir) = temp_env();
+        let mut harness = Harness::new(&env, 7).unwrap();
+        for _ in 0..10 {
+            harness.step().unwrap();
+        }
+        let tip = harness.state.best_block_height;
+        let mut wtxn = env.write_txn().unwrap();
+        harness
+            .state
+            .meta
+            .put(&mut wtxn, "best_block_height", &(tip + 1))
+            .unwrap();
+        wtxn.commit().unwrap();
+        assert!(matches!(
+            harness.state.check_invariants(),
+            Err(Error::BitNames(BitNamesError::InvariantViolation { .. }))
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 pub use sdk_types::hash;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 pub type Hash = [u8; 32];
 
@@ -114,3 +115,287 @@ pub fn blake2b_hmac(key: &Key, salt: u64) -> Commitment {
             .into();
     commitment.into()
 }
+
+/// How reveals are checked against commitments. Commitments made under one
+/// scheme can only be revealed on a chain using the same one.
+pub trait CommitmentScheme: Send + Sync {
+    fn commit(&self, key: &Key, salt: u64) -> Commitment;
+}
+
+/// The default scheme, see `blake2b_hmac`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake2bHmac;
+
+impl CommitmentScheme for Blake2bHmac {
+    fn commit(&self, key: &Key, salt: u64) -> Commitment {
+        blake2b_hmac(key, salt)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Cname,
+    Mx,
+    Txt,
+}
+
+impl RecordType {
+    /// Blake2b personalization, at most 16 bytes.
+    fn personalization(&self) -> &'static [u8] {
+        match self {
+            Self::A => b"bitnames-A",
+            Self::Aaaa => b"bitnames-AAAA",
+            Self::Cname => b"bitnames-CNAME",
+            Self::Mx => b"bitnames-MX",
+            Self::Txt => b"bitnames-TXT",
+        }
+    }
+}
+
+/// Hash of a record value, personalized by its record type so that a value
+/// committed to for one type can't be served as another.
+pub fn hash_value(record_type: RecordType, bytes: &[u8]) -> Value {
+    let mut hasher = blake2::Blake2bMac::<digest::consts::U32>::new_with_salt_and_personal(
+        &[],
+        &[],
+        record_type.personalization(),
+    )
+    .unwrap();
+    digest::Update::update(&mut hasher, bytes);
+    let value: [u8; 32] = hasher.finalize_fixed().into();
+    value.into()
+}
+
+/// Records of a name, at most one per type. A name's on-chain value is the
+/// `value` of its whole record set.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RecordSet(BTreeMap<RecordType, String>);
+
+impl RecordSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the record of `record_type`, returning the one it replaces.
+    pub fn insert(&mut self, record_type: RecordType, value: impl Into<String>) -> Option<String> {
+        self.0.insert(record_type, value.into())
+    }
+
+    pub fn get(&self, record_type: RecordType) -> Option<&str> {
+        self.0.get(&record_type).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RecordType, &str)> {
+        self.0
+            .iter()
+            .map(|(record_type, value)| (*record_type, value.as_str()))
+    }
+
+    /// Every record is hashed with `hash_value` first, so each stays bound
+    /// to its type.
+    pub fn value(&self) -> Value {
+        let records: Vec<(RecordType, Value)> = self
+            .iter()
+            .map(|(record_type, value)| (record_type, hash_value(record_type, value.as_bytes())))
+            .collect();
+        hash(&records).into()
+    }
+}
+
+/// Sibling of a node on the way from a leaf to the merkle root.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum MerkleStep {
+    Left(Hash),
+    Right(Hash),
+}
+
+/// Tags hashed in front of leaves and inner nodes, so that an inner node
+/// can't be passed off as a leaf of a tree or the other way round.
+const MERKLE_LEAF: u8 = 0;
+const MERKLE_NODE: u8 = 1;
+
+fn merkle_leaf(leaf: Hash) -> Hash {
+    hash(&(MERKLE_LEAF, leaf))
+}
+
+fn merkle_node(left: &Hash, right: &Hash) -> Hash {
+    hash(&(MERKLE_NODE, left, right))
+}
+
+/// Merkle root over `leaves`, an odd node at the end of a level is carried up
+/// as is. The root of a single leaf is its tagged hash.
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    let mut level: Vec<Hash> = leaves.iter().copied().map(merkle_leaf).collect();
+    while level.len() > 1 {
+        level = merkle_level(&level);
+    }
+    level.first().copied().unwrap_or_default()
+}
+
+fn merkle_level(nodes: &[Hash]) -> Vec<Hash> {
+    nodes
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => merkle_node(left, right),
+            [node] => *node,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Path proving that `leaves[index]` is included in `merkle_root(leaves)`.
+pub fn merkle_path(leaves: &[Hash], mut index: usize) -> Vec<MerkleStep> {
+    let mut path = vec![];
+    let mut level: Vec<Hash> = leaves.iter().copied().map(merkle_leaf).collect();
+    while level.len() > 1 {
+        if index % 2 == 1 {
+            path.push(MerkleStep::Left(level[index - 1]));
+        } else if let Some(sibling) = level.get(index + 1) {
+            path.push(MerkleStep::Right(*sibling));
+        }
+        level = merkle_level(&level);
+        index /= 2;
+    }
+    path
+}
+
+/// Deepest merkle path verifiers accept, enough for 2^32 leaves. Anything
+/// deeper can't come from a real tree, so it is rejected before hashing.
+pub const MAX_PROOF_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ProofError {
+    #[error("proof of depth {depth} exceeds the maximum of {max}")]
+    TooLarge { depth: usize, max: usize },
+}
+
+pub fn check_proof_depth(path: &[MerkleStep]) -> Result<(), ProofError> {
+    if path.len() > MAX_PROOF_DEPTH {
+        return Err(ProofError::TooLarge {
+            depth: path.len(),
+            max: MAX_PROOF_DEPTH,
+        });
+    }
+    Ok(())
+}
+
+pub fn merkle_root_from_path(leaf: Hash, path: &[MerkleStep]) -> Hash {
+    path.iter()
+        .fold(merkle_leaf(leaf), |node, step| match step {
+            MerkleStep::Left(sibling) => merkle_node(sibling, &node),
+            MerkleStep::Right(sibling) => merkle_node(&node, sibling),
+        })
+}
+
+/// Commit to several keys at once, so that the number of names being
+/// registered isn't revealed. Each key is revealed with its `merkle_path`.
+/// A batch of one key is a commitment to that key alone, which is revealed
+/// with an empty path.
+pub fn blake2b_batch_commit(keys: &[Key], salt: u64) -> Commitment {
+    if let [key] = keys {
+        return blake2b_hmac(key, salt);
+    }
+    let leaves: Vec<Hash> = keys.iter().copied().map(Hash::from).collect();
+    blake2b_hmac(&merkle_root(&leaves).into(), salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<Hash> {
+        (0..count).map(|index| hash(&index)).collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_inclusion() {
+        for count in 1..=7 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let path = merkle_path(&leaves, index);
+                assert_eq!(
+                    merkle_root_from_path(*leaf, &path),
+                    root,
+                    "{index} of {count}"
+                );
+            }
+            let outsider = hash(&"outsider");
+            assert_ne!(
+                merkle_root_from_path(outsider, &merkle_path(&leaves, 0)),
+                root
+            );
+        }
+    }
+
+    #[test]
+    fn inner_node_isnt_a_leaf() {
+        let leaves = leaves(4);
+        let root = merkle_root(&leaves);
+        // The parent of the first two leaves, with the path above it.
+        let node = merkle_node(&merkle_leaf(leaves[0]), &merkle_leaf(leaves[1]));
+        let path = merkle_path(&leaves, 0);
+        assert_eq!(path.len(), 2);
+        assert_ne!(merkle_root_from_path(node, &path[1..]), root);
+        // Nor is a tree over the two inner nodes the same tree.
+        let nodes = merkle_level(&leaves.iter().copied().map(merkle_leaf).collect::<Vec<_>>());
+        assert_ne!(merkle_root(&nodes), root);
+    }
+
+    #[test]
+    fn batch_of_one_key_commits_to_the_key() {
+        let key: Key = hash(&"single").into();
+        assert_eq!(blake2b_batch_commit(&[key], 1), blake2b_hmac(&key, 1));
+        let other: Key = hash(&"other").into();
+        assert_ne!(
+            blake2b_batch_commit(&[key, other], 1),
+            blake2b_hmac(&key, 1)
+        );
+    }
+
+    #[test]
+    fn value_hashes_are_personalized_by_record_type() {
+        let a = hash_value(RecordType::A, b"192.0.2.1");
+        assert_eq!(a, hash_value(RecordType::A, b"192.0.2.1"));
+        assert_ne!(a, hash_value(RecordType::A, b"192.0.2.2"));
+        for other in [
+            RecordType::Aaaa,
+            RecordType::Cname,
+            RecordType::Mx,
+            RecordType::Txt,
+        ] {
+            assert_ne!(a, hash_value(other, b"192.0.2.1"), "{other:?}");
+        }
+    }
+
+    #[test]
+    fn record_set_value_covers_every_record() {
+        let mut records = RecordSet::new();
+        assert_eq!(records.insert(RecordType::Txt, "hello"), None);
+        records.insert(RecordType::A, "192.0.2.1");
+        let mut reordered = RecordSet::new();
+        reordered.insert(RecordType::A, "192.0.2.1");
+        reordered.insert(RecordType::Txt, "hello");
+        assert_eq!(records.value(), reordered.value());
+        assert_eq!(records.get(RecordType::A), Some("192.0.2.1"));
+        assert_eq!(records.get(RecordType::Mx), None);
+
+        let value = records.value();
+        assert_eq!(records.insert(RecordType::Txt, "bye"), Some("hello".into()));
+        assert_ne!(records.value(), value);
+        assert_ne!(RecordSet::new().value(), value);
+    }
+
+    #[test]
+    fn proof_depth_is_bounded() {
+        let path = vec![MerkleStep::Left(hash(&0u8)); MAX_PROOF_DEPTH];
+        assert!(check_proof_depth(&path).is_ok());
+        let path = vec![MerkleStep::Left(hash(&0u8)); MAX_PROOF_DEPTH + 1];
+        assert!(matches!(
+            check_proof_depth(&path),
+            Err(ProofError::TooLarge { depth, max: MAX_PROOF_DEPTH }) if depth == MAX_PROOF_DEPTH + 1
+        ));
+    }
+}
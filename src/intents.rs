@@ -0,0 +1,152 @@
+use crate::hashes::*;
+use crate::types::Address;
+use crate::validation::{BitNamesState, Error};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer as _, Verifier as _};
+use std::collections::HashMap;
+
+/// Off-chain promise, signed by `signer`, to reserve `key` for `address`
+/// until block `expiry`. It is honored once the name is registered on chain
+/// to that address.
+#[derive(Debug, Clone)]
+pub struct ReservationIntent {
+    pub key: Key,
+    pub address: Address,
+    pub expiry: u32,
+    pub signer: PublicKey,
+    pub signature: Signature,
+}
+
+impl ReservationIntent {
+    pub fn new(keypair: &Keypair, key: Key, address: Address, expiry: u32) -> Self {
+        let signature = keypair.sign(&intent_message(&key, &address, expiry));
+        Self {
+            key,
+            address,
+            expiry,
+            signer: keypair.public,
+            signature,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), IntentError> {
+        let message = intent_message(&self.key, &self.address, self.expiry);
+        self.signer
+            .verify(&message, &self.signature)
+            .map_err(|_| IntentError::InvalidSignature { key: self.key })
+    }
+}
+
+/// Domain separated so an intent can't double as a signature over some
+/// other message of the same shape.
+fn intent_message(key: &Key, address: &Address, expiry: u32) -> Hash {
+    hash(&("bitnames-intent", key, address, expiry))
+}
+
+/// Verified intents, at most one per key.
+#[derive(Debug, Default)]
+pub struct IntentStore {
+    intents: HashMap<Key, ReservationIntent>,
+}
+
+impl IntentStore {
+    pub fn insert(&mut self, intent: ReservationIntent) -> Result<(), IntentError> {
+        intent.verify()?;
+        self.intents.insert(intent.key, intent);
+        Ok(())
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&ReservationIntent> {
+        self.intents.get(key)
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Option<ReservationIntent> {
+        self.intents.remove(key)
+    }
+}
+
+/// Check that the on-chain registration of the intent's key went to the
+/// reserved address before the intent expired.
+pub fn honor_intent(state: &BitNamesState, intent: &ReservationIntent) -> Result<(), IntentError> {
+    intent.verify()?;
+    let key = intent.key;
+    if state.best_block_height > intent.expiry {
+        return Err(IntentError::Expired {
+            key,
+            expiry: intent.expiry,
+        });
+    }
+    match state.get_owner(&key)? {
+        None => Err(IntentError::NotRegistered { key }),
+        Some(owner) if owner != intent.address => Err(IntentError::WrongOwner {
+            key,
+            expected: intent.address,
+            actual: owner,
+        }),
+        Some(_) => Ok(()),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IntentError {
+    #[error("invalid signature on intent for key {key}")]
+    InvalidSignature { key: Key },
+    #[error("intent for key {key} expired at block {expiry}")]
+    Expired { key: Key, expiry: u32 },
+    #[error("key {key} is not registered")]
+    NotRegistered { key: Key },
+    #[error("key {key} was registered to {actual:?} instead of {expected:?}")]
+    WrongOwner {
+        key: Key,
+        expected: Address,
+        actual: Address,
+    },
+    #[error("state error")]
+    State(#[from] Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+
+    #[test]
+    fn honors_intent_once_registered_to_reserved_address() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (signer, alice, bob) = (chain.addresses[0], chain.addresses[1], chain.addresses[2]);
+        let key = key_of("intent.com");
+        let intent = ReservationIntent::new(&chain.keypairs[&signer], key, alice, 10);
+        assert!(matches!(
+            honor_intent(&chain.state, &intent),
+            Err(IntentError::NotRegistered { .. })
+        ));
+        chain.register(alice, "intent.com", 1).unwrap();
+        honor_intent(&chain.state, &intent).unwrap();
+
+        let other = ReservationIntent::new(&chain.keypairs[&signer], key, bob, 10);
+        assert!(matches!(
+            honor_intent(&chain.state, &other),
+            Err(IntentError::WrongOwner { actual, .. }) if actual == alice
+        ));
+        let expired = ReservationIntent::new(&chain.keypairs[&signer], key, alice, 1);
+        assert!(matches!(
+            honor_intent(&chain.state, &expired),
+            Err(IntentError::Expired { expiry: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn untagged_signature_is_rejected() {
+        let chain = TestChain::new(BitNamesParams::default());
+        let keypair = &chain.keypairs[&chain.addresses[0]];
+        let key = key_of("tagged.com");
+        let address = chain.addresses[1];
+        let mut intent = ReservationIntent::new(keypair, key, address, 10);
+        intent.verify().unwrap();
+        intent.signature = keypair.sign(&hash(&(key, address, 10u32)));
+        assert!(matches!(
+            intent.verify(),
+            Err(IntentError::InvalidSignature { .. })
+        ));
+    }
+}
@@ -1,22 +1,52 @@
 mod authorization;
+mod builder;
+mod codes;
+mod dns;
+mod doh;
+mod events;
+mod fees;
+mod harness;
 mod hashes;
+mod intents;
+mod mempool;
+mod name;
 mod nameserver;
+mod params;
 mod random;
+mod rpc;
+mod snapshot;
+mod storage;
+#[cfg(test)]
+mod testing;
 mod types;
+mod undo;
 mod validation;
+mod wallet;
 
 use anyhow::Result;
 use authorization::*;
+use builder::*;
 use fake::{Fake, Faker};
 use hashes::*;
+use name::*;
 use nameserver::*;
+use params::*;
 use random::*;
 use types::*;
 use validation::*;
 
 fn main() -> Result<()> {
     let env = new_env();
-    let mut state = BitNamesState::new(&env)?;
+    // Replay a harness run with `sdk_bitnames replay <seed> <steps>`.
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, seed, steps] = args.as_slice() {
+        if command == "replay" {
+            harness::replay(&env, seed.parse()?, steps.parse()?)?;
+            return Ok(());
+        }
+    }
+
+    let mut state = BitNamesState::new(&env, BitNamesParams::default())?;
 
     const NUM_KEYPAIRS: usize = 10;
     const NUM_DEPOSITS: usize = 2;
@@ -27,27 +57,25 @@ fn main() -> Result<()> {
     let keypairs = random_keypairs(NUM_KEYPAIRS);
     let addresses: Vec<Address> = keypairs.keys().copied().collect();
     let utxos = random_deposits(&addresses, DEPOSIT_VALUE, NUM_DEPOSITS);
-    let (inputs, spent_utxos, value_in) = random_inputs(&utxos, NUM_INPUTS);
+    let (inputs, spent_utxos, _) = random_inputs(&utxos, NUM_INPUTS);
 
-    let key: Key = hash(&"nytimes.com").into();
-    let value: Value = hash(&"151.101.193.164").into();
+    let name = Name::parse("nytimes.com")?;
+    let key = name.key();
+    let mut records = RecordSet::new();
+    records.insert(RecordType::A, "151.101.193.164");
+    let value = records.value();
     let salt: u64 = Faker.fake();
 
     state.connect_deposits(&utxos)?;
 
     let commitment_transaction = {
         let commitment = blake2b_hmac(&key, salt);
-        let outputs = vec![
-            Output {
-                address: addresses[0],
-                content: Content::Value(value_in - 10),
-            },
-            Output {
-                address: addresses[1],
-                content: Content::Custom(BitNamesOutput::Commitment(commitment)),
-            },
-        ];
-        let unsigned_transaction = Transaction { inputs, outputs };
+        let commit_output = Output {
+            address: addresses[1],
+            content: Content::Custom(BitNamesOutput::Commitment { commitment, fee: 0 }),
+        };
+        let unsigned_transaction =
+            commit_with_change(commit_output, addresses[0], inputs, &spent_utxos, 10)?;
         state.validate_transaction(&unsigned_transaction)?;
         authorize_transaction(&keypairs, &spent_utxos, unsigned_transaction)
     };
@@ -64,7 +92,13 @@ fn main() -> Result<()> {
         // let wrong_key: Key = hash(&"NyTimes.com").into();
         let outputs = vec![Output {
             address: addresses[2],
-            content: Content::Custom(BitNamesOutput::Reveal { salt, key }),
+            content: Content::Custom(BitNamesOutput::Reveal {
+                salt,
+                key,
+                name: Some(name.to_string()),
+                path: vec![],
+                value: None,
+            }),
         }];
         let unsigned_transaction = Transaction { inputs, outputs };
         state.validate_transaction(&unsigned_transaction)?;
@@ -96,17 +130,44 @@ fn main() -> Result<()> {
     let body = Body::new(vec![key_value_transaction], vec![]);
     state.connect_body(&body)?;
 
-    let mut nameserver = NameServer::default();
-    nameserver
-        .store(&state, "nytimes.com", "151.101.193.164")
-        .unwrap();
+    let mut nameserver = NameServer::open(&env)?;
+    nameserver.store(&state, &name, &records)?;
 
     dbg!(&nameserver);
 
-    let name = "nytimes.com";
     println!("looking up {name}");
-    let value = nameserver.lookup(&state, name).unwrap();
+    let value = nameserver.lookup(&state, &name, RecordType::A)?;
     println!("value = {value}");
+
+    // Serve the demo names with `sdk_bitnames doh <addr>`.
+    if let [_, command, addr] = args.as_slice() {
+        if command == "doh" {
+            doh::serve(std::net::TcpListener::bind(addr)?, &nameserver, &state)?;
+        }
+        // Or plain DNS with `sdk_bitnames dns <addr>`, usually port 53.
+        if command == "dns" {
+            dns::serve(addr.parse()?, &nameserver, &state)?;
+        }
+        // Or answer JSON-RPC with `sdk_bitnames rpc <addr>`.
+        if command == "rpc" {
+            let state = std::sync::Arc::new(std::sync::RwLock::new(state));
+            let context = rpc::RpcContext {
+                state: state.clone(),
+                mempool: Default::default(),
+                snapshots: snapshot::SnapshotService::spawn(
+                    state,
+                    snapshot::DEFAULT_SESSION_TIMEOUT,
+                ),
+            };
+            let runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(async {
+                let (addr, handle) = rpc::start(addr.parse()?, context).await?;
+                println!("serving json-rpc on {addr}");
+                handle.stopped().await;
+                anyhow::Ok(())
+            })?;
+        }
+    }
     Ok(())
 }
 
@@ -114,10 +175,5 @@ fn new_env() -> heed::Env {
     let env_path = std::path::Path::new("target").join("clear-database.mdb");
     let _ = std::fs::remove_dir_all(&env_path);
     std::fs::create_dir_all(&env_path).unwrap();
-    let env = heed::EnvOpenOptions::new()
-        .map_size(10 * 1024 * 1024) // 10MB
-        .max_dbs(6)
-        .open(env_path)
-        .unwrap();
-    env
+    storage::OpenOptions::default().open(&env_path).unwrap()
 }
@@ -0,0 +1,668 @@
+use crate::hashes::{Commitment, Key};
+use crate::types::*;
+use crate::validation::{verify_transaction_stateless, BitNamesState, Error};
+use sdk_types::{validate_transaction, MerkleRoot, Txid};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone)]
+struct PooledTransaction {
+    transaction: AuthorizedTransaction,
+    fee: u64,
+}
+
+/// Transactions waiting to be included in a body. Transactions may spend
+/// outputs of other pooled transactions.
+#[derive(Debug)]
+pub struct Mempool {
+    transactions: HashMap<Txid, PooledTransaction>,
+    /// Pooled transaction spending each outpoint.
+    spent_by: HashMap<OutPoint, Txid>,
+    /// Pooled txids in arrival order, which is the candidate body's order.
+    /// Each txid appears once.
+    order: Vec<Txid>,
+    /// Root of `select_body(usize::MAX)`, see `candidate_root`.
+    candidate_root: MerkleRoot,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self {
+            transactions: HashMap::new(),
+            spent_by: HashMap::new(),
+            order: vec![],
+            candidate_root: Body::new(vec![], vec![]).compute_merkle_root(),
+        }
+    }
+}
+
+impl Mempool {
+    /// Add `transaction` to the pool, returning the txids it evicted.
+    ///
+    /// A transaction spending an input of pooled transactions replaces them
+    /// and everything that depends on them, as long as it pays a higher fee
+    /// than all of the transactions it evicts together, so that replacing
+    /// never lowers the pool's fees. It can't spend an output of any of
+    /// them, since that would be gone along with them.
+    pub fn add_transaction(
+        &mut self,
+        state: &BitNamesState,
+        transaction: AuthorizedTransaction,
+    ) -> Result<Vec<Txid>, MempoolError> {
+        let txid = transaction.transaction.txid();
+        // Would otherwise only be caught by the double spend check, which
+        // transactions without inputs pass.
+        if self.transactions.contains_key(&txid) {
+            return Err(MempoolError::AlreadyPooled { txid });
+        }
+        verify_transaction_stateless(&transaction)?;
+        let fee = self.fee(state, &transaction.transaction)?;
+        let conflicts: HashSet<Txid> = transaction
+            .transaction
+            .inputs
+            .iter()
+            .filter_map(|input| self.spent_by.get(input).copied())
+            .collect();
+        let mut replaced = HashSet::new();
+        for conflict in &conflicts {
+            replaced.extend(self.with_descendants(conflict));
+        }
+        if let Some(outpoint) = transaction.transaction.inputs.iter().find(
+            |input| matches!(input, OutPoint::Regular { txid, .. } if replaced.contains(txid)),
+        ) {
+            return Err(MempoolError::SpendsReplaced {
+                outpoint: *outpoint,
+            });
+        }
+        let replaced_fee: u64 = replaced
+            .iter()
+            .map(|txid| self.transactions[txid].fee)
+            .sum();
+        if !conflicts.is_empty() && fee <= replaced_fee {
+            return Err(MempoolError::ReplacementUnderpriced { fee, replaced_fee });
+        }
+        let mut evicted = vec![];
+        for txid in conflicts {
+            evicted.extend(self.remove_transaction(&txid));
+        }
+        for input in &transaction.transaction.inputs {
+            self.spent_by.insert(*input, txid);
+        }
+        self.transactions
+            .insert(txid, PooledTransaction { transaction, fee });
+        self.order.push(txid);
+        self.refresh_candidate_root();
+        Ok(evicted)
+    }
+
+    /// Remove a transaction and every pooled transaction that depends on
+    /// it, returning the txids removed.
+    pub fn remove_transaction(&mut self, txid: &Txid) -> Vec<Txid> {
+        let removed = self.remove_with_descendants(txid);
+        if !removed.is_empty() {
+            self.rebuild_order();
+        }
+        removed
+    }
+
+    /// Drop the transactions of `body` once it is connected to `state`, and
+    /// evict what it invalidated along with everything depending on it:
+    /// pooled transactions spending an input the body spent, and ones
+    /// spending only confirmed outputs that no longer validate against
+    /// `state`, e.g. reveals of commitments that have since expired.
+    /// Returns the evicted txids, not counting the confirmed ones.
+    pub fn remove_confirmed(&mut self, state: &BitNamesState, body: &Body) -> Vec<Txid> {
+        let mut evicted = vec![];
+        for transaction in &body.transactions {
+            // Its outputs are confirmed now, so transactions spending them
+            // stay.
+            if let Some(pooled) = self.transactions.remove(&transaction.txid()) {
+                for input in &pooled.transaction.transaction.inputs {
+                    self.spent_by.remove(input);
+                }
+            }
+            for input in &transaction.inputs {
+                if let Some(conflict) = self.spent_by.get(input).copied() {
+                    evicted.extend(self.remove_with_descendants(&conflict));
+                }
+            }
+        }
+        let invalid: Vec<Txid> = self
+            .order
+            .iter()
+            .filter(|txid| {
+                let Some(pooled) = self.transactions.get(*txid) else {
+                    return false;
+                };
+                let transaction = &pooled.transaction.transaction;
+                let confirmed = transaction
+                    .inputs
+                    .iter()
+                    .all(|input| self.pooled_output(input).is_none());
+                confirmed && state.validate_transaction(transaction).is_err()
+            })
+            .copied()
+            .collect();
+        for txid in invalid {
+            evicted.extend(self.remove_with_descendants(&txid));
+        }
+        self.rebuild_order();
+        evicted
+    }
+
+    /// Drop removed transactions from `order` and recompute the candidate
+    /// root.
+    fn rebuild_order(&mut self) {
+        self.order
+            .retain(|txid| self.transactions.contains_key(txid));
+        self.refresh_candidate_root();
+    }
+
+    fn refresh_candidate_root(&mut self) {
+        self.candidate_root = self.select_body(usize::MAX).compute_merkle_root();
+    }
+
+    /// `txid` and every pooled transaction depending on it.
+    fn with_descendants(&self, txid: &Txid) -> Vec<Txid> {
+        let Some(pooled) = self.transactions.get(txid) else {
+            return vec![];
+        };
+        let mut found = vec![*txid];
+        for vout in 0..pooled.transaction.transaction.outputs.len() {
+            let outpoint = OutPoint::Regular {
+                txid: *txid,
+                vout: vout as u32,
+            };
+            if let Some(child) = self.spent_by.get(&outpoint) {
+                found.extend(self.with_descendants(child));
+            }
+        }
+        found
+    }
+
+    fn remove_with_descendants(&mut self, txid: &Txid) -> Vec<Txid> {
+        let Some(pooled) = self.transactions.remove(txid) else {
+            return vec![];
+        };
+        for input in &pooled.transaction.transaction.inputs {
+            self.spent_by.remove(input);
+        }
+        let mut removed = vec![*txid];
+        for vout in 0..pooled.transaction.transaction.outputs.len() {
+            let outpoint = OutPoint::Regular {
+                txid: *txid,
+                vout: vout as u32,
+            };
+            if let Some(child) = self.spent_by.get(&outpoint).copied() {
+                removed.extend(self.remove_with_descendants(&child));
+            }
+        }
+        removed
+    }
+
+    /// Everything pooled that `transaction` conflicts with. Adding it would
+    /// replace the transactions it double spends against.
+    pub fn conflicts(&self, transaction: &Transaction) -> Vec<Conflict> {
+        let mut conflicts: Vec<Conflict> = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| {
+                let txid = self.spent_by.get(input)?;
+                Some(Conflict::DoubleSpend {
+                    outpoint: *input,
+                    txid: *txid,
+                })
+            })
+            .collect();
+        let commitments: HashSet<Commitment> = transaction
+            .outputs
+            .iter()
+            .filter_map(|output| match &output.content {
+                Content::Custom(output) => output.commitment(),
+                _ => None,
+            })
+            .collect();
+        if commitments.is_empty() {
+            return conflicts;
+        }
+        for (txid, pooled) in &self.transactions {
+            for output in &pooled.transaction.transaction.outputs {
+                if let Content::Custom(BitNamesOutput::Commitment { commitment, .. }) =
+                    &output.content
+                {
+                    if commitments.contains(commitment) {
+                        conflicts.push(Conflict::DuplicateCommitment {
+                            commitment: *commitment,
+                            txid: *txid,
+                        });
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Merkle root a block would commit to with the candidate body, every
+    /// pooled transaction `select_body` would include, as computed by
+    /// `Body::compute_merkle_root`. It is worked out whenever the pool
+    /// changes, so reading it is free.
+    pub fn candidate_root(&self) -> &MerkleRoot {
+        &self.candidate_root
+    }
+
+    /// Body of up to `max_count` pooled transactions for block assembly,
+    /// oldest first. Transactions only ever arrive after the pooled ones
+    /// they spend from, so parents always come before their children. The
+    /// pool can hold transactions that can't share a body, e.g. two making
+    /// the same commitment or revealing the same key, so a transaction
+    /// clashing with one already selected is left out, along with its
+    /// descendants.
+    pub fn select_body(&self, max_count: usize) -> Body {
+        let mut transactions = vec![];
+        let mut selected = HashSet::new();
+        let mut commitments = HashSet::new();
+        let mut keys = HashSet::new();
+        for txid in &self.order {
+            if transactions.len() == max_count {
+                break;
+            }
+            let transaction = &self.transactions[txid].transaction;
+            let inputs = &transaction.transaction.inputs;
+            let unselected_parent = inputs.iter().any(|input| match input {
+                OutPoint::Regular { txid, .. } => {
+                    self.transactions.contains_key(txid) && !selected.contains(txid)
+                }
+                _ => false,
+            });
+            if unselected_parent {
+                continue;
+            }
+            // Keys carried on from a selected parent are the same name
+            // moving along, not a second claim on it.
+            let inherited: HashSet<Key> = inputs
+                .iter()
+                .filter_map(|input| match self.pooled_output(input)?.content {
+                    Content::Custom(output) => output.key(),
+                    _ => None,
+                })
+                .collect();
+            let outputs = transaction
+                .transaction
+                .outputs
+                .iter()
+                .filter_map(|output| match &output.content {
+                    Content::Custom(output) => Some(output),
+                    _ => None,
+                });
+            let made: Vec<Commitment> = outputs
+                .clone()
+                .filter_map(|output| output.commitment())
+                .collect();
+            let claimed: Vec<Key> = outputs
+                .filter_map(|output| output.key())
+                .filter(|key| !inherited.contains(key))
+                .collect();
+            if made
+                .iter()
+                .any(|commitment| commitments.contains(commitment))
+                || claimed.iter().any(|key| keys.contains(key))
+            {
+                continue;
+            }
+            commitments.extend(made);
+            keys.extend(claimed);
+            selected.insert(*txid);
+            transactions.push(transaction.clone());
+        }
+        Body::new(transactions, vec![])
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&AuthorizedTransaction> {
+        self.transactions
+            .get(txid)
+            .map(|pooled| &pooled.transaction)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Fee of `transaction`. Transactions spending only confirmed outputs
+    /// are fully validated against `state`, others only have their value
+    /// checked since their BitNames inputs aren't in the state yet.
+    fn fee(&self, state: &BitNamesState, transaction: &Transaction) -> Result<u64, MempoolError> {
+        let mut spent_utxos = vec![];
+        let mut confirmed = true;
+        for input in &transaction.inputs {
+            if let Some(output) = self.pooled_output(input) {
+                confirmed = false;
+                spent_utxos.push(output);
+            } else if let Some(output) = state.get_utxo(input)? {
+                spent_utxos.push(output);
+            } else {
+                return Err(MempoolError::MissingInput { outpoint: *input });
+            }
+        }
+        if confirmed {
+            Ok(state.validate_transaction(transaction)?)
+        } else {
+            Ok(validate_transaction(&spent_utxos, transaction)?)
+        }
+    }
+
+    fn pooled_output(&self, outpoint: &OutPoint) -> Option<Output> {
+        let OutPoint::Regular { txid, vout } = outpoint else {
+            return None;
+        };
+        let pooled = self.transactions.get(txid)?;
+        pooled
+            .transaction
+            .transaction
+            .outputs
+            .get(*vout as usize)
+            .cloned()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// `outpoint` is already spent by pooled transaction `txid`.
+    DoubleSpend { outpoint: OutPoint, txid: Txid },
+    /// Pooled transaction `txid` already makes `commitment`.
+    DuplicateCommitment { commitment: Commitment, txid: Txid },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MempoolError {
+    #[error("transaction {txid:?} is already pooled")]
+    AlreadyPooled { txid: Txid },
+    #[error("replacement fee {fee} doesn't exceed replaced fee {replaced_fee}")]
+    ReplacementUnderpriced { fee: u64, replaced_fee: u64 },
+    #[error("replacement spends {outpoint:?}, an output of a transaction it replaces")]
+    SpendsReplaced { outpoint: OutPoint },
+    #[error("input {outpoint:?} is neither confirmed nor pooled")]
+    MissingInput { outpoint: OutPoint },
+    #[error("sdk error")]
+    Sdk(#[from] sdk_types::Error),
+    #[error("state error")]
+    State(#[from] Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashes::blake2b_hmac;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+
+    fn pay(chain: &TestChain, input: OutPoint, value: u64) -> AuthorizedTransaction {
+        let output = Output {
+            address: chain.addresses[1],
+            content: Content::Value(value),
+        };
+        chain.sign(vec![input], vec![output])
+    }
+
+    #[test]
+    fn rejects_conflicting_transaction() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let deposit = chain.deposit(chain.addresses[0], 10);
+        let first = pay(&chain, deposit, 5);
+        let second = pay(&chain, deposit, 6);
+        let mut mempool = Mempool::default();
+        mempool
+            .add_transaction(&chain.state, first.clone())
+            .unwrap();
+
+        let first_txid = first.transaction.txid();
+        assert_eq!(
+            mempool.conflicts(&second.transaction),
+            vec![Conflict::DoubleSpend {
+                outpoint: deposit,
+                txid: first_txid,
+            }]
+        );
+        assert!(matches!(
+            mempool.add_transaction(&chain.state, second),
+            Err(MempoolError::ReplacementUnderpriced {
+                fee: 4,
+                replaced_fee: 5,
+            })
+        ));
+        assert!(matches!(
+            mempool.add_transaction(&chain.state, first),
+            Err(MempoolError::AlreadyPooled { txid }) if txid == first_txid
+        ));
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.select_body(10).transactions.len(), 1);
+    }
+
+    #[test]
+    fn reports_duplicate_commitments() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let first = chain.commit_tx(alice, "dup.com", 1);
+        let mut mempool = Mempool::default();
+        mempool
+            .add_transaction(&chain.state, first.clone())
+            .unwrap();
+
+        let copy = chain.commit_tx(bob, "dup.com", 1);
+        assert_eq!(
+            mempool.conflicts(&copy.transaction),
+            vec![Conflict::DuplicateCommitment {
+                commitment: blake2b_hmac(&key_of("dup.com"), 1),
+                txid: first.transaction.txid(),
+            }]
+        );
+        let fresh = chain.commit_tx(bob, "dup.com", 2);
+        assert!(mempool.conflicts(&fresh.transaction).is_empty());
+    }
+
+    /// Transaction spending output 0 of `parent`, paying `value` of it on.
+    fn pay_pooled(
+        chain: &TestChain,
+        parent: &AuthorizedTransaction,
+        value: u64,
+    ) -> AuthorizedTransaction {
+        let input = OutPoint::Regular {
+            txid: parent.transaction.txid(),
+            vout: 0,
+        };
+        let transaction = Transaction {
+            inputs: vec![input],
+            outputs: vec![Output {
+                address: chain.addresses[2],
+                content: Content::Value(value),
+            }],
+        };
+        let spent = vec![parent.transaction.outputs[0].clone()];
+        crate::authorization::authorize_transaction(&chain.keypairs, &spent, transaction)
+    }
+
+    #[test]
+    fn replacement_pays_for_evicted_descendants() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let deposit = chain.deposit(chain.addresses[0], 10);
+        let parent = pay(&chain, deposit, 8);
+        let child = pay_pooled(&chain, &parent, 5);
+        let mut mempool = Mempool::default();
+        mempool
+            .add_transaction(&chain.state, parent.clone())
+            .unwrap();
+        mempool
+            .add_transaction(&chain.state, child.clone())
+            .unwrap();
+
+        // Beats the parent's fee of 2, but not that of 5 with its child.
+        assert!(matches!(
+            mempool.add_transaction(&chain.state, pay(&chain, deposit, 7)),
+            Err(MempoolError::ReplacementUnderpriced {
+                fee: 3,
+                replaced_fee: 5,
+            })
+        ));
+        assert_eq!(mempool.len(), 2);
+        let evicted: HashSet<Txid> = mempool
+            .add_transaction(&chain.state, pay(&chain, deposit, 4))
+            .unwrap()
+            .into_iter()
+            .collect();
+        let expected = HashSet::from([parent.transaction.txid(), child.transaction.txid()]);
+        assert_eq!(evicted, expected);
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn replacement_cant_spend_what_it_evicts() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let deposit = chain.deposit(chain.addresses[0], 10);
+        let parent = pay(&chain, deposit, 8);
+        let mut mempool = Mempool::default();
+        mempool
+            .add_transaction(&chain.state, parent.clone())
+            .unwrap();
+
+        let parent_output = OutPoint::Regular {
+            txid: parent.transaction.txid(),
+            vout: 0,
+        };
+        let replacement = crate::authorization::authorize_transaction(
+            &chain.keypairs,
+            &[
+                chain.state.get_utxo(&deposit).unwrap().unwrap(),
+                parent.transaction.outputs[0].clone(),
+            ],
+            Transaction {
+                inputs: vec![deposit, parent_output],
+                outputs: vec![Output {
+                    address: chain.addresses[2],
+                    content: Content::Value(1),
+                }],
+            },
+        );
+        assert!(matches!(
+            mempool.add_transaction(&chain.state, replacement),
+            Err(MempoolError::SpendsReplaced { outpoint }) if outpoint == parent_output
+        ));
+        assert!(mempool.get(&parent.transaction.txid()).is_some());
+    }
+
+    #[test]
+    fn remove_confirmed_evicts_conflicts_and_keeps_descendants() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let deposit = chain.deposit(chain.addresses[0], 10);
+        let other = chain.deposit(chain.addresses[0], 10);
+        let confirmed = pay(&chain, deposit, 8);
+        let child = {
+            let input = OutPoint::Regular {
+                txid: confirmed.transaction.txid(),
+                vout: 0,
+            };
+            let transaction = Transaction {
+                inputs: vec![input],
+                outputs: vec![Output {
+                    address: chain.addresses[2],
+                    content: Content::Value(7),
+                }],
+            };
+            let spent = vec![confirmed.transaction.outputs[0].clone()];
+            crate::authorization::authorize_transaction(&chain.keypairs, &spent, transaction)
+        };
+        let conflicting = pay(&chain, other, 5);
+        let mut mempool = Mempool::default();
+        mempool
+            .add_transaction(&chain.state, confirmed.clone())
+            .unwrap();
+        mempool
+            .add_transaction(&chain.state, child.clone())
+            .unwrap();
+        mempool
+            .add_transaction(&chain.state, conflicting.clone())
+            .unwrap();
+
+        // The block confirms the first transaction and double spends the
+        // last one.
+        let double_spend = pay(&chain, other, 4);
+        let body = chain.connect(vec![confirmed, double_spend]).unwrap();
+        let evicted = mempool.remove_confirmed(&chain.state, &body);
+        assert_eq!(evicted, vec![conflicting.transaction.txid()]);
+        assert_eq!(mempool.len(), 1);
+        let child_txid = child.transaction.txid();
+        assert!(mempool.get(&child_txid).is_some());
+        let candidate = mempool.select_body(10);
+        assert_eq!(candidate.transactions.len(), 1);
+        assert_eq!(*mempool.candidate_root(), candidate.compute_merkle_root());
+    }
+
+    #[test]
+    fn candidate_root_tracks_the_selected_body() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let mut mempool = Mempool::default();
+        let check = |mempool: &Mempool| {
+            let body = mempool.select_body(usize::MAX);
+            assert_eq!(*mempool.candidate_root(), body.compute_merkle_root());
+        };
+        check(&mempool);
+        let mut txids = vec![];
+        for index in 0..5 {
+            let deposit = chain.deposit(chain.addresses[0], 10);
+            let transaction = pay(&chain, deposit, 10 - index);
+            txids.push(transaction.transaction.txid());
+            mempool.add_transaction(&chain.state, transaction).unwrap();
+            check(&mempool);
+        }
+        for txid in [txids[3], txids[0], txids[4]] {
+            mempool.remove_transaction(&txid);
+            check(&mempool);
+        }
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn selected_body_leaves_out_clashing_transactions() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        // Both make the same commitment, from different deposits.
+        let first = chain.commit_tx(alice, "clash.com", 1);
+        let second = chain.commit_tx(bob, "clash.com", 1);
+        let child = {
+            let input = OutPoint::Regular {
+                txid: second.transaction.txid(),
+                vout: 0,
+            };
+            let transaction = Transaction {
+                inputs: vec![input],
+                outputs: vec![Output {
+                    address: bob,
+                    content: Content::Value(0),
+                }],
+            };
+            let spent = vec![second.transaction.outputs[0].clone()];
+            crate::authorization::authorize_transaction(&chain.keypairs, &spent, transaction)
+        };
+        let other = chain.commit_tx(alice, "other.com", 1);
+        let mut mempool = Mempool::default();
+        for transaction in [&first, &second, &child, &other] {
+            mempool
+                .add_transaction(&chain.state, transaction.clone())
+                .unwrap();
+        }
+        assert_eq!(mempool.len(), 4);
+
+        let body = mempool.select_body(10);
+        let txids: Vec<Txid> = body.transactions.iter().map(Transaction::txid).collect();
+        assert_eq!(
+            txids,
+            vec![first.transaction.txid(), other.transaction.txid()]
+        );
+        chain
+            .state
+            .validate_body(chain.height() + 1, &body)
+            .unwrap();
+        chain.state.connect_body(&body).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+use crate::hashes::*;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NameError {
+    #[error("name is empty")]
+    Empty,
+    #[error("name {name} starts with a dot")]
+    LeadingDot { name: String },
+    #[error("name {name} has an empty label")]
+    EmptyLabel { name: String },
+}
+
+/// A validated name in canonical form, the only thing keys are derived from.
+/// The key is computed once on parse, so a `Name` can be looked up
+/// repeatedly without rehashing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Name {
+    name: String,
+    key: Key,
+}
+
+impl Name {
+    pub fn parse(name: &str) -> Result<Self, NameError> {
+        let name = normalize(name)?;
+        let key = hash(&name).into();
+        Ok(Self { name, key })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// Key the name is registered under, which is the hash of its canonical
+    /// form.
+    pub fn key(&self) -> Key {
+        self.key
+    }
+
+    /// The name without its first label, `None` for a single label.
+    pub fn parent(&self) -> Option<Name> {
+        let (_, parent) = self.name.split_once('.')?;
+        Some(Self {
+            name: parent.into(),
+            key: hash(&parent).into(),
+        })
+    }
+}
+
+impl std::fmt::Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Canonical form of a name: lowercase and without the trailing dot of a
+/// fully qualified name, so `Example.com.` and `example.com` are one name.
+fn normalize(name: &str) -> Result<String, NameError> {
+    let dotless = name.strip_suffix('.').unwrap_or(name);
+    if dotless.is_empty() {
+        return Err(NameError::Empty);
+    }
+    if dotless.starts_with('.') {
+        return Err(NameError::LeadingDot { name: name.into() });
+    }
+    if dotless.split('.').any(str::is_empty) {
+        return Err(NameError::EmptyLabel { name: name.into() });
+    }
+    Ok(dotless.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spellings_of_a_name_share_its_key() {
+        let canonical = Name::parse("example.com").unwrap();
+        for spelling in ["Example.COM", "example.com.", "EXAMPLE.com."] {
+            let name = Name::parse(spelling).unwrap();
+            assert_eq!(name, canonical, "{spelling}");
+            assert_eq!(name.key(), Key::from(hash(&"example.com")));
+        }
+        assert_ne!(Name::parse("example.org").unwrap().key(), canonical.key());
+    }
+
+    #[test]
+    fn rejects_names_without_canonical_form() {
+        assert_eq!(Name::parse(""), Err(NameError::Empty));
+        assert_eq!(Name::parse("."), Err(NameError::Empty));
+        assert_eq!(
+            Name::parse(".example.com"),
+            Err(NameError::LeadingDot {
+                name: ".example.com".into()
+            })
+        );
+        assert_eq!(
+            Name::parse("example..com"),
+            Err(NameError::EmptyLabel {
+                name: "example..com".into()
+            })
+        );
+    }
+
+    #[test]
+    fn parent_drops_the_first_label() {
+        let name = Name::parse("WWW.Example.com.").unwrap();
+        assert_eq!(name.to_string(), "www.example.com");
+        let parent = name.parent().unwrap();
+        assert_eq!(parent, Name::parse("example.com").unwrap());
+        assert_eq!(parent.key(), Name::parse("example.com").unwrap().key());
+        assert_eq!(parent.parent().unwrap().as_str(), "com");
+        assert_eq!(Name::parse("com").unwrap().parent(), None);
+    }
+}
@@ -1,48 +1,839 @@
+use crate::events::Event;
 use crate::hashes::*;
-use crate::validation::BitNamesState;
+use crate::name::{Name, NameError};
+use crate::validation::{BitNamesState, Error};
+use heed::types::*;
+use heed::Database;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
 use std::collections::HashMap;
 
-#[derive(Debug, Default)]
 pub struct NameServer {
-    data: HashMap<Key, String>,
+    env: heed::Env,
+    /// Plaintext records by key, persisted so they survive restarts. They are
+    /// checked against the on-chain value hash on every read.
+    data: Database<SerdeBincode<Key>, SerdeBincode<(String, RecordSet)>>,
+    /// Plaintexts of weighted values, by their hash.
+    weighted: HashMap<Value, (RecordType, String)>,
+    /// On-chain value hashes loaded by `warm_from_state`, good for as long
+    /// as the state is still at the stored generation.
+    warm: Option<(u32, HashMap<Key, Option<Value>>)>,
+    /// Names with each stored A or AAAA address, for reverse lookups.
+    value_to_primary_name: HashMap<String, Vec<Name>>,
+    /// Height each key was last transferred at, until its new owner sets a
+    /// value. The old owner's records aren't served in the meantime, for up
+    /// to `transfer_grace_period` blocks.
+    transferred: HashMap<Key, u32>,
+    transfer_grace_period: u32,
 }
 
+const DEFAULT_TRANSFER_GRACE_PERIOD: u32 = 100;
+
 impl NameServer {
-    pub fn store(&mut self, state: &BitNamesState, name: &str, value: &str) -> Result<(), String> {
-        let key: Key = hash(&name).into();
-        if let Some(value_hash) = state.get_value(&key).unwrap() {
-            if let Some(value_hash) = value_hash {
-                if Value::from(hash(&value)) != value_hash {
-                    return Err(format!("attempting to store value that doesn't match hash: blake2b({value}) != {value_hash}"));
+    /// Open the records stored in `env` by a previous run, which can be the
+    /// environment the state lives in.
+    pub fn open(env: &heed::Env) -> Result<Self, heed::Error> {
+        let data: Database<SerdeBincode<Key>, SerdeBincode<(String, RecordSet)>> =
+            env.create_database(Some("nameserver_records"))?;
+        let mut value_to_primary_name: HashMap<String, Vec<Name>> = HashMap::new();
+        let rtxn = env.read_txn()?;
+        for item in data.iter(&rtxn)? {
+            let (_, (name, records)) = item?;
+            let Ok(name) = Name::parse(&name) else {
+                continue;
+            };
+            for address in addresses(&records) {
+                value_to_primary_name
+                    .entry(address.into())
+                    .or_default()
+                    .push(name.clone());
+            }
+        }
+        drop(rtxn);
+        Ok(Self {
+            env: env.clone(),
+            data,
+            weighted: HashMap::new(),
+            warm: None,
+            value_to_primary_name,
+            transferred: HashMap::new(),
+            transfer_grace_period: DEFAULT_TRANSFER_GRACE_PERIOD,
+        })
+    }
+
+    /// `open`, then `warm_from_state` if nothing has been stored yet, so a
+    /// first run after switching from an in-memory server answers from the
+    /// cache right away. Only hashes are on chain, so records the old server
+    /// held still have to be `store`d again.
+    pub fn open_and_warm(env: &heed::Env, state: &BitNamesState) -> Result<Self, Error> {
+        let mut nameserver = Self::open(env)?;
+        let rtxn = env.read_txn()?;
+        let is_empty = nameserver.data.is_empty(&rtxn)?;
+        drop(rtxn);
+        if is_empty {
+            nameserver.warm_from_state(state)?;
+        }
+        Ok(nameserver)
+    }
+
+    pub fn with_transfer_grace_period(mut self, blocks: u32) -> Self {
+        self.transfer_grace_period = blocks;
+        self
+    }
+
+    /// Keep up with the chain, fed with the events of connected bodies.
+    pub fn handle_event(&mut self, event: &Event) {
+        match event {
+            Event::NameTransferred { key, height, .. } => {
+                self.transferred.insert(*key, *height);
+            }
+            Event::ValueUpdated { key, .. } => {
+                self.transferred.remove(key);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether `key` moved recently enough that its records may still be
+    /// the previous owner's.
+    fn awaiting_new_owner(&self, state: &BitNamesState, key: &Key) -> bool {
+        self.transferred.get(key).map_or(false, |height| {
+            state.best_block_height.saturating_sub(*height) < self.transfer_grace_period
+        })
+    }
+
+    fn get_record(&self, key: &Key) -> Result<Option<(String, RecordSet)>, heed::Error> {
+        let rtxn = self.env.read_txn()?;
+        self.data.get(&rtxn, key)
+    }
+
+    /// Load every registered key's value hash in one pass, so lookups don't
+    /// read the state until it changes. Only hashes are on chain, plaintexts
+    /// still have to be `store`d. Returns the number of keys with a value.
+    pub fn warm_from_state(&mut self, state: &BitNamesState) -> Result<usize, Error> {
+        let names: HashMap<Key, Option<Value>> = state.iter_names()?.into_iter().collect();
+        let loaded = names.values().filter(|value| value.is_some()).count();
+        self.warm = Some((state.current_generation()?, names));
+        Ok(loaded)
+    }
+
+    /// On-chain value of `key`: `None` if it isn't registered, `Some(None)`
+    /// if it is but no value has been set yet.
+    fn get_value(
+        &self,
+        state: &BitNamesState,
+        key: &Key,
+    ) -> Result<Option<Option<Value>>, NameServerError> {
+        match &self.warm {
+            Some((generation, names)) if *generation == state.current_generation()? => {
+                Ok(names.get(key).copied())
+            }
+            _ => Ok(state.get_value(key)?),
+        }
+    }
+
+    /// Store the records of `name`, which have to hash to its on-chain value
+    /// as a whole. A name registered without a value yet accepts any
+    /// records, which are served once a value matching them is set.
+    pub fn store(
+        &mut self,
+        state: &BitNamesState,
+        name: &Name,
+        records: &RecordSet,
+    ) -> Result<(), NameServerError> {
+        let key = name.key();
+        match self.get_value(state, &key)? {
+            None => {
+                return Err(NameServerError::NotRegistered {
+                    name: name.to_string(),
+                })
+            }
+            Some(Some(value_hash)) if records.value() != value_hash => {
+                return Err(NameServerError::ValueMismatch {
+                    name: name.to_string(),
+                    records: records.value(),
+                    value: value_hash,
+                });
+            }
+            Some(_) => {}
+        }
+        let mut wtxn = self.env.write_txn()?;
+        let prev = self.data.get(&wtxn, &key)?;
+        self.data
+            .put(&mut wtxn, &key, &(name.to_string(), records.clone()))?;
+        wtxn.commit()?;
+        if let Some((_, prev_records)) = prev {
+            for address in addresses(&prev_records) {
+                if let Some(names) = self.value_to_primary_name.get_mut(address) {
+                    names.retain(|prev_name| prev_name != name);
                 }
-                self.data.insert(key, value.into());
-                Ok(())
-            } else {
-                Err(format!(
-                    "{name} is registered, but it has no associated value hash"
-                ))
             }
-        } else {
-            Err(format!("{name} is not registered"))
         }
+        for address in addresses(records) {
+            self.value_to_primary_name
+                .entry(address.into())
+                .or_default()
+                .push(name.clone());
+        }
+        Ok(())
+    }
+
+    /// Store the plaintext of one of the weighted values of `name`.
+    pub fn store_weighted(
+        &mut self,
+        state: &BitNamesState,
+        name: &Name,
+        record_type: RecordType,
+        value: &str,
+    ) -> Result<(), NameServerError> {
+        let key = name.key();
+        let values =
+            state
+                .get_weighted_values(&key)?
+                .ok_or_else(|| NameServerError::NoWeightedValues {
+                    name: name.to_string(),
+                })?;
+        let value_hash = hash_value(record_type, value.as_bytes());
+        if !values.iter().any(|(value, _)| *value == value_hash) {
+            return Err(NameServerError::NotWeightedValue {
+                name: name.to_string(),
+                value: value_hash,
+            });
+        }
+        self.weighted
+            .insert(value_hash, (record_type, value.into()));
+        Ok(())
+    }
+
+    /// Pick one of the weighted values of `name` of type `record_type` in
+    /// proportion to its weight, among those whose plaintext is stored.
+    /// Names without weighted values resolve like `lookup`.
+    pub fn resolve(
+        &self,
+        state: &BitNamesState,
+        name: &Name,
+        record_type: RecordType,
+        rng: &mut impl Rng,
+    ) -> Result<String, NameServerError> {
+        let key = name.key();
+        let Some(values) = state.get_weighted_values(&key)? else {
+            return self.lookup(state, name, record_type);
+        };
+        let (values, weights): (Vec<&String>, Vec<u16>) = values
+            .iter()
+            .filter_map(|(value, weight)| {
+                let (value_type, value) = self.weighted.get(value)?;
+                (*value_type == record_type).then_some((value, *weight))
+            })
+            .unzip();
+        let index =
+            WeightedIndex::new(&weights).map_err(|_| NameServerError::NoStoredWeightedValues {
+                name: name.to_string(),
+            })?;
+        Ok(values[index.sample(rng)].clone())
     }
 
-    pub fn lookup(&self, state: &BitNamesState, name: &str) -> Result<String, String> {
-        let key: Key = hash(&name).into();
-        if let Some(value_hash) = state.get_value(&key).unwrap() {
-            if let Some(value_hash) = value_hash {
-                let value = self.data[&key].clone();
-                if Value::from(hash(&value)) != value_hash {
-                    return Err(format!("store has invalid value for {key}"));
+    /// Names whose A or AAAA record points at `ip`, skipping any whose
+    /// stored records no longer match the chain.
+    pub fn reverse_lookup(&self, state: &BitNamesState, ip: &str) -> Result<Vec<String>, Error> {
+        let Some(names) = self.value_to_primary_name.get(ip) else {
+            return Ok(vec![]);
+        };
+        let mut matches = vec![];
+        for name in names {
+            let key = name.key();
+            let Some((_, records)) = self.get_record(&key)? else {
+                continue;
+            };
+            if state.get_value(&key)? == Some(Some(records.value())) {
+                matches.push(name.to_string());
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Stored records whose plaintext satisfies `pred`, e.g. every address
+    /// in a subnet, skipping names whose records no longer match the chain.
+    /// The chain only holds value hashes, so only stored plaintexts can be
+    /// searched.
+    pub fn names_matching_value<F: Fn(&[u8]) -> bool>(
+        &self,
+        state: &BitNamesState,
+        pred: F,
+    ) -> Result<Vec<(Key, Vec<u8>)>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut matches = vec![];
+        for item in self.data.iter(&rtxn)? {
+            let (key, (_, records)) = item?;
+            if state.get_value(&key)? != Some(Some(records.value())) {
+                continue;
+            }
+            for (_, value) in records.iter() {
+                if pred(value.as_bytes()) {
+                    matches.push((key, value.as_bytes().to_vec()));
                 }
-                Ok(value)
-            } else {
-                Err(format!(
-                    "{name} is registered, but it has no associated value hash"
-                ))
-            }
-        } else {
-            Err(format!("{name} is not registered"))
+            }
+        }
+        Ok(matches)
+    }
+
+    /// `lookup` for a name that hasn't been parsed yet. Hot paths should
+    /// parse once and keep the `Name`, which carries its key.
+    pub fn lookup_str(
+        &self,
+        state: &BitNamesState,
+        name: &str,
+        record_type: RecordType,
+    ) -> Result<String, NameServerError> {
+        let name = Name::parse(name)?;
+        self.lookup(state, &name, record_type)
+    }
+
+    /// Record of `record_type` for `name`, `Ok(None)` if its records don't
+    /// include one.
+    pub fn lookup_record(
+        &self,
+        state: &BitNamesState,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<Option<String>, NameServerError> {
+        let records = self.lookup_records(state, name)?;
+        Ok(records.get(record_type).map(String::from))
+    }
+
+    pub fn lookup(
+        &self,
+        state: &BitNamesState,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<String, NameServerError> {
+        self.lookup_record(state, name, record_type)?
+            .ok_or_else(|| NameServerError::NoRecord {
+                name: name.to_string(),
+                record_type,
+            })
+    }
+
+    pub fn lookup_records(
+        &self,
+        state: &BitNamesState,
+        name: &Name,
+    ) -> Result<RecordSet, NameServerError> {
+        let key = name.key();
+        let value_hash = match self.get_value(state, &key)? {
+            None => {
+                return Err(NameServerError::NotRegistered {
+                    name: name.to_string(),
+                })
+            }
+            Some(None) => {
+                return Err(NameServerError::NoValue {
+                    name: name.to_string(),
+                })
+            }
+            Some(Some(value_hash)) => value_hash,
+        };
+        if self.awaiting_new_owner(state, &key) {
+            return Err(NameServerError::AwaitingNewOwner {
+                name: name.to_string(),
+            });
+        }
+        let (_, records) =
+            self.get_record(&key)?
+                .ok_or_else(|| NameServerError::MissingLocalRecord {
+                    key,
+                    name: name.to_string(),
+                })?;
+        if records.value() != value_hash {
+            return Err(NameServerError::StaleRecords { key });
+        }
+        Ok(records)
+    }
+}
+
+/// A and AAAA records of `records`, the ones reverse lookups go by.
+fn addresses(records: &RecordSet) -> impl Iterator<Item = &str> {
+    records
+        .iter()
+        .filter(|(record_type, _)| matches!(record_type, RecordType::A | RecordType::Aaaa))
+        .map(|(_, address)| address)
+}
+
+impl std::fmt::Debug for NameServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NameServer")
+            .field("weighted", &self.weighted)
+            .field("warm", &self.warm)
+            .field("value_to_primary_name", &self.value_to_primary_name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NameServerError {
+    #[error("{name} is not registered")]
+    NotRegistered { name: String },
+    #[error("{name} is registered, but has no value set")]
+    NoValue { name: String },
+    #[error("attempting to store records that don't match hash: {records} != {value}")]
+    ValueMismatch {
+        name: String,
+        records: Value,
+        value: Value,
+    },
+    #[error("no records are stored for {name}")]
+    MissingLocalRecord { key: Key, name: String },
+    /// The stored records hash to something other than the on-chain value,
+    /// which was set since they were stored.
+    #[error("store has invalid value for {key}")]
+    StaleRecords { key: Key },
+    #[error("{name} has no {record_type:?} record")]
+    NoRecord {
+        name: String,
+        record_type: RecordType,
+    },
+    #[error("{name} was transferred and has no current value from its new owner")]
+    AwaitingNewOwner { name: String },
+    #[error("{name} has no weighted values")]
+    NoWeightedValues { name: String },
+    #[error("{value} isn't one of the weighted values of {name}")]
+    NotWeightedValue { name: String, value: Value },
+    #[error("no weighted values of {name} are stored")]
+    NoStoredWeightedValues { name: String },
+    #[error(transparent)]
+    InvalidName(#[from] NameError),
+    #[error(transparent)]
+    State(#[from] Error),
+    #[error(transparent)]
+    Heed(#[from] heed::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+    use crate::types::{BitNamesOutput, OutPoint};
+
+    #[test]
+    fn warm_cache_is_invalidated_by_connect_and_disconnect() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let name = Name::parse("warm.com").unwrap();
+        let mut records = RecordSet::new();
+        records.insert(RecordType::A, "192.0.2.1");
+        let registration = chain.register(owner, "warm.com", 1).unwrap();
+        let set_value = chain.spend_tx(
+            registration,
+            owner,
+            BitNamesOutput::KeyValue {
+                key: name.key(),
+                value: Some(records.value()),
+            },
+        );
+        let body = chain.connect(vec![set_value]).unwrap();
+
+        // Served through a handle of its own, which never connects anything.
+        let state = BitNamesState::new(&env, BitNamesParams::default()).unwrap();
+        let mut nameserver = NameServer::open(&env).unwrap();
+        nameserver.store(&state, &name, &records).unwrap();
+        assert_eq!(nameserver.warm_from_state(&state).unwrap(), 1);
+        assert_eq!(nameserver.lookup_records(&state, &name).unwrap(), records);
+
+        chain.state.disconnect_body(&body).unwrap();
+        assert!(matches!(
+            nameserver.lookup_records(&state, &name),
+            Err(NameServerError::NoValue { .. })
+        ));
+        chain.state.connect_body(&body).unwrap();
+        assert_eq!(nameserver.lookup_records(&state, &name).unwrap(), records);
+    }
+
+    #[test]
+    fn resolve_picks_stored_weighted_values_by_weight() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let name = Name::parse("weighted.com").unwrap();
+        let (heavy, light) = ("192.0.2.1", "192.0.2.2");
+        let registration = chain.register(owner, "weighted.com", 1).unwrap();
+        let set_values = chain.spend_tx(
+            registration,
+            owner,
+            BitNamesOutput::WeightedValues {
+                key: name.key(),
+                values: vec![
+                    (hash_value(RecordType::A, heavy.as_bytes()), 9),
+                    (hash_value(RecordType::A, light.as_bytes()), 1),
+                ],
+            },
+        );
+        chain.connect(vec![set_values]).unwrap();
+
+        let mut nameserver = NameServer::open(&env).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!(matches!(
+            nameserver.resolve(&chain.state, &name, RecordType::A, &mut rng),
+            Err(NameServerError::NoStoredWeightedValues { .. })
+        ));
+        assert!(matches!(
+            nameserver.store_weighted(&chain.state, &name, RecordType::A, "192.0.2.3"),
+            Err(NameServerError::NotWeightedValue { .. })
+        ));
+        nameserver
+            .store_weighted(&chain.state, &name, RecordType::A, heavy)
+            .unwrap();
+        nameserver
+            .store_weighted(&chain.state, &name, RecordType::A, light)
+            .unwrap();
+        let picks: Vec<String> = (0..1000)
+            .map(|_| {
+                nameserver
+                    .resolve(&chain.state, &name, RecordType::A, &mut rng)
+                    .unwrap()
+            })
+            .collect();
+        let heavy_picks = picks.iter().filter(|pick| *pick == heavy).count();
+        assert!((800..1000).contains(&heavy_picks), "{heavy_picks}");
+        assert!(picks.iter().all(|pick| pick == heavy || pick == light));
+        assert!(matches!(
+            nameserver.resolve(&chain.state, &name, RecordType::Aaaa, &mut rng),
+            Err(NameServerError::NoStoredWeightedValues { .. })
+        ));
+    }
+
+    #[test]
+    fn reverse_lookup_skips_names_that_no_longer_match() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let mut nameserver = NameServer::open(&env).unwrap();
+        register_with_a_record(&mut chain, &mut nameserver, "first.com", "192.0.2.7");
+        let owner = chain.addresses[0];
+        let name = Name::parse("second.com").unwrap();
+        let mut records = RecordSet::new();
+        records.insert(RecordType::A, "192.0.2.7");
+        let registration = chain.register(owner, "second.com", 2).unwrap();
+        let set_value = chain.spend_tx(
+            registration,
+            owner,
+            BitNamesOutput::KeyValue {
+                key: name.key(),
+                value: Some(records.value()),
+            },
+        );
+        let body = chain.connect(vec![set_value]).unwrap();
+        nameserver.store(&chain.state, &name, &records).unwrap();
+        assert_eq!(
+            nameserver
+                .reverse_lookup(&chain.state, "192.0.2.7")
+                .unwrap(),
+            vec!["first.com", "second.com"]
+        );
+        assert!(nameserver
+            .reverse_lookup(&chain.state, "192.0.2.8")
+            .unwrap()
+            .is_empty());
+
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(
+            nameserver
+                .reverse_lookup(&chain.state, "192.0.2.7")
+                .unwrap(),
+            vec!["first.com"]
+        );
+    }
+
+    #[test]
+    fn stored_records_survive_reopening() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let mut nameserver = NameServer::open(&env).unwrap();
+        register_with_a_record(&mut chain, &mut nameserver, "kept.com", "192.0.2.9");
+        let name = Name::parse("kept.com").unwrap();
+        let mut other = RecordSet::new();
+        other.insert(RecordType::A, "192.0.2.10");
+        assert!(matches!(
+            nameserver.store(&chain.state, &name, &other),
+            Err(NameServerError::ValueMismatch { .. })
+        ));
+        drop(nameserver);
+
+        let nameserver = NameServer::open(&env).unwrap();
+        assert_eq!(
+            nameserver
+                .lookup(&chain.state, &name, RecordType::A)
+                .unwrap(),
+            "192.0.2.9"
+        );
+        assert_eq!(
+            nameserver
+                .reverse_lookup(&chain.state, "192.0.2.9")
+                .unwrap(),
+            vec!["kept.com"]
+        );
+    }
+
+    #[test]
+    fn lookup_serves_each_record_of_the_set() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let mut nameserver = NameServer::open(&env).unwrap();
+        let owner = chain.addresses[0];
+        let name = Name::parse("set.com").unwrap();
+        let mut records = RecordSet::new();
+        records.insert(RecordType::A, "192.0.2.1");
+        records.insert(RecordType::Txt, "v=spf1 -all");
+        let registration = chain.register(owner, "set.com", 1).unwrap();
+        let set_value = chain.spend_tx(
+            registration,
+            owner,
+            BitNamesOutput::KeyValue {
+                key: name.key(),
+                value: Some(records.value()),
+            },
+        );
+        chain.connect(vec![set_value]).unwrap();
+        let mut tampered = records.clone();
+        tampered.insert(RecordType::Txt, "other");
+        assert!(matches!(
+            nameserver.store(&chain.state, &name, &tampered),
+            Err(NameServerError::ValueMismatch { .. })
+        ));
+        nameserver.store(&chain.state, &name, &records).unwrap();
+
+        assert_eq!(
+            nameserver
+                .lookup(&chain.state, &name, RecordType::Txt)
+                .unwrap(),
+            "v=spf1 -all"
+        );
+        assert_eq!(
+            nameserver
+                .lookup(&chain.state, &name, RecordType::A)
+                .unwrap(),
+            "192.0.2.1"
+        );
+        assert!(matches!(
+            nameserver.lookup(&chain.state, &name, RecordType::Mx),
+            Err(NameServerError::NoRecord {
+                record_type: RecordType::Mx,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn names_matching_value_searches_current_records() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let mut nameserver = NameServer::open(&env).unwrap();
+        register_with_a_record(&mut chain, &mut nameserver, "inside.com", "10.0.0.1");
+        register_with_a_record(&mut chain, &mut nameserver, "outside.com", "192.0.2.1");
+        let in_subnet = |value: &[u8]| value.starts_with(b"10.");
+        assert_eq!(
+            nameserver
+                .names_matching_value(&chain.state, in_subnet)
+                .unwrap(),
+            vec![(key_of("inside.com"), b"10.0.0.1".to_vec())]
+        );
+
+        // Once the chain disagrees with the stored records they no longer
+        // match.
+        let mut records = RecordSet::new();
+        records.insert(RecordType::A, "10.0.0.2");
+        let name = chain.register(chain.addresses[0], "moved.com", 2).unwrap();
+        let set_value = chain.spend_tx(
+            name,
+            chain.addresses[0],
+            BitNamesOutput::KeyValue {
+                key: key_of("moved.com"),
+                value: Some(records.value()),
+            },
+        );
+        let body = chain.connect(vec![set_value]).unwrap();
+        nameserver
+            .store(&chain.state, &Name::parse("moved.com").unwrap(), &records)
+            .unwrap();
+        assert_eq!(
+            nameserver
+                .names_matching_value(&chain.state, in_subnet)
+                .unwrap()
+                .len(),
+            2
+        );
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(
+            nameserver
+                .names_matching_value(&chain.state, in_subnet)
+                .unwrap(),
+            vec![(key_of("inside.com"), b"10.0.0.1".to_vec())]
+        );
+    }
+
+    #[test]
+    fn transferred_names_wait_for_their_new_owner() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let mut nameserver = NameServer::open(&env)
+            .unwrap()
+            .with_transfer_grace_period(3);
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let name = Name::parse("moving.com").unwrap();
+        let key = name.key();
+        let mut records = RecordSet::new();
+        records.insert(RecordType::A, "192.0.2.1");
+        let value = Some(records.value());
+        let registration = chain.register(alice, "moving.com", 1).unwrap();
+        let set_value =
+            chain.spend_tx(registration, alice, BitNamesOutput::KeyValue { key, value });
+        let held = OutPoint::Regular {
+            txid: set_value.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![set_value]).unwrap();
+        nameserver.store(&chain.state, &name, &records).unwrap();
+
+        let events = chain.state.subscribe();
+        let transfer = chain.spend_tx(held, bob, BitNamesOutput::Transfer { key, value });
+        chain.connect(vec![transfer]).unwrap();
+        for event in events.try_iter() {
+            nameserver.handle_event(&event);
+        }
+        assert!(matches!(
+            nameserver.lookup(&chain.state, &name, RecordType::A),
+            Err(NameServerError::AwaitingNewOwner { .. })
+        ));
+        chain.advance(2);
+        assert!(matches!(
+            nameserver.lookup(&chain.state, &name, RecordType::A),
+            Err(NameServerError::AwaitingNewOwner { .. })
+        ));
+        chain.advance(1);
+        assert_eq!(
+            nameserver
+                .lookup(&chain.state, &name, RecordType::A)
+                .unwrap(),
+            "192.0.2.1"
+        );
+
+        // A value set by the new owner ends the wait right away.
+        nameserver.handle_event(&Event::NameTransferred {
+            key,
+            to: bob,
+            height: chain.height(),
+        });
+        nameserver.handle_event(&Event::ValueUpdated {
+            key,
+            value,
+            height: chain.height(),
+        });
+        assert!(nameserver
+            .lookup(&chain.state, &name, RecordType::A)
+            .is_ok());
+    }
+
+    #[test]
+    fn lookup_errors_say_what_went_wrong() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let mut nameserver = NameServer::open(&env).unwrap();
+        let owner = chain.addresses[0];
+        let message = |result: Result<String, NameServerError>| result.unwrap_err().to_string();
+        let lookup = |nameserver: &NameServer, chain: &TestChain, name: &str| {
+            nameserver.lookup_str(&chain.state, name, RecordType::A)
+        };
+
+        assert_eq!(
+            message(lookup(&nameserver, &chain, "nobody.com")),
+            "nobody.com is not registered"
+        );
+        assert_eq!(message(lookup(&nameserver, &chain, "")), "name is empty");
+
+        // Records stored while there is no value yet, then a different value
+        // set on chain.
+        let name = Name::parse("stale.com").unwrap();
+        let registration = chain.register(owner, "stale.com", 1).unwrap();
+        assert_eq!(
+            message(lookup(&nameserver, &chain, "stale.com")),
+            "stale.com is registered, but has no value set"
+        );
+        let mut records = RecordSet::new();
+        records.insert(RecordType::A, "192.0.2.1");
+        nameserver.store(&chain.state, &name, &records).unwrap();
+        let value = hash_value(RecordType::A, b"192.0.2.9");
+        let set_value = chain.spend_tx(
+            registration,
+            owner,
+            BitNamesOutput::KeyValue {
+                key: name.key(),
+                value: Some(value),
+            },
+        );
+        chain.connect(vec![set_value]).unwrap();
+        assert_eq!(
+            message(lookup(&nameserver, &chain, "stale.com")),
+            format!("store has invalid value for {}", name.key())
+        );
+        assert_eq!(
+            nameserver
+                .store(&chain.state, &name, &records)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "attempting to store records that don't match hash: {} != {value}",
+                records.value()
+            )
+        );
+        assert_eq!(
+            nameserver
+                .store_weighted(&chain.state, &name, RecordType::A, "192.0.2.1")
+                .unwrap_err()
+                .to_string(),
+            "stale.com has no weighted values"
+        );
+
+        register_with_a_record(&mut chain, &mut nameserver, "served.com", "192.0.2.2");
+        assert_eq!(
+            message(nameserver.lookup_str(&chain.state, "served.com", RecordType::Mx)),
+            "served.com has no Mx record"
+        );
+        let (unstored_env, _unstored_dir) = temp_env();
+        let unstored = NameServer::open(&unstored_env).unwrap();
+        assert_eq!(
+            message(lookup(&unstored, &chain, "served.com")),
+            "no records are stored for served.com"
+        );
+
+        let value = hash_value(RecordType::A, b"192.0.2.3");
+        let constructed = [
+            (
+                NameServerError::AwaitingNewOwner {
+                    name: "moved.com".into(),
+                },
+                String::from(
+                    "moved.com was transferred and has no current value from its new owner",
+                ),
+            ),
+            (
+                NameServerError::NotWeightedValue {
+                    name: "pool.com".into(),
+                    value,
+                },
+                format!("{value} isn't one of the weighted values of pool.com"),
+            ),
+            (
+                NameServerError::NoStoredWeightedValues {
+                    name: "pool.com".into(),
+                },
+                "no weighted values of pool.com are stored".into(),
+            ),
+        ];
+        for (err, expected) in constructed {
+            assert_eq!(err.to_string(), expected);
         }
     }
 }
@@ -0,0 +1,274 @@
+use crate::events::OverflowPolicy;
+use crate::hashes::Key;
+use crate::name::{Name, NameError};
+use crate::types::{Auction, AuctionPhase};
+
+#[derive(Debug, Clone)]
+pub struct BitNamesParams {
+    /// Names ending with any of these suffixes can't be registered. Since
+    /// consensus only sees hashed keys, reveals must carry the name inline
+    /// whenever this is non-empty. Each suffix is a name, optionally with a
+    /// leading dot, and matches whole labels in any case, see
+    /// `reserved_suffix`.
+    pub reserved_suffixes: Vec<String>,
+    /// System names reserved at genesis, which no reveal can ever claim.
+    pub reserved_names: Vec<String>,
+    /// Number of events buffered for each subscriber before `event_overflow`
+    /// kicks in.
+    pub event_capacity: usize,
+    pub event_overflow: OverflowPolicy,
+    /// Length of a fee epoch in blocks, must be non-zero. Genesis starts
+    /// epoch 0.
+    pub epoch_length: u32,
+    /// Number of most recent blocks that keep undo records and so can be
+    /// disconnected, must be non-zero.
+    pub max_reorg_depth: u32,
+    /// Addresses holding more than this many names without a value are
+    /// flagged by `BitNamesState::squatting_report`.
+    pub squatting_threshold: usize,
+    /// Names with fewer characters than this pay `premium_per_char` for
+    /// every character they are short by.
+    pub premium_name_length: usize,
+    pub premium_per_char: u64,
+    /// Minimum number of blocks between a commitment and its reveal.
+    pub commitment_min_age: u32,
+    /// Least fee a commitment has to lock for its reveal to pay.
+    pub min_commitment_fee: u64,
+    /// Least amount a `BurnReserve` output has to burn.
+    pub min_burn_reserve: u64,
+    /// Maximum number of blocks between a commitment and its reveal.
+    /// Commitments older than this expire: one made at height `h` can be
+    /// revealed up to block `h + commitment_max_age` and is swept when block
+    /// `h + commitment_max_age + 1` is connected. Genesis is height 0 and
+    /// never has a body, so the earliest commitment is at height 1.
+    pub commitment_max_age: u32,
+    /// Largest weight a single weighted value may carry.
+    pub max_value_weight: u16,
+    /// Paid on top of the fee by every reveal and held until the name's
+    /// first value is set, when it is refunded to the reveal's address.
+    /// Names left without a value for `deposit_grace_period` blocks forfeit
+    /// it.
+    pub registration_deposit: u64,
+    pub deposit_grace_period: u32,
+    /// Blocks an auction takes bids for, counting the block of the bid
+    /// that opened it, and zero to turn auctions off. Keys under auction can
+    /// only be registered by revealing the top bid, in the
+    /// `auction_reveal_window` blocks after bidding ends.
+    pub auction_bid_window: u32,
+    pub auction_reveal_window: u32,
+    /// Require outputs to be sorted by their serialized content and then
+    /// address, so reordering outputs can't produce a second txid for the
+    /// same transaction.
+    pub canonical_output_order: bool,
+}
+
+impl Default for BitNamesParams {
+    fn default() -> Self {
+        Self {
+            reserved_suffixes: vec![],
+            reserved_names: vec![],
+            event_capacity: 1024,
+            event_overflow: OverflowPolicy::default(),
+            epoch_length: 1000,
+            max_reorg_depth: 100,
+            squatting_threshold: 5,
+            premium_name_length: 0,
+            premium_per_char: 0,
+            commitment_min_age: 0,
+            commitment_max_age: 1,
+            min_commitment_fee: 0,
+            min_burn_reserve: 0,
+            max_value_weight: 1000,
+            registration_deposit: 0,
+            deposit_grace_period: 1000,
+            auction_bid_window: 0,
+            auction_reveal_window: 0,
+            canonical_output_order: false,
+        }
+    }
+}
+
+impl BitNamesParams {
+    /// Reject configurations no chain could run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.commitment_min_age > self.commitment_max_age {
+            return Err(ConfigError::ImpossibleRevealWindow {
+                min_age: self.commitment_min_age,
+                max_age: self.commitment_max_age,
+            });
+        }
+        if self.auction_bid_window > 0 && self.auction_reveal_window == 0 {
+            return Err(ConfigError::ZeroAuctionRevealWindow);
+        }
+        if self.epoch_length == 0 {
+            return Err(ConfigError::ZeroEpochLength);
+        }
+        // Every undo record would be pruned by the block that wrote it.
+        if self.max_reorg_depth == 0 {
+            return Err(ConfigError::ZeroReorgDepth);
+        }
+        for suffix in &self.reserved_suffixes {
+            parse_suffix(suffix).map_err(|err| ConfigError::InvalidReservedSuffix {
+                suffix: suffix.clone(),
+                err,
+            })?;
+        }
+        for name in &self.reserved_names {
+            Name::parse(name).map_err(|err| ConfigError::InvalidReservedName {
+                name: name.clone(),
+                err,
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn is_reserved(&self, key: &Key) -> bool {
+        self.reserved_names
+            .iter()
+            .filter_map(|name| Name::parse(name).ok())
+            .any(|name| name.key() == *key)
+    }
+
+    /// The reserved suffix `name` falls under, matched on label boundaries
+    /// so that `com` reserves `com` and `example.com` but not `telecom`.
+    pub fn reserved_suffix(&self, name: &Name) -> Option<&str> {
+        self.reserved_suffixes
+            .iter()
+            .map(String::as_str)
+            .find(|suffix| {
+                let Ok(suffix) = parse_suffix(suffix) else {
+                    return false;
+                };
+                let (name, suffix) = (name.as_str(), suffix.as_str());
+                name == suffix || name.ends_with(&format!(".{suffix}"))
+            })
+    }
+
+    pub fn auction_phase(&self, auction: &Auction, height: u32) -> AuctionPhase {
+        let age = height.saturating_sub(auction.opened);
+        if age < self.auction_bid_window {
+            AuctionPhase::Bidding
+        } else if age < self.auction_bid_window + self.auction_reveal_window {
+            AuctionPhase::Revealing
+        } else {
+            AuctionPhase::Ended
+        }
+    }
+
+    pub fn name_premium(&self, name: &str) -> u64 {
+        let length = name.chars().count();
+        self.premium_name_length.saturating_sub(length) as u64 * self.premium_per_char
+    }
+}
+
+/// Normalized form of a reserved suffix.
+fn parse_suffix(suffix: &str) -> Result<Name, NameError> {
+    Name::parse(suffix.strip_prefix('.').unwrap_or(suffix))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("no reveal can be valid with min age {min_age} above max age {max_age}")]
+    ImpossibleRevealWindow { min_age: u32, max_age: u32 },
+    #[error("auctions need a non-zero reveal window for any bid to win")]
+    ZeroAuctionRevealWindow,
+    #[error("epoch length must be non-zero")]
+    ZeroEpochLength,
+    #[error("max reorg depth must be non-zero")]
+    ZeroReorgDepth,
+    #[error("reserved name {name} is invalid")]
+    InvalidReservedName { name: String, err: NameError },
+    #[error("reserved suffix {suffix} is invalid")]
+    InvalidReservedSuffix { suffix: String, err: NameError },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_reorg_depth() {
+        let params = BitNamesParams {
+            max_reorg_depth: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            params.validate(),
+            Err(ConfigError::ZeroReorgDepth)
+        ));
+        assert!(BitNamesParams::default().validate().is_ok());
+    }
+
+    #[test]
+    fn reserved_suffixes_match_whole_labels() {
+        let params = BitNamesParams {
+            reserved_suffixes: vec!["COM".into(), ".test.org".into()],
+            ..Default::default()
+        };
+        params.validate().unwrap();
+        let suffix = |name: &str| params.reserved_suffix(&Name::parse(name).unwrap());
+        assert_eq!(suffix("com"), Some("COM"));
+        assert_eq!(suffix("Example.com"), Some("COM"));
+        assert_eq!(suffix("a.test.org"), Some(".test.org"));
+        assert_eq!(suffix("telecom"), None);
+        assert_eq!(suffix("contest.org"), None);
+        assert_eq!(suffix("org"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_reserved_suffix() {
+        for suffix in ["", "a..b", "..com"] {
+            let params = BitNamesParams {
+                reserved_suffixes: vec![suffix.into()],
+                ..Default::default()
+            };
+            assert!(
+                matches!(
+                    params.validate(),
+                    Err(ConfigError::InvalidReservedSuffix { .. })
+                ),
+                "{suffix:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_min_age_above_max_age() {
+        let params = BitNamesParams {
+            commitment_min_age: 3,
+            commitment_max_age: 2,
+            ..Default::default()
+        };
+        assert!(matches!(
+            params.validate(),
+            Err(ConfigError::ImpossibleRevealWindow {
+                min_age: 3,
+                max_age: 2
+            })
+        ));
+        let params = BitNamesParams {
+            commitment_min_age: 2,
+            ..params
+        };
+        assert!(params.validate().is_ok());
+    }
+
+    #[test]
+    fn reserved_names_match_any_spelling() {
+        let params = BitNamesParams {
+            reserved_names: vec!["Root.Example".into()],
+            ..Default::default()
+        };
+        params.validate().unwrap();
+        assert!(params.is_reserved(&Name::parse("root.example.").unwrap().key()));
+        assert!(!params.is_reserved(&Name::parse("leaf.root.example").unwrap().key()));
+        let params = BitNamesParams {
+            reserved_names: vec!["a..b".into()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            params.validate(),
+            Err(ConfigError::InvalidReservedName { name, .. }) if name == "a..b"
+        ));
+    }
+}
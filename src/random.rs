@@ -1,6 +1,7 @@
+use crate::authorization::address_from_pubkey;
 use bitcoin::hashes::Hash as _;
 use fake::{Fake, Faker};
-use sdk_authorization_ed25519_dalek::{get_address, Keypair};
+use sdk_authorization_ed25519_dalek::Keypair;
 use sdk_types::*;
 use std::collections::HashMap;
 
@@ -9,7 +10,7 @@ pub fn random_keypairs(num_keypairs: usize) -> HashMap<Address, Keypair> {
     (0..num_keypairs)
         .map(|_| {
             let keypair = Keypair::generate(&mut csprng);
-            (get_address(&keypair.public), keypair)
+            (address_from_pubkey(&keypair.public), keypair)
         })
         .collect()
 }
@@ -0,0 +1,311 @@
+use crate::mempool::{Mempool, MempoolError};
+use crate::name::Name;
+use crate::snapshot::{SessionError, SnapshotService, BEGIN_SNAPSHOT, END_SNAPSHOT};
+use crate::types::*;
+use crate::validation::{BitNamesState, Error};
+use jsonrpsee::core::Error as RpcError;
+use jsonrpsee::server::{ServerBuilder, ServerHandle};
+use jsonrpsee::types::error::{CallError, ErrorObject};
+use jsonrpsee::RpcModule;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+
+pub const GET_VALUE: &str = "bitnames_getValue";
+pub const GET_UTXO: &str = "bitnames_getUtxo";
+pub const SUBMIT_TRANSACTION: &str = "bitnames_submitTransaction";
+pub const BEST_BLOCK_HEIGHT: &str = "bitnames_bestBlockHeight";
+
+/// Code of errors that don't come from the state, e.g. malformed params.
+/// State errors use their `ErrorCode`.
+const INVALID_REQUEST: i32 = -32602;
+
+pub struct RpcContext {
+    pub state: Arc<RwLock<BitNamesState>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    /// Sessions opened with `BEGIN_SNAPSHOT`. Queries that pass a session's
+    /// token as their last param read from its snapshot instead of the tip.
+    pub snapshots: SnapshotService,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValueResponse {
+    pub registered: bool,
+    /// Hex value hash, `None` for names without a value.
+    pub value: Option<String>,
+}
+
+/// Start serving JSON-RPC on `addr`, returning the bound address, which
+/// differs from `addr` for port 0, and a handle to stop the server with.
+/// Has to be called from within a tokio runtime.
+pub async fn start(
+    addr: SocketAddr,
+    context: RpcContext,
+) -> Result<(SocketAddr, ServerHandle), RpcError> {
+    let server = ServerBuilder::default().build(addr).await?;
+    let addr = server.local_addr()?;
+    Ok((addr, server.start(module(context)?)?))
+}
+
+/// Every method `start` serves.
+fn module(context: RpcContext) -> Result<RpcModule<RpcContext>, RpcError> {
+    let mut module = RpcModule::new(context);
+    module.register_method(GET_VALUE, |params, context| {
+        let mut params = params.sequence();
+        let name: String = params.next()?;
+        let token: Option<u64> = params.optional_next()?;
+        let name = Name::parse(&name).map_err(|err| invalid_request(err.to_string()))?;
+        let value = match token {
+            Some(token) => context
+                .snapshots
+                .get_value(token, name.key())
+                .map_err(session_error)?,
+            None => {
+                let state = context.state.read().unwrap();
+                state.get_value(&name.key()).map_err(state_error)?
+            }
+        };
+        Ok(ValueResponse {
+            registered: value.is_some(),
+            value: value.flatten().map(|value| value.to_string()),
+        })
+    })?;
+    module.register_method(GET_UTXO, |params, context| {
+        let mut params = params.sequence();
+        let outpoint: OutPoint = params.next()?;
+        let token: Option<u64> = params.optional_next()?;
+        match token {
+            Some(token) => context
+                .snapshots
+                .get_utxo(token, outpoint)
+                .map_err(session_error),
+            None => {
+                let state = context.state.read().unwrap();
+                state.get_utxo(&outpoint).map_err(state_error)
+            }
+        }
+    })?;
+    module.register_method(SUBMIT_TRANSACTION, |params, context| {
+        let transaction: String = params.one()?;
+        let transaction =
+            hex::decode(transaction).map_err(|err| invalid_request(err.to_string()))?;
+        let transaction: AuthorizedTransaction =
+            bincode::deserialize(&transaction).map_err(|err| invalid_request(err.to_string()))?;
+        let txid = transaction.transaction.txid();
+        let state = context.state.read().unwrap();
+        // The mempool runs transactions spending confirmed outputs through
+        // `validate_transaction` before pooling them.
+        context
+            .mempool
+            .lock()
+            .unwrap()
+            .add_transaction(&state, transaction)
+            .map_err(mempool_error)?;
+        Ok(txid)
+    })?;
+    module.register_method(BEST_BLOCK_HEIGHT, |params, context| {
+        let token: Option<u64> = params.sequence().optional_next()?;
+        match token {
+            Some(token) => context.snapshots.height(token).map_err(session_error),
+            None => Ok(context.state.read().unwrap().best_block_height),
+        }
+    })?;
+    // The state lock must not be held while waiting on the session thread,
+    // which takes it to begin a session.
+    module.register_method(BEGIN_SNAPSHOT, |_, context| {
+        context.snapshots.begin().map_err(session_error)
+    })?;
+    module.register_method(END_SNAPSHOT, |params, context| {
+        let token: u64 = params.one()?;
+        context.snapshots.end(token).map_err(session_error)
+    })?;
+    Ok(module)
+}
+
+fn invalid_request(message: String) -> RpcError {
+    RpcError::Call(CallError::Custom(ErrorObject::owned(
+        INVALID_REQUEST,
+        message,
+        None::<()>,
+    )))
+}
+
+fn state_error(err: Error) -> RpcError {
+    RpcError::Call(CallError::Custom(ErrorObject::owned(
+        err.code().as_u32() as i32,
+        err.to_string(),
+        None::<()>,
+    )))
+}
+
+fn session_error(err: SessionError) -> RpcError {
+    match err {
+        SessionError::State(err) => state_error(err),
+        err => invalid_request(err.to_string()),
+    }
+}
+
+fn mempool_error(err: MempoolError) -> RpcError {
+    match err {
+        MempoolError::State(err) => state_error(err),
+        MempoolError::Sdk(err) => state_error(Error::Sdk(err)),
+        err => invalid_request(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashes::hash;
+    use crate::params::BitNamesParams;
+    use crate::snapshot::DEFAULT_SESSION_TIMEOUT;
+    use crate::testing::*;
+    use serde_json::{json, Value as Json};
+
+    /// Call `method` on `module` the way a client would, returning the
+    /// result or the error object.
+    async fn call(module: &RpcModule<RpcContext>, method: &str, params: Json) -> Json {
+        let request = json!({"jsonrpc": "2.0", "id": 0, "method": method, "params": params});
+        let (response, _) = module.raw_json_request(&request.to_string()).await.unwrap();
+        let mut response: Json = serde_json::from_str(&response.result).unwrap();
+        match response.get("result") {
+            Some(_) => response["result"].take(),
+            None => response["error"].take(),
+        }
+    }
+
+    #[test]
+    fn snapshot_session_sees_pinned_height() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let key = key_of("pinned.com");
+        let name = chain.register(owner, "pinned.com", 1).unwrap();
+        let value: crate::hashes::Value = hash(&"new").into();
+        let set_value = chain.spend_tx(
+            name,
+            owner,
+            BitNamesOutput::KeyValue {
+                key,
+                value: Some(value),
+            },
+        );
+        let state = Arc::new(RwLock::new(chain.state));
+        let context = RpcContext {
+            state: state.clone(),
+            mempool: Default::default(),
+            snapshots: SnapshotService::spawn(state.clone(), DEFAULT_SESSION_TIMEOUT),
+        };
+        let module = module(context).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let token = call(&module, BEGIN_SNAPSHOT, json!([])).await;
+            assert_eq!(token, json!(0));
+            let body = Body::new(vec![set_value], vec![]);
+            state.write().unwrap().connect_body(&body).unwrap();
+
+            let pinned = call(&module, GET_VALUE, json!(["pinned.com", token])).await;
+            assert_eq!(pinned, json!({"registered": true, "value": null}));
+            let height = call(&module, BEST_BLOCK_HEIGHT, json!([token])).await;
+            assert_eq!(height, json!(2));
+            let tip = call(&module, GET_VALUE, json!(["pinned.com"])).await;
+            assert_eq!(tip, json!({"registered": true, "value": value.to_string()}));
+            assert_eq!(call(&module, BEST_BLOCK_HEIGHT, json!([])).await, json!(3));
+
+            assert_eq!(
+                call(&module, END_SNAPSHOT, json!([token])).await,
+                json!(true)
+            );
+            let released = call(&module, GET_VALUE, json!(["pinned.com", token])).await;
+            assert_eq!(released["code"], json!(INVALID_REQUEST));
+            assert_eq!(
+                call(&module, END_SNAPSHOT, json!([token])).await,
+                json!(false)
+            );
+        });
+    }
+
+    /// Call `method` over HTTP on a server started at `addr`, returning the
+    /// result or the error object.
+    fn http_call(addr: SocketAddr, method: &str, params: Json) -> Json {
+        use std::io::{Read, Write};
+        let request = json!({"jsonrpc": "2.0", "id": 0, "method": method, "params": params});
+        let request = request.to_string();
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{request}",
+            request.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let mut response: Json = serde_json::from_str(body).unwrap();
+        match response.get("result") {
+            Some(_) => response["result"].take(),
+            None => response["error"].take(),
+        }
+    }
+
+    #[test]
+    fn submitted_transaction_sets_value_once_connected() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let key = key_of("served.com");
+        let name = chain.register(owner, "served.com", 1).unwrap();
+        let value: crate::hashes::Value = hash(&"served").into();
+        let set_value = chain.spend_tx(
+            name,
+            owner,
+            BitNamesOutput::KeyValue {
+                key,
+                value: Some(value),
+            },
+        );
+        let txid = set_value.transaction.txid();
+        let state = Arc::new(RwLock::new(chain.state));
+        let mempool: Arc<Mutex<Mempool>> = Default::default();
+        let context = RpcContext {
+            state: state.clone(),
+            mempool: mempool.clone(),
+            snapshots: SnapshotService::spawn(state.clone(), DEFAULT_SESSION_TIMEOUT),
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (addr, handle) = runtime
+            .block_on(start("127.0.0.1:0".parse().unwrap(), context))
+            .unwrap();
+
+        let transaction = hex::encode(bincode::serialize(&set_value).unwrap());
+        let submitted = http_call(addr, SUBMIT_TRANSACTION, json!([transaction]));
+        assert_eq!(submitted, serde_json::to_value(txid).unwrap());
+        let pending = http_call(addr, GET_VALUE, json!(["served.com"]));
+        assert_eq!(pending, json!({"registered": true, "value": null}));
+
+        let body = mempool.lock().unwrap().select_body(usize::MAX);
+        state.write().unwrap().connect_body(&body).unwrap();
+        let served = http_call(addr, GET_VALUE, json!(["served.com"]));
+        assert_eq!(
+            served,
+            json!({"registered": true, "value": value.to_string()})
+        );
+        assert_eq!(http_call(addr, BEST_BLOCK_HEIGHT, json!([])), json!(3));
+        let rejected = http_call(addr, SUBMIT_TRANSACTION, json!(["not hex"]));
+        assert_eq!(rejected["code"], json!(INVALID_REQUEST));
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn idle_snapshot_sessions_expire() {
+        let chain = TestChain::new(BitNamesParams::default());
+        let state = Arc::new(RwLock::new(chain.state));
+        let snapshots = SnapshotService::spawn(state, std::time::Duration::from_millis(50));
+        let token = snapshots.begin().unwrap();
+        assert_eq!(snapshots.height(token).unwrap(), 0);
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(matches!(
+            snapshots.height(token),
+            Err(SessionError::UnknownToken { .. })
+        ));
+    }
+}
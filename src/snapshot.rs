@@ -0,0 +1,235 @@
+use crate::hashes::*;
+use crate::types::*;
+use crate::validation::{BitNamesState, Error};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use heed::types::*;
+use heed::{Database, RoTxn};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub const BEGIN_SNAPSHOT: &str = "bitnames_beginSnapshot";
+pub const END_SNAPSHOT: &str = "bitnames_endSnapshot";
+
+/// How long an RPC snapshot session can go unused before it is dropped.
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Read view pinned at the height it was taken at, unaffected by bodies
+/// connected or disconnected since.
+pub struct Snapshot<'env> {
+    txn: RoTxn<'env>,
+    key_to_value: Database<SerdeBincode<Key>, SerdeBincode<Option<Value>>>,
+    utxos: Database<SerdeBincode<OutPoint>, SerdeBincode<Output>>,
+    pub height: u32,
+}
+
+impl<'env> Snapshot<'env> {
+    /// `env` has to be the environment `state` was opened in.
+    pub fn new(env: &'env heed::Env, state: &BitNamesState) -> Result<Self, Error> {
+        Ok(Self {
+            txn: env.read_txn()?,
+            key_to_value: state.key_to_value,
+            utxos: state.utxos,
+            height: state.best_block_height,
+        })
+    }
+
+    pub fn get_value(&self, key: &Key) -> Result<Option<Option<Value>>, Error> {
+        Ok(self.key_to_value.get(&self.txn, key)?)
+    }
+
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<Output>, Error> {
+        Ok(self.utxos.get(&self.txn, outpoint)?)
+    }
+}
+
+/// Snapshots held for RPC sessions between `bitnames_beginSnapshot` and
+/// `bitnames_endSnapshot`. A snapshot keeps its read transaction open, which
+/// stops LMDB from reusing pages, so sessions that go unused for `timeout`
+/// are dropped.
+pub struct SnapshotSessions<'env> {
+    env: &'env heed::Env,
+    timeout: Duration,
+    next_token: u64,
+    sessions: HashMap<u64, (Snapshot<'env>, Instant)>,
+}
+
+impl<'env> SnapshotSessions<'env> {
+    pub fn new(env: &'env heed::Env, timeout: Duration) -> Self {
+        Self {
+            env,
+            timeout,
+            next_token: 0,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn begin(&mut self, state: &BitNamesState) -> Result<u64, Error> {
+        self.expire();
+        let token = self.next_token;
+        self.next_token += 1;
+        let snapshot = Snapshot::new(self.env, state)?;
+        self.sessions.insert(token, (snapshot, Instant::now()));
+        Ok(token)
+    }
+
+    /// Snapshot of a live session, using it restarts its timeout.
+    pub fn get(&mut self, token: u64) -> Result<&Snapshot<'env>, SessionError> {
+        self.expire();
+        let (snapshot, last_used) = self
+            .sessions
+            .get_mut(&token)
+            .ok_or(SessionError::UnknownToken { token })?;
+        *last_used = Instant::now();
+        Ok(snapshot)
+    }
+
+    /// Release a session, returning whether it was still live.
+    pub fn end(&mut self, token: u64) -> bool {
+        self.expire();
+        self.sessions.remove(&token).is_some()
+    }
+
+    /// Drop every session unused for longer than the timeout.
+    pub fn expire(&mut self) {
+        let timeout = self.timeout;
+        self.sessions
+            .retain(|_, (_, last_used)| last_used.elapsed() < timeout);
+    }
+}
+
+/// `SnapshotSessions` served from a thread of their own, since the read
+/// transactions they hold can't be used from any other thread. Sessions
+/// still expire while no requests come in, so a client that never ends its
+/// session can't hold a transaction open forever.
+#[derive(Clone)]
+pub struct SnapshotService {
+    requests: Sender<Request>,
+}
+
+enum Request {
+    Begin {
+        reply: Sender<Result<u64, SessionError>>,
+    },
+    GetValue {
+        token: u64,
+        key: Key,
+        reply: Sender<Result<Option<Option<Value>>, SessionError>>,
+    },
+    GetUtxo {
+        token: u64,
+        outpoint: OutPoint,
+        reply: Sender<Result<Option<Output>, SessionError>>,
+    },
+    Height {
+        token: u64,
+        reply: Sender<Result<u32, SessionError>>,
+    },
+    End {
+        token: u64,
+        reply: Sender<Result<bool, SessionError>>,
+    },
+}
+
+impl SnapshotService {
+    /// Start the session thread, which stops once every clone of the
+    /// service is dropped.
+    pub fn spawn(state: Arc<RwLock<BitNamesState>>, timeout: Duration) -> Self {
+        let (requests, receiver) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            let env = state.read().unwrap().env.clone();
+            let mut sessions = SnapshotSessions::new(&env, timeout);
+            loop {
+                match receiver.recv_timeout(timeout) {
+                    Ok(request) => handle(&mut sessions, &state, request),
+                    Err(RecvTimeoutError::Timeout) => sessions.expire(),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+        Self { requests }
+    }
+
+    /// Pin a snapshot at the current tip, returning the token to query it
+    /// with.
+    pub fn begin(&self) -> Result<u64, SessionError> {
+        self.call(|reply| Request::Begin { reply })
+    }
+
+    pub fn get_value(&self, token: u64, key: Key) -> Result<Option<Option<Value>>, SessionError> {
+        self.call(|reply| Request::GetValue { token, key, reply })
+    }
+
+    pub fn get_utxo(&self, token: u64, outpoint: OutPoint) -> Result<Option<Output>, SessionError> {
+        self.call(|reply| Request::GetUtxo {
+            token,
+            outpoint,
+            reply,
+        })
+    }
+
+    /// Height the session's snapshot was taken at.
+    pub fn height(&self, token: u64) -> Result<u32, SessionError> {
+        self.call(|reply| Request::Height { token, reply })
+    }
+
+    /// Release a session, returning whether it was still live.
+    pub fn end(&self, token: u64) -> Result<bool, SessionError> {
+        self.call(|reply| Request::End { token, reply })
+    }
+
+    fn call<T>(
+        &self,
+        request: impl FnOnce(Sender<Result<T, SessionError>>) -> Request,
+    ) -> Result<T, SessionError> {
+        let (reply, response) = crossbeam_channel::bounded(1);
+        self.requests
+            .send(request(reply))
+            .map_err(|_| SessionError::Stopped)?;
+        response.recv().map_err(|_| SessionError::Stopped)?
+    }
+}
+
+fn handle(sessions: &mut SnapshotSessions, state: &RwLock<BitNamesState>, request: Request) {
+    // Replies only fail to send if the caller has given up waiting.
+    match request {
+        Request::Begin { reply } => {
+            // Holding the lock keeps the tip from moving between reading
+            // its height and opening the transaction.
+            let state = state.read().unwrap();
+            let _ = reply.send(sessions.begin(&state).map_err(SessionError::from));
+        }
+        Request::GetValue { token, key, reply } => {
+            let value = sessions
+                .get(token)
+                .and_then(|snapshot| Ok(snapshot.get_value(&key)?));
+            let _ = reply.send(value);
+        }
+        Request::GetUtxo {
+            token,
+            outpoint,
+            reply,
+        } => {
+            let utxo = sessions
+                .get(token)
+                .and_then(|snapshot| Ok(snapshot.get_utxo(&outpoint)?));
+            let _ = reply.send(utxo);
+        }
+        Request::Height { token, reply } => {
+            let _ = reply.send(sessions.get(token).map(|snapshot| snapshot.height));
+        }
+        Request::End { token, reply } => {
+            let _ = reply.send(Ok(sessions.end(token)));
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("no live snapshot session with token {token}")]
+    UnknownToken { token: u64 },
+    #[error("snapshot session thread has stopped")]
+    Stopped,
+    #[error("state error")]
+    State(#[from] Error),
+}
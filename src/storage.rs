@@ -0,0 +1,64 @@
+use heed::flags::Flags;
+use std::path::Path;
+
+/// How hard LMDB works to make a committed write transaction survive a crash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// Flush data and metadata on every commit. A connected body is never
+    /// lost, at the cost of an fsync per block. What an archival node wants.
+    #[default]
+    Sync,
+    /// Flush data but not the meta page on commit (`MDB_NOMETASYNC`). A
+    /// crash can roll back the last committed transaction, but never
+    /// corrupts the database.
+    MetaAsync,
+    /// Leave flushing to the OS (`MDB_NOSYNC`). A crash can lose any number
+    /// of recent commits, and without a journaling filesystem may corrupt
+    /// the database. Fine for a resolver that re-syncs from its peers.
+    Async,
+}
+
+impl DurabilityMode {
+    pub fn flags(self) -> &'static [Flags] {
+        match self {
+            Self::Sync => &[],
+            Self::MetaAsync => &[Flags::MdbNoMetaSync],
+            Self::Async => &[Flags::MdbNoSync],
+        }
+    }
+}
+
+/// Options for the environment `BitNamesState` is opened in.
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    pub map_size: usize,
+    pub max_dbs: u32,
+    pub durability: DurabilityMode,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            map_size: 10 * 1024 * 1024, // 10MB
+            // The state's databases and the nameserver's records, with room
+            // for more so that adding one doesn't need a bump here too.
+            max_dbs: 32,
+            durability: DurabilityMode::default(),
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn open(&self, path: &Path) -> heed::Result<heed::Env> {
+        let mut options = heed::EnvOpenOptions::new();
+        options.map_size(self.map_size).max_dbs(self.max_dbs);
+        for flag in self.durability.flags() {
+            // Neither sync flag changes how the memory map is accessed, so
+            // they can't cause the unsoundness the other flags can.
+            unsafe {
+                options.flag(*flag);
+            }
+        }
+        options.open(path)
+    }
+}
@@ -0,0 +1,246 @@
+use crate::authorization::{address_from_pubkey, authorize_transaction};
+use crate::hashes::*;
+use crate::name::Name;
+use crate::nameserver::NameServer;
+use crate::params::BitNamesParams;
+use crate::storage::OpenOptions;
+use crate::types::*;
+use crate::validation::{BitNamesState, Error};
+use bitcoin::hashes::Hash as _;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use sdk_authorization_ed25519_dalek::Keypair;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const NUM_KEYPAIRS: usize = 4;
+
+static NEXT_DIR: AtomicU64 = AtomicU64::new(0);
+
+/// Directory removed again when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let index = NEXT_DIR.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("bitnames-test-{}-{index}.mdb", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Environment in a directory of its own, so tests can run in parallel.
+pub fn temp_env() -> (heed::Env, TempDir) {
+    let dir = TempDir::new();
+    let env = OpenOptions::default().open(&dir.0).unwrap();
+    (env, dir)
+}
+
+pub fn key_of(name: &str) -> Key {
+    Name::parse(name).unwrap().key()
+}
+
+pub fn custom(address: Address, output: BitNamesOutput) -> Output {
+    Output {
+        address,
+        content: Content::Custom(output),
+    }
+}
+
+/// A state on a fresh environment along with keypairs to fund and sign
+/// transactions with.
+pub struct TestChain {
+    pub state: BitNamesState,
+    pub keypairs: HashMap<Address, Keypair>,
+    /// Addresses of `keypairs`, in a fixed order.
+    pub addresses: Vec<Address>,
+    deposits: u64,
+    _dir: TempDir,
+}
+
+impl TestChain {
+    pub fn new(params: BitNamesParams) -> Self {
+        let (env, dir) = temp_env();
+        Self::open(&env, dir, params)
+    }
+
+    /// Chain on `env`, which is removed along with `dir`.
+    pub fn open(env: &heed::Env, dir: TempDir, params: BitNamesParams) -> Self {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut keypairs = HashMap::new();
+        let mut addresses = vec![];
+        for _ in 0..NUM_KEYPAIRS {
+            let keypair = Keypair::generate(&mut rng);
+            let address = address_from_pubkey(&keypair.public);
+            addresses.push(address);
+            keypairs.insert(address, keypair);
+        }
+        Self {
+            state: BitNamesState::new(env, params).unwrap(),
+            keypairs,
+            addresses,
+            deposits: 0,
+            _dir: dir,
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        self.state.best_block_height
+    }
+
+    /// Pay `value` to `address` with a deposit no other call makes.
+    pub fn deposit(&mut self, address: Address, value: u64) -> OutPoint {
+        self.deposits += 1;
+        let txid = bitcoin::Txid::from_inner(hash(&("test-deposit", self.deposits)));
+        let outpoint = OutPoint::Deposit(bitcoin::OutPoint { txid, vout: 0 });
+        let output = Output {
+            address,
+            content: Content::Value(value),
+        };
+        self.state
+            .connect_deposits(&HashMap::from([(outpoint, output)]))
+            .unwrap();
+        outpoint
+    }
+
+    /// Authorize a transaction spending `inputs`, all of which have to be
+    /// utxos.
+    pub fn sign(&self, inputs: Vec<OutPoint>, outputs: Vec<Output>) -> AuthorizedTransaction {
+        let spent_utxos: Vec<Output> = inputs
+            .iter()
+            .map(|input| self.state.get_utxo(input).unwrap().unwrap())
+            .collect();
+        authorize_transaction(
+            &self.keypairs,
+            &spent_utxos,
+            Transaction { inputs, outputs },
+        )
+    }
+
+    pub fn connect(&mut self, transactions: Vec<AuthorizedTransaction>) -> Result<Body, Error> {
+        let body = Body::new(transactions, vec![]);
+        self.state.connect_body(&body)?;
+        Ok(body)
+    }
+
+    /// Connect `blocks` empty bodies.
+    pub fn advance(&mut self, blocks: u32) {
+        for _ in 0..blocks {
+            self.connect(vec![]).unwrap();
+        }
+    }
+
+    /// Transaction committing to `name` under `salt`, paid to `owner` and
+    /// funded by a fresh deposit. The commitment is vout 0.
+    pub fn commit_tx(&mut self, owner: Address, name: &str, salt: u64) -> AuthorizedTransaction {
+        let funding = self.deposit(owner, 1);
+        let commitment = blake2b_hmac(&key_of(name), salt);
+        self.sign(
+            vec![funding],
+            vec![custom(
+                owner,
+                BitNamesOutput::Commitment { commitment, fee: 0 },
+            )],
+        )
+    }
+
+    /// Commit to `name` in a block of its own, returning the outpoint of the
+    /// commitment.
+    pub fn commit(&mut self, owner: Address, name: &str, salt: u64) -> Result<OutPoint, Error> {
+        let transaction = self.commit_tx(owner, name, salt);
+        let txid = transaction.transaction.txid();
+        self.connect(vec![transaction])?;
+        Ok(OutPoint::Regular { txid, vout: 0 })
+    }
+
+    /// Transaction revealing the commitment at `commitment` to `name`, paid
+    /// to `owner`. The name is vout 0.
+    pub fn reveal_tx(
+        &self,
+        commitment: OutPoint,
+        owner: Address,
+        name: &str,
+        salt: u64,
+    ) -> AuthorizedTransaction {
+        self.sign(
+            vec![commitment],
+            vec![custom(
+                owner,
+                BitNamesOutput::Reveal {
+                    salt,
+                    key: key_of(name),
+                    name: Some(name.into()),
+                    path: vec![],
+                    value: None,
+                },
+            )],
+        )
+    }
+
+    /// Reveal the commitment at `commitment` in a block of its own,
+    /// returning the outpoint of the name.
+    pub fn reveal(
+        &mut self,
+        commitment: OutPoint,
+        owner: Address,
+        name: &str,
+        salt: u64,
+    ) -> Result<OutPoint, Error> {
+        let transaction = self.reveal_tx(commitment, owner, name, salt);
+        let txid = transaction.transaction.txid();
+        self.connect(vec![transaction])?;
+        Ok(OutPoint::Regular { txid, vout: 0 })
+    }
+
+    /// Commit to `name` and reveal it in the next block.
+    pub fn register(&mut self, owner: Address, name: &str, salt: u64) -> Result<OutPoint, Error> {
+        let commitment = self.commit(owner, name, salt)?;
+        self.reveal(commitment, owner, name, salt)
+    }
+
+    /// Spend the output at `holder` into a single `output`,
+    /// paid to `to`.
+    pub fn spend_tx(
+        &self,
+        holder: OutPoint,
+        to: Address,
+        output: BitNamesOutput,
+    ) -> AuthorizedTransaction {
+        self.sign(vec![holder], vec![custom(to, output)])
+    }
+}
+
+/// Register `name` with a value of a single A record pointing at `ip`, and
+/// store the record in `nameserver` so it is served.
+pub fn register_with_a_record(
+    chain: &mut TestChain,
+    nameserver: &mut NameServer,
+    name: &str,
+    ip: &str,
+) {
+    let owner = chain.addresses[0];
+    let mut records = RecordSet::new();
+    records.insert(RecordType::A, ip);
+    let registration = chain.register(owner, name, 1).unwrap();
+    let set_value = chain.spend_tx(
+        registration,
+        owner,
+        BitNamesOutput::KeyValue {
+            key: key_of(name),
+            value: Some(records.value()),
+        },
+    );
+    chain.connect(vec![set_value]).unwrap();
+    nameserver
+        .store(&chain.state, &Name::parse(name).unwrap(), &records)
+        .unwrap();
+}
@@ -3,12 +3,176 @@ use sdk_authorization_ed25519_dalek::Authorization;
 use sdk_types::*;
 pub use sdk_types::{Address, Content, OutPoint};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BitNamesOutput {
-    Commitment(Commitment),
-    Reveal { salt: u64, key: Key },
-    KeyValue { key: Key, value: Option<Value> },
+    /// `fee` is locked in the commitment and has to be paid as fee by the
+    /// reveal spending it, see `BitNamesParams::min_commitment_fee`.
+    Commitment {
+        commitment: Commitment,
+        fee: u64,
+    },
+    Reveal {
+        salt: u64,
+        key: Key,
+        /// Plaintext name, needed for rules that can't be checked on the key alone.
+        name: Option<String>,
+        /// Proof that `key` is part of a batch commitment, empty for a
+        /// commitment to `key` alone.
+        path: Vec<MerkleStep>,
+        /// Initial value, so that a name can be registered and resolve in a
+        /// single transaction. Setting one refunds the deposit right away.
+        value: Option<Value>,
+    },
+    KeyValue {
+        key: Key,
+        value: Option<Value>,
+    },
+    /// Values to pick between in proportion to their weight, e.g. for load
+    /// balancing.
+    WeightedValues {
+        key: Key,
+        values: Vec<(Value, u16)>,
+    },
+    /// Hands issuance of names under `key` to `delegate`, or takes it back
+    /// with `None`. Only the holder of `key` can delegate it.
+    Delegation {
+        key: Key,
+        delegate: Option<Address>,
+    },
+    /// Binds the transaction to the block context it was signed for, see
+    /// `BitNamesState::next_block_context`. It is covered by the signatures
+    /// like any output, but never becomes a utxo.
+    ContextBinding(Hash),
+    /// Restarts the expiry countdown of `key`'s commitment at the renewal's
+    /// height. The commitment keeps the height it was made at, so renewing
+    /// never changes who wins a contested name. Only the holder of `key`
+    /// can renew it, and renewing any key of a batch renews the whole batch
+    /// since they share a commitment.
+    Renewal {
+        key: Key,
+    },
+    /// Moves `key` to the output's address, which has to differ from the
+    /// address of the spent output holding it. `value` has to be the
+    /// current value of `key`, `None` for a name without one, which the
+    /// transfer leaves as is. Transfers of several keys can share a
+    /// transaction, e.g. to swap names between owners, as long as it carries
+    /// on every key it spends.
+    Transfer {
+        key: Key,
+        value: Option<Value>,
+    },
+    /// Burns `amount` to make `key` permanently unregistrable, see
+    /// `BitNamesParams::min_burn_reserve`. Only unregistered keys can be
+    /// burned, and like a context binding the output never becomes a utxo.
+    BurnReserve {
+        key: Key,
+        amount: u64,
+    },
+    /// Bids `amount` for `key` in its auction, see
+    /// `BitNamesParams::auction_bid_window`. `commitment` is made like that
+    /// of a `Commitment`, and only the reveal opening the highest bid wins
+    /// the key, paying the bid as fee. Losing bids are reclaimed by spending
+    /// them once bidding is over.
+    Bid {
+        key: Key,
+        commitment: Commitment,
+        amount: u64,
+    },
+}
+
+impl BitNamesOutput {
+    /// Key this output registers or updates, if any.
+    pub fn key(&self) -> Option<Key> {
+        match self {
+            Self::Commitment { .. } => None,
+            Self::Reveal { key, .. }
+            | Self::KeyValue { key, .. }
+            | Self::WeightedValues { key, .. }
+            | Self::Delegation { key, .. }
+            | Self::Renewal { key }
+            | Self::Transfer { key, .. } => Some(*key),
+            Self::ContextBinding(_) | Self::BurnReserve { .. } | Self::Bid { .. } => None,
+        }
+    }
+
+    /// Commitment opened by a reveal under `scheme`.
+    pub fn revealed_commitment(&self, scheme: &dyn CommitmentScheme) -> Option<Commitment> {
+        match self {
+            Self::Reveal {
+                salt, key, path, ..
+            } => {
+                // An empty path opens a commitment to the key alone.
+                if path.is_empty() {
+                    return Some(scheme.commit(key, *salt));
+                }
+                let root = merkle_root_from_path((*key).into(), path);
+                Some(scheme.commit(&root.into(), *salt))
+            }
+            Self::Commitment { .. }
+            | Self::KeyValue { .. }
+            | Self::WeightedValues { .. }
+            | Self::Delegation { .. }
+            | Self::ContextBinding(_)
+            | Self::Renewal { .. }
+            | Self::Transfer { .. }
+            | Self::BurnReserve { .. }
+            | Self::Bid { .. } => None,
+        }
+    }
+
+    /// Commitment carried by this output, a reveal only implies one.
+    pub fn commitment(&self) -> Option<Commitment> {
+        match self {
+            Self::Commitment { commitment, .. } => Some(*commitment),
+            Self::Reveal { .. }
+            | Self::KeyValue { .. }
+            | Self::WeightedValues { .. }
+            | Self::Delegation { .. }
+            | Self::ContextBinding(_)
+            | Self::Renewal { .. }
+            | Self::Transfer { .. }
+            | Self::BurnReserve { .. }
+            | Self::Bid { .. } => None,
+        }
+    }
+}
+
+/// Heights of the events in a name's life, `None` for those that haven't
+/// happened yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameTimeline {
+    /// Height of the commitment the name was revealed against.
+    pub committed: Option<u32>,
+    pub revealed: Option<u32>,
+    /// Latest height a value was set at.
+    pub value_set: Option<u32>,
+    /// Latest height the name was passed on by a `Transfer` output at.
+    pub transferred: Option<u32>,
+}
+
+/// Auction of a key, opened by its first bid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Auction {
+    pub opened: u32,
+    /// Outpoint and amount of the highest bid, the earliest of equal ones.
+    pub top_bid: (OutPoint, u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuctionPhase {
+    Bidding,
+    Revealing,
+    Ended,
+}
+
+/// Deposit escrowed by a reveal, see `BitNamesParams::registration_deposit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegistrationDeposit {
+    pub height: u32,
+    pub amount: u64,
+    pub refund_address: Address,
 }
 
 pub type Output = sdk_types::Output<BitNamesOutput>;
@@ -19,6 +183,101 @@ pub type Body = sdk_types::Body<Authorization, BitNamesOutput>;
 impl GetValue for BitNamesOutput {
     #[inline(always)]
     fn get_value(&self) -> u64 {
-        0
+        match self {
+            Self::Commitment { fee, .. } => *fee,
+            Self::BurnReserve { amount, .. } | Self::Bid { amount, .. } => *amount,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BodySummary {
+    pub transactions: usize,
+    pub inputs: usize,
+    pub outputs: usize,
+    pub commitments: usize,
+    pub reveals: usize,
+    pub key_values: usize,
+    pub value_transfers: usize,
+    pub value_out: u64,
+    /// Only known once spent utxos are resolved, see `BitNamesState::summarize_body`.
+    pub fee: Option<u64>,
+}
+
+pub trait BodyExt {
+    fn summary(&self) -> BodySummary;
+    /// Every input in the body, in transaction order.
+    fn inputs(&self) -> Vec<OutPoint>;
+    /// Set of outpoints the body consumes, comparing its length with
+    /// `inputs` detects an outpoint being spent twice.
+    fn spent_outpoints(&self) -> HashSet<OutPoint>;
+}
+
+impl BodyExt for Body {
+    fn inputs(&self) -> Vec<OutPoint> {
+        self.transactions
+            .iter()
+            .flat_map(|transaction| transaction.inputs.iter())
+            .copied()
+            .collect()
+    }
+
+    fn spent_outpoints(&self) -> HashSet<OutPoint> {
+        self.inputs().into_iter().collect()
+    }
+
+    fn summary(&self) -> BodySummary {
+        let mut summary = BodySummary {
+            transactions: self.transactions.len(),
+            ..Default::default()
+        };
+        for transaction in &self.transactions {
+            summary.inputs += transaction.inputs.len();
+            summary.outputs += transaction.outputs.len();
+            for output in &transaction.outputs {
+                match output.content {
+                    Content::Custom(BitNamesOutput::Commitment { .. }) => summary.commitments += 1,
+                    Content::Custom(BitNamesOutput::Reveal { .. }) => summary.reveals += 1,
+                    Content::Custom(
+                        BitNamesOutput::KeyValue { .. } | BitNamesOutput::WeightedValues { .. },
+                    ) => summary.key_values += 1,
+                    Content::Value(value) => {
+                        summary.value_transfers += 1;
+                        summary.value_out += value;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessors_pick_out_keys_and_commitments() {
+        let key: Key = hash(&"accessor").into();
+        let commitment = blake2b_hmac(&key, 1);
+        let committed = BitNamesOutput::Commitment { commitment, fee: 0 };
+        assert_eq!(committed.key(), None);
+        assert_eq!(committed.commitment(), Some(commitment));
+
+        let reveal = BitNamesOutput::Reveal {
+            salt: 1,
+            key,
+            name: None,
+            path: vec![],
+            value: None,
+        };
+        assert_eq!(reveal.key(), Some(key));
+        assert_eq!(reveal.commitment(), None);
+        assert_eq!(reveal.revealed_commitment(&Blake2bHmac), Some(commitment));
+        let update = BitNamesOutput::KeyValue { key, value: None };
+        assert_eq!(update.key(), Some(key));
+        assert_eq!(update.revealed_commitment(&Blake2bHmac), None);
     }
 }
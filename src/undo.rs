@@ -0,0 +1,30 @@
+use crate::hashes::*;
+use crate::types::*;
+use sdk_types::Txid;
+use serde::{Deserialize, Serialize};
+
+/// Everything needed to disconnect a block: the prior contents of every
+/// database entry it touched, in the order they were touched, with `None`
+/// for entries that didn't exist. Restoring each list in reverse undoes the
+/// block.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    /// Transactions of the connected body, to check that the body being
+    /// disconnected is the one this undo record belongs to.
+    pub txids: Vec<Txid>,
+    pub key_to_value: Vec<(Key, Option<Option<Value>>)>,
+    pub key_to_weighted_values: Vec<(Key, Option<Vec<(Value, u16)>>)>,
+    pub commitment_to_height: Vec<(Commitment, Option<u32>)>,
+    pub commitment_to_renewal: Vec<(Commitment, Option<u32>)>,
+    pub commitment_to_outpoint: Vec<(Commitment, Option<OutPoint>)>,
+    pub key_to_commitment: Vec<(Key, Option<Commitment>)>,
+    pub commitment_to_key: Vec<(Commitment, Option<Vec<Key>>)>,
+    pub key_to_deposit: Vec<(Key, Option<RegistrationDeposit>)>,
+    pub ownership_log: Vec<(Key, Option<Vec<(u32, Address)>>)>,
+    pub key_to_delegate: Vec<(Key, Option<Address>)>,
+    pub key_to_timeline: Vec<(Key, Option<NameTimeline>)>,
+    pub value_to_keys: Vec<(Value, Option<Vec<Key>>)>,
+    pub burned_keys: Vec<(Key, Option<u32>)>,
+    pub key_to_auction: Vec<(Key, Option<Auction>)>,
+    pub utxos: Vec<(OutPoint, Option<Output>)>,
+}
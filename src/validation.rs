@@ -1,46 +1,161 @@
+#[cfg(not(feature = "parallel"))]
+use crate::authorization::verify_authorizations_batch;
+#[cfg(feature = "parallel")]
+use crate::authorization::verify_authorizations_parallel;
+use crate::events::{Event, EventSink, Subscription};
 use crate::hashes::*;
+use crate::name::{Name, NameError};
+use crate::params::{BitNamesParams, ConfigError};
 use crate::types::*;
+use crate::undo::BlockUndo;
 use sdk_authorization_ed25519_dalek::verify_authorizations;
-use sdk_types::{validate_body, validate_transaction, OutPoint};
+use sdk_types::{validate_body, validate_transaction, GetValue as _, OutPoint};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
 use heed::types::*;
-use heed::{Database, RoTxn};
+use heed::{BytesEncode, Database, RoTxn, RwTxn, BEU32};
+
+pub use heed::DatabaseStat as DbStat;
+
+/// Format version of `BitNamesState::export_snapshot` streams, bumped
+/// whenever a database is added or changes encoding.
+pub const SNAPSHOT_VERSION: u8 = 7;
+
+/// Entry of `BitNamesState::meta` holding the height of the tip.
+const TIP_HEIGHT: &str = "best_block_height";
+
+/// Entry of `BitNamesState::meta` bumped on every connected or disconnected
+/// body, see `current_generation`.
+const GENERATION: &str = "generation";
 
 pub struct BitNamesState {
     pub env: heed::Env,
 
     pub key_to_value: Database<SerdeBincode<Key>, SerdeBincode<Option<Value>>>,
+    pub key_to_weighted_values: Database<SerdeBincode<Key>, SerdeBincode<Vec<(Value, u16)>>>,
     pub commitment_to_height: Database<SerdeBincode<Commitment>, OwnedType<u32>>,
+    /// Height each renewed commitment was last renewed at. Its expiry
+    /// countdown restarts there, while `commitment_to_height` keeps the
+    /// height it was made at, which decides contested names.
+    pub commitment_to_renewal: Database<SerdeBincode<Commitment>, OwnedType<u32>>,
     pub commitment_to_outpoint: Database<SerdeBincode<Commitment>, SerdeBincode<OutPoint>>,
     pub key_to_commitment: Database<SerdeBincode<Key>, SerdeBincode<Commitment>>,
-    pub commitment_to_key: Database<SerdeBincode<Commitment>, SerdeBincode<Key>>,
+    /// Every key revealed against a commitment, more than one for batches.
+    pub commitment_to_key: Database<SerdeBincode<Commitment>, SerdeBincode<Vec<Key>>>,
+    /// Deposits of names that have no value yet.
+    pub key_to_deposit: Database<SerdeBincode<Key>, SerdeBincode<RegistrationDeposit>>,
+
+    /// Every address that has held each key, with the height it received
+    /// the key at, oldest first.
+    pub ownership_log: Database<SerdeBincode<Key>, SerdeBincode<Vec<(u32, Address)>>>,
+
+    /// Address that has to authorize registrations under each delegated
+    /// key.
+    pub key_to_delegate: Database<SerdeBincode<Key>, SerdeBincode<Address>>,
+
+    pub key_to_timeline: Database<SerdeBincode<Key>, SerdeBincode<NameTimeline>>,
+
+    /// Keys currently set to each value, the reverse of `key_to_value`.
+    pub value_to_keys: Database<SerdeBincode<Value>, SerdeBincode<Vec<Key>>>,
+
+    /// Height each burn-reserved key was burned at.
+    pub burned_keys: Database<SerdeBincode<Key>, OwnedType<u32>>,
+
+    /// Latest auction of each key that has been bid for.
+    pub key_to_auction: Database<SerdeBincode<Key>, SerdeBincode<Auction>>,
+
+    /// Keys revealed in each block, in output order.
+    pub height_to_registrations: Database<OwnedType<BEU32>, SerdeBincode<Vec<Key>>>,
+
+    /// Change in bytes of database pages in use caused by connecting each
+    /// block, negative when it freed more than it took.
+    pub height_to_growth: Database<OwnedType<BEU32>, SerdeBincode<i64>>,
+
+    /// Id of the block connected at each height, see `block_id`.
+    pub height_to_block_id: Database<OwnedType<BEU32>, SerdeBincode<Hash>>,
 
     pub utxos: Database<SerdeBincode<OutPoint>, SerdeBincode<Output>>,
+    /// Undo records of the last `max_reorg_depth` blocks, by height. Keys
+    /// are big endian so that the last entry is the tip.
+    pub undo: Database<OwnedType<BEU32>, SerdeBincode<BlockUndo>>,
+    /// Entries that describe the chain as a whole rather than any key, see
+    /// `TIP_HEIGHT`.
+    pub meta: Database<Str, OwnedType<u32>>,
     pub best_block_height: u32,
+
+    pub params: BitNamesParams,
+    commitment_scheme: Box<dyn CommitmentScheme>,
+    events: EventSink,
 }
 
 impl BitNamesState {
-    pub fn new(env: &heed::Env) -> Result<Self, Error> {
+    pub fn new(env: &heed::Env, params: BitNamesParams) -> Result<Self, Error> {
+        params.validate()?;
         let key_to_value = env.create_database(Some("key_to_value"))?;
+        let key_to_weighted_values = env.create_database(Some("key_to_weighted_values"))?;
         let commitment_to_height = env.create_database(Some("commitment_to_height"))?;
+        let commitment_to_renewal = env.create_database(Some("commitment_to_renewal"))?;
         let commitment_to_outpoint = env.create_database(Some("commitment_to_outpoint"))?;
         let key_to_commitment = env.create_database(Some("key_to_commitment"))?;
         let commitment_to_key = env.create_database(Some("commitment_to_key"))?;
+        let key_to_deposit = env.create_database(Some("key_to_deposit"))?;
+        let ownership_log = env.create_database(Some("ownership_log"))?;
+        let key_to_delegate = env.create_database(Some("key_to_delegate"))?;
+        let key_to_timeline = env.create_database(Some("key_to_timeline"))?;
+        let value_to_keys = env.create_database(Some("value_to_keys"))?;
+        let burned_keys = env.create_database(Some("burned_keys"))?;
+        let key_to_auction = env.create_database(Some("key_to_auction"))?;
+        let height_to_registrations = env.create_database(Some("height_to_registrations"))?;
+        let height_to_growth = env.create_database(Some("height_to_growth"))?;
+        let height_to_block_id = env.create_database(Some("height_to_block_id"))?;
         let utxos = env.create_database(Some("utxos"))?;
+        let undo = env.create_database(Some("undo"))?;
+        let meta: Database<Str, OwnedType<u32>> = env.create_database(Some("meta"))?;
+        // Written along with every connected and disconnected body, so it
+        // tells us where we were before a restart.
+        let best_block_height = {
+            let rtxn = env.read_txn()?;
+            meta.get(&rtxn, TIP_HEIGHT)?.unwrap_or(0)
+        };
 
         Ok(Self {
             env: env.clone(),
             key_to_value,
+            key_to_weighted_values,
             commitment_to_height,
+            commitment_to_renewal,
             commitment_to_outpoint,
             key_to_commitment,
             commitment_to_key,
+            key_to_deposit,
+            ownership_log,
+            key_to_delegate,
+            key_to_timeline,
+            value_to_keys,
+            burned_keys,
+            key_to_auction,
+            height_to_registrations,
+            height_to_growth,
+            height_to_block_id,
             utxos,
-            best_block_height: 0,
+            undo,
+            meta,
+            best_block_height,
+            commitment_scheme: Box::new(Blake2bHmac),
+            events: EventSink::new(params.event_capacity, params.event_overflow),
+            params,
         })
     }
 
+    /// Check reveals against commitments made under `scheme` rather than
+    /// `Blake2bHmac`. Every node of a chain has to use the same scheme.
+    pub fn with_commitment_scheme(mut self, scheme: Box<dyn CommitmentScheme>) -> Self {
+        self.commitment_scheme = scheme;
+        self
+    }
+
     pub fn connect_deposits(&self, deposits: &HashMap<OutPoint, Output>) -> Result<(), Error> {
         let mut wtxn = self.env.write_txn()?;
         for (outpoint, deposit) in deposits {
@@ -50,53 +165,538 @@ impl BitNamesState {
         Ok(())
     }
 
+    /// Events are emitted once a connected body is committed, see
+    /// `BitNamesParams::event_overflow` for what happens to slow subscribers.
+    pub fn subscribe(&self) -> Subscription {
+        self.events.subscribe()
+    }
+
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
+    /// Bumped on every connected or disconnected body, so cached query
+    /// results can tell whether they are stale. It is stored along with the
+    /// state, so every handle on the same environment sees the same value.
+    pub fn current_generation(&self) -> Result<u32, Error> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.meta.get(&rtxn, GENERATION)?.unwrap_or(0))
+    }
+
+    fn bump_generation(&self, wtxn: &mut RwTxn) -> Result<(), Error> {
+        let generation = self.meta.get(wtxn, GENERATION)?.unwrap_or(0);
+        self.meta
+            .put(wtxn, GENERATION, &generation.wrapping_add(1))?;
+        Ok(())
+    }
+
+    pub fn epoch_of(&self, height: u32) -> u32 {
+        height / self.params.epoch_length
+    }
+
+    pub fn current_epoch(&self) -> u32 {
+        self.epoch_of(self.best_block_height)
+    }
+
     pub fn get_value(&self, key: &Key) -> Result<Option<Option<Value>>, Error> {
         let rtxn = self.env.read_txn()?;
         Ok(self.key_to_value.get(&rtxn, key)?)
     }
 
+    pub fn get_weighted_values(&self, key: &Key) -> Result<Option<Vec<(Value, u16)>>, Error> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.key_to_weighted_values.get(&rtxn, key)?)
+    }
+
     pub fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<Output>, Error> {
         let rtxn = self.env.read_txn()?;
         Ok(self.utxos.get(&rtxn, outpoint)?)
     }
 
-    fn get_utxos(
+    /// Outpoint of the output that made `commitment`, which its reveal has
+    /// to spend. `None` once the commitment has expired.
+    pub fn get_commitment_outpoint(
         &self,
-        txn: &RoTxn,
-        inputs: &[OutPoint],
-    ) -> (Vec<Option<Output>>, Vec<heed::Error>) {
-        let (spent_utxos, errors): (Vec<_>, Vec<_>) = inputs
-            .iter()
-            .map(|outpoint| self.utxos.get(txn, outpoint))
-            .partition(Result::is_ok);
-        let spent_utxos: Vec<_> = spent_utxos.into_iter().map(Result::unwrap).collect();
-        let errors: Vec<_> = errors.into_iter().map(Result::unwrap_err).collect();
-        (spent_utxos, errors)
+        commitment: &Commitment,
+    ) -> Result<Option<OutPoint>, Error> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.commitment_to_outpoint.get(&rtxn, commitment)?)
     }
 
-    pub fn validate_body(&self, block_height: u32, body: &Body) -> Result<u64, Error> {
-        verify_authorizations(body)?;
+    /// Commitments that will be swept by one of the next `blocks` blocks,
+    /// with the number of blocks left until then, soonest first. A
+    /// commitment with `n` blocks left is swept when block
+    /// `best_block_height + n` is connected, so it can still be revealed in
+    /// the `n - 1` blocks before.
+    pub fn expiring_within(&self, blocks: u32) -> Result<Vec<(Commitment, u32)>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut expiring = vec![];
+        for item in self.commitment_to_height.iter(&rtxn)? {
+            let (commitment, height) = item?;
+            let height = self.expiry_base(&rtxn, &commitment, height)?;
+            let swept_at = height + self.params.commitment_max_age + 1;
+            let remaining = swept_at.saturating_sub(self.best_block_height);
+            if remaining <= blocks {
+                expiring.push((commitment, remaining));
+            }
+        }
+        expiring.sort_by_key(|(_, remaining)| *remaining);
+        Ok(expiring)
+    }
+
+    /// Address holding the utxo that controls `key`, see `owner_of`.
+    pub fn get_owner(&self, key: &Key) -> Result<Option<Address>, Error> {
+        self.owner_of(key)
+    }
+
+    /// Outpoint of the commitment `key` was revealed against. Keys are only
+    /// mapped to commitments by their reveal, so this is `None` for keys
+    /// that were never revealed or whose commitment has since expired.
+    pub fn commitment_outpoint_for_key(&self, key: &Key) -> Result<Option<OutPoint>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let Some(commitment) = self.key_to_commitment.get(&rtxn, key)? else {
+            return Ok(None);
+        };
+        Ok(self.commitment_to_outpoint.get(&rtxn, &commitment)?)
+    }
+
+    /// All registered keys, with `None` for keys that have no value yet.
+    pub fn iter_names(&self) -> Result<Vec<(Key, Option<Value>)>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut names = vec![];
+        for item in self.key_to_value.iter(&rtxn)? {
+            names.push(item?);
+        }
+        Ok(names)
+    }
+
+    /// Registered keys that are still waiting for their first value.
+    pub fn registered_without_value(&self) -> Result<Vec<Key>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut keys = vec![];
+        for item in self.key_to_value.iter(&rtxn)? {
+            if let (key, None) = item? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let rtxn = self.env.read_txn()?;
+        Ok(Stats {
+            names: self.key_to_value.len(&rtxn)?,
+            commitments: self.commitment_to_height.len(&rtxn)?,
+            utxos: self.utxos.len(&rtxn)?,
+        })
+    }
+
+    /// Every state database along with its name, typed as raw bytes so that
+    /// they can all be walked, dumped and compared alike.
+    fn raw_databases(&self) -> [(&'static str, Database<ByteSlice, ByteSlice>); 20] {
+        [
+            ("key_to_value", self.key_to_value.remap_types()),
+            (
+                "key_to_weighted_values",
+                self.key_to_weighted_values.remap_types(),
+            ),
+            (
+                "commitment_to_height",
+                self.commitment_to_height.remap_types(),
+            ),
+            (
+                "commitment_to_renewal",
+                self.commitment_to_renewal.remap_types(),
+            ),
+            (
+                "commitment_to_outpoint",
+                self.commitment_to_outpoint.remap_types(),
+            ),
+            ("key_to_commitment", self.key_to_commitment.remap_types()),
+            ("commitment_to_key", self.commitment_to_key.remap_types()),
+            ("key_to_deposit", self.key_to_deposit.remap_types()),
+            ("ownership_log", self.ownership_log.remap_types()),
+            ("key_to_delegate", self.key_to_delegate.remap_types()),
+            ("key_to_timeline", self.key_to_timeline.remap_types()),
+            ("value_to_keys", self.value_to_keys.remap_types()),
+            ("burned_keys", self.burned_keys.remap_types()),
+            ("key_to_auction", self.key_to_auction.remap_types()),
+            (
+                "height_to_registrations",
+                self.height_to_registrations.remap_types(),
+            ),
+            ("height_to_growth", self.height_to_growth.remap_types()),
+            ("height_to_block_id", self.height_to_block_id.remap_types()),
+            ("utxos", self.utxos.remap_types()),
+            ("undo", self.undo.remap_types()),
+            ("meta", self.meta.remap_types()),
+        ]
+    }
+
+    /// Write every database and the best block height to `w`, so a new node
+    /// can `import_snapshot` instead of replaying every block. The stream
+    /// starts with `SNAPSHOT_VERSION`, then each database's name and entry
+    /// count followed by its entries, all bincode encoded.
+    pub fn export_snapshot<W: std::io::Write>(&self, w: &mut W) -> Result<(), Error> {
+        let rtxn = self.env.read_txn()?;
+        bincode::serialize_into(&mut *w, &SNAPSHOT_VERSION)?;
+        bincode::serialize_into(&mut *w, &self.best_block_height)?;
+        for (name, db) in self.raw_databases() {
+            bincode::serialize_into(&mut *w, &(name, db.len(&rtxn)?))?;
+            for item in db.iter(&rtxn)? {
+                let (key, value) = item?;
+                bincode::serialize_into(&mut *w, &(key, value))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the whole state with a snapshot written by `export_snapshot`,
+    /// in a single write transaction, so a failed import leaves the state as
+    /// it was.
+    pub fn import_snapshot<R: std::io::Read>(&mut self, r: &mut R) -> Result<(), Error> {
+        let version: u8 = bincode::deserialize_from(&mut *r)?;
+        if version != SNAPSHOT_VERSION {
+            Err(BitNamesError::SnapshotVersionMismatch {
+                expected: SNAPSHOT_VERSION,
+                found: version,
+            })?;
+        }
+        let best_block_height: u32 = bincode::deserialize_from(&mut *r)?;
+        let mut wtxn = self.env.write_txn()?;
+        // Carried over rather than taken from the snapshot, which could hold
+        // a generation some cache was filled at.
+        let generation = self.meta.get(&wtxn, GENERATION)?.unwrap_or(0);
+        for (expected, db) in self.raw_databases() {
+            let (name, len): (String, u64) = bincode::deserialize_from(&mut *r)?;
+            if name != expected {
+                Err(BitNamesError::SnapshotDatabaseMismatch {
+                    expected: expected.into(),
+                    found: name,
+                })?;
+            }
+            db.clear(&mut wtxn)?;
+            for _ in 0..len {
+                let (key, value): (Vec<u8>, Vec<u8>) = bincode::deserialize_from(&mut *r)?;
+                db.put(&mut wtxn, &key, &value)?;
+            }
+        }
+        self.meta.put(&mut wtxn, GENERATION, &generation)?;
+        self.bump_generation(&mut wtxn)?;
+        wtxn.commit()?;
+        self.best_block_height = best_block_height;
+        Ok(())
+    }
+
+    /// LMDB statistics of every state database, by database name.
+    pub fn db_stats(&self) -> Result<HashMap<&'static str, DbStat>, Error> {
+        let rtxn = self.env.read_txn()?;
+        self.raw_databases()
+            .into_iter()
+            .map(|(name, db)| Ok((name, db.stat(&rtxn)?)))
+            .collect()
+    }
+
+    /// Merkle root over every utxo, in database order.
+    pub fn utxo_root(&self) -> Result<Hash, Error> {
+        let rtxn = self.env.read_txn()?;
+        let (leaves, _) = self.utxo_leaves(&rtxn)?;
+        Ok(merkle_root(&leaves))
+    }
+
+    /// Merkle root over every registered key and its value, in database
+    /// order. Light clients check `KeyProof`s against it, which the flat
+    /// `compute_state_root` can't support.
+    pub fn name_root(&self) -> Result<Hash, Error> {
+        let rtxn = self.env.read_txn()?;
+        let (leaves, _) = self.name_leaves(&rtxn)?;
+        Ok(merkle_root(&leaves))
+    }
+
+    /// Value of `key` with a proof that it is included in `name_root`. Only
+    /// registered keys can be proven, there are no proofs of absence.
+    pub fn prove_key(&self, key: &Key) -> Result<KeyProof, Error> {
         let rtxn = self.env.read_txn()?;
-        let inputs: Vec<OutPoint> = body
-            .transactions
+        let (leaves, names) = self.name_leaves(&rtxn)?;
+        let index = names
             .iter()
-            .flat_map(|transaction| transaction.inputs.iter())
-            .copied()
+            .position(|(name_key, _)| name_key == key)
+            .ok_or(BitNamesError::KeyNotFound { key: *key })?;
+        Ok(KeyProof {
+            value: names[index].1,
+            path: merkle_path(&leaves, index),
+        })
+    }
+
+    fn name_leaves(&self, txn: &RoTxn) -> Result<(Vec<Hash>, Vec<(Key, Option<Value>)>), Error> {
+        let mut leaves = vec![];
+        let mut names = vec![];
+        for item in self.key_to_value.iter(txn)? {
+            let (key, value) = item?;
+            leaves.push(name_leaf(&key, &value));
+            names.push((key, value));
+        }
+        Ok((leaves, names))
+    }
+
+    /// Blake2b over every `(database tag, key, value)` of `key_to_value`,
+    /// `commitment_to_height`, `commitment_to_renewal` and `utxos`, each
+    /// database in LMDB key order.
+    /// Keys are compared as their bincode encoding, so the order, and with it
+    /// the root, doesn't depend on the machine or LMDB version.
+    pub fn compute_state_root(&self) -> Result<Hash, Error> {
+        use digest::Digest as _;
+        let rtxn = self.env.read_txn()?;
+        let mut hasher = blake2::Blake2b::<digest::consts::U32>::new();
+        let mut feed = |entry: Vec<u8>| hasher.update(entry);
+        for item in self.key_to_value.iter(&rtxn)? {
+            let (key, value) = item?;
+            feed(bincode::serialize(&("key_to_value", key, value)).unwrap());
+        }
+        for item in self.commitment_to_height.iter(&rtxn)? {
+            let (commitment, height) = item?;
+            feed(bincode::serialize(&("commitment_to_height", commitment, height)).unwrap());
+        }
+        for item in self.commitment_to_renewal.iter(&rtxn)? {
+            let (commitment, height) = item?;
+            feed(bincode::serialize(&("commitment_to_renewal", commitment, height)).unwrap());
+        }
+        for item in self.utxos.iter(&rtxn)? {
+            let (outpoint, output) = item?;
+            feed(bincode::serialize(&("utxos", outpoint, output)).unwrap());
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// The utxos held by `address`, each with a proof that it is included in
+    /// `utxo_root`.
+    pub fn address_proof(&self, address: &Address) -> Result<AddressProof, Error> {
+        let rtxn = self.env.read_txn()?;
+        let (leaves, utxos) = self.utxo_leaves(&rtxn)?;
+        let utxos = utxos
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (_, output))| output.address == *address)
+            .map(|(index, (outpoint, output))| (outpoint, output, merkle_path(&leaves, index)))
             .collect();
-        let (spent_utxos, _) = self.get_utxos(&rtxn, &inputs);
-        let spent_utxos: Vec<Output> = spent_utxos
+        Ok(AddressProof {
+            address: *address,
+            root: merkle_root(&leaves),
+            utxos,
+        })
+    }
+
+    fn utxo_leaves(&self, txn: &RoTxn) -> Result<(Vec<Hash>, Vec<(OutPoint, Output)>), Error> {
+        let mut leaves = vec![];
+        let mut utxos = vec![];
+        for item in self.utxos.iter(txn)? {
+            let (outpoint, output) = item?;
+            leaves.push(utxo_leaf(&outpoint, &output));
+            utxos.push((outpoint, output));
+        }
+        Ok((leaves, utxos))
+    }
+
+    /// Keys currently set to `value`, in the order they were set to it.
+    pub fn get_keys_for_value(&self, value: &Value) -> Result<Vec<Key>, Error> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.value_to_keys.get(&rtxn, value)?.unwrap_or_default())
+    }
+
+    /// Timeline of the current registration of `key`, `None` if it was
+    /// never registered.
+    pub fn name_timeline(&self, key: &Key) -> Result<Option<NameTimeline>, Error> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.key_to_timeline.get(&rtxn, key)?)
+    }
+
+    /// Context a transaction has to bind itself to, see `block_context`, to
+    /// only be valid in the next block on top of the current tip.
+    pub fn next_block_context(&self) -> Result<Hash, Error> {
+        let rtxn = self.env.read_txn()?;
+        let parent = self.block_id(&rtxn, self.best_block_height)?;
+        Ok(block_context(self.best_block_height + 1, &parent))
+    }
+
+    /// Id of the block connected at `height`, all zeros for the empty chain
+    /// at height 0.
+    fn block_id(&self, txn: &RoTxn, height: u32) -> Result<Hash, Error> {
+        if height == 0 {
+            return Ok(Hash::default());
+        }
+        Ok(self
+            .height_to_block_id
+            .get(txn, &BEU32::new(height))?
+            .ok_or(BitNamesError::BlockIdNotFound { height })?)
+    }
+
+    /// Height `key` was burn reserved at, `None` if it never was.
+    pub fn burned_at(&self, key: &Key) -> Result<Option<u32>, Error> {
+        let rtxn = self.env.read_txn()?;
+        Ok(self.burned_keys.get(&rtxn, key)?)
+    }
+
+    /// Address the latest output carrying `key` was paid to.
+    pub fn owner_of(&self, key: &Key) -> Result<Option<Address>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let owners = self.ownership_log.get(&rtxn, key)?;
+        Ok(owners.and_then(|owners| owners.last().map(|(_, owner)| *owner)))
+    }
+
+    /// Every key `address` has ever held, with the heights it held it for.
+    /// Ranges of keys it still holds end after the current tip.
+    pub fn historical_names_of(&self, address: &Address) -> Result<Vec<(Key, Range<u32>)>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut names = vec![];
+        for item in self.ownership_log.iter(&rtxn)? {
+            let (key, owners) = item?;
+            for (index, (start, owner)) in owners.iter().enumerate() {
+                if owner != address {
+                    continue;
+                }
+                let end = match owners.get(index + 1) {
+                    Some((end, _)) => *end,
+                    None => self.best_block_height + 1,
+                };
+                names.push((key, *start..end));
+            }
+        }
+        Ok(names)
+    }
+
+    /// Up to `limit` most recent registrations with their heights, newest
+    /// first.
+    pub fn recent_registrations(&self, limit: usize) -> Result<Vec<(Key, u32)>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut registrations = vec![];
+        for item in self.height_to_registrations.rev_iter(&rtxn)? {
+            let (height, keys) = item?;
+            for key in keys.into_iter().rev() {
+                if registrations.len() == limit {
+                    return Ok(registrations);
+                }
+                registrations.push((key, height.get()));
+            }
+        }
+        Ok(registrations)
+    }
+
+    /// Growth in bytes of the databases caused by each of the last `last_n`
+    /// connected blocks, with their heights, oldest first.
+    pub fn growth_history(&self, last_n: usize) -> Result<Vec<(u32, i64)>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut history = self
+            .height_to_growth
+            .rev_iter(&rtxn)?
+            .take(last_n)
+            .map(|item| item.map(|(height, growth)| (height.get(), growth)))
+            .collect::<Result<Vec<_>, _>>()?;
+        history.reverse();
+        Ok(history)
+    }
+
+    /// Bytes of database pages in use, not counting free pages LMDB keeps
+    /// around for reuse.
+    fn used_bytes(&self, rtxn: &RoTxn) -> Result<u64, Error> {
+        let mut used = 0;
+        for (_, db) in self.raw_databases() {
+            let stat = db.stat(rtxn)?;
+            let pages = stat.branch_pages + stat.leaf_pages + stat.overflow_pages;
+            used += pages as u64 * stat.page_size as u64;
+        }
+        Ok(used)
+    }
+
+    /// Addresses controlling more than `squatting_threshold` names that
+    /// still have no value.
+    pub fn squatting_report(&self) -> Result<Vec<SquatFinding>, Error> {
+        let rtxn = self.env.read_txn()?;
+        let mut empty_names: HashMap<Address, Vec<Key>> = HashMap::new();
+        for item in self.utxos.iter(&rtxn)? {
+            let (_, output) = item?;
+            let Content::Custom(bitnames_output) = &output.content else {
+                continue;
+            };
+            let Some(key) = bitnames_output.key() else {
+                continue;
+            };
+            if let Some(None) = self.key_to_value.get(&rtxn, &key)? {
+                empty_names.entry(output.address).or_default().push(key);
+            }
+        }
+        Ok(empty_names
             .into_iter()
-            .collect::<Option<Vec<Output>>>()
-            .unwrap();
+            .filter(|(_, names)| names.len() > self.params.squatting_threshold)
+            .map(|(address, names)| SquatFinding { address, names })
+            .collect())
+    }
+
+    /// Open a handle on a database written to by another `BitNamesState`.
+    /// Reads go straight to `env`, so the handle sees every write as soon as
+    /// it is committed.
+    pub fn read_only(env: &heed::Env) -> Result<ReadOnlyState, Error> {
+        Ok(ReadOnlyState {
+            state: Self::new(env, BitNamesParams::default())?,
+        })
+    }
+
+    fn get_utxos(&self, txn: &RoTxn, inputs: &[OutPoint]) -> Result<Vec<Output>, Error> {
+        inputs
+            .iter()
+            .map(|outpoint| {
+                Ok(self
+                    .utxos
+                    .get(txn, outpoint)?
+                    .ok_or(BitNamesError::UtxoNotFound {
+                        outpoint: *outpoint,
+                    })?)
+            })
+            .collect()
+    }
+
+    /// Checks run cheapest first, so that a malformed body never gets as far
+    /// as signature verification.
+    pub fn validate_body(&self, block_height: u32, body: &Body) -> Result<u64, Error> {
+        let rtxn = self.env.read_txn()?;
+        self.validate_body_in(&rtxn, block_height, body)
+    }
+
+    /// `validate_body` against the state as `txn` sees it, which for a
+    /// write transaction includes bodies applied to it but not committed.
+    fn validate_body_in(&self, txn: &RoTxn, block_height: u32, body: &Body) -> Result<u64, Error> {
+        check_body_structure(body)?;
+        let spent_utxos = self.get_utxos(txn, &body.inputs())?;
         {
             let mut index = 0;
             for transaction in &body.transactions {
-                let spent_utxos = &spent_utxos[index..transaction.inputs.len()];
-                self.validate_transaction_pure(&rtxn, spent_utxos, block_height, transaction)?;
+                let spent_utxos = &spent_utxos[index..index + transaction.inputs.len()];
+                self.validate_transaction_pure(txn, spent_utxos, block_height, transaction)?;
                 index += transaction.inputs.len();
             }
         }
-        Ok(validate_body(spent_utxos.as_slice(), body)?)
+        let fee = validate_body(spent_utxos.as_slice(), body)?;
+        // Every transaction covers its own deposits, so this can't underflow.
+        let deposits = body.summary().reveals as u64 * self.params.registration_deposit;
+        let fee = fee - deposits;
+        let coinbase: u64 = body.coinbase.iter().map(|output| output.get_value()).sum();
+        if coinbase > fee {
+            Err(BitNamesError::CoinbaseClaimsDeposits { coinbase, fee })?;
+        }
+        // Either verifier reaches the verdict `verify_authorizations` would,
+        // the feature only decides how the work is done, never consensus.
+        #[cfg(feature = "parallel")]
+        verify_authorizations_parallel(body)?;
+        #[cfg(not(feature = "parallel"))]
+        verify_authorizations_batch(body)?;
+        Ok(fee)
+    }
+
+    pub fn summarize_body(&self, body: &Body) -> Result<BodySummary, Error> {
+        let fee = self.validate_body(self.best_block_height + 1, body)?;
+        Ok(BodySummary {
+            fee: Some(fee),
+            ..body.summary()
+        })
     }
 
     fn validate_transaction_pure(
@@ -108,85 +708,476 @@ impl BitNamesState {
     ) -> Result<(), Error> {
         let spent_commitments: HashSet<Commitment> = spent_utxos
             .iter()
-            .filter_map(|utxo| match utxo.content {
-                Content::Custom(BitNamesOutput::Commitment(commitment)) => Some(commitment),
+            .filter_map(|utxo| match &utxo.content {
+                Content::Custom(output) => output.commitment(),
                 _ => None,
             })
             .collect();
         let spent_keys: HashSet<Key> = spent_utxos
             .iter()
-            .filter_map(|utxo| match utxo.content {
-                Content::Custom(BitNamesOutput::Reveal { key, .. }) => Some(key),
-                Content::Custom(BitNamesOutput::KeyValue { key, .. }) => Some(key),
+            .filter_map(|utxo| match &utxo.content {
+                Content::Custom(output) => output.key(),
                 _ => None,
             })
             .collect();
-        for commitment in &spent_commitments {
-            let height = self.get_commitment_height(txn, commitment)?;
-            if block_height - height > COMMITMENT_MAX_AGE {
-                Err(BitNamesError::RevealTooLate {
-                    commitment: *commitment,
-                    late_by: block_height - height - COMMITMENT_MAX_AGE,
+        if self.params.canonical_output_order {
+            let sort_keys: Vec<Vec<u8>> = transaction
+                .outputs
+                .iter()
+                .map(|output| bincode::serialize(&(&output.content, &output.address)).unwrap())
+                .collect();
+            if let Some(vout) = sort_keys.windows(2).position(|pair| pair[0] > pair[1]) {
+                Err(BitNamesError::NonCanonicalOutputOrder {
+                    vout: vout as u32 + 1,
                 })?;
             }
         }
+        // Bids can't be withdrawn while their auction takes bids, or the top
+        // bid could be pulled once nobody can outbid it any more.
+        for (outpoint, utxo) in transaction.inputs.iter().zip(spent_utxos) {
+            if let Content::Custom(BitNamesOutput::Bid { key, .. }) = &utxo.content {
+                if let Some(auction) = self.key_to_auction.get(txn, key)? {
+                    if self.params.auction_phase(&auction, block_height) == AuctionPhase::Bidding {
+                        Err(BitNamesError::BidLocked {
+                            outpoint: *outpoint,
+                        })?;
+                    }
+                }
+            }
+        }
+        let mut revealed_keys = HashSet::new();
+        let mut revealed_commitments = HashSet::new();
+        let mut value_keys = HashSet::new();
         for output in &transaction.outputs {
-            match output.content {
-                Content::Custom(BitNamesOutput::Reveal { salt, key }) => {
-                    let commitment = blake2b_hmac(&key, salt);
-                    if !spent_commitments.contains(&commitment) {
-                        Err(BitNamesError::InvalidNameCommitment {
+            match &output.content {
+                Content::Custom(
+                    reveal @ BitNamesOutput::Reveal {
+                        salt,
+                        key,
+                        name,
+                        path,
+                        ..
+                    },
+                ) => {
+                    check_proof_depth(path)?;
+                    let (salt, key) = (*salt, *key);
+                    // Checked before anything else, a valid commitment
+                    // doesn't make a reserved name claimable.
+                    if self.params.is_reserved(&key) || self.burned_keys.get(txn, &key)?.is_some() {
+                        Err(BitNamesError::NameReserved { key })?;
+                    }
+                    let name = self.validate_inline_name(txn, &key, name.as_deref())?;
+                    self.validate_delegation(txn, name.as_ref(), spent_utxos)?;
+                    // Reveals from the same batch all spend its one
+                    // commitment, so they have to share a transaction.
+                    let commitment = reveal
+                        .revealed_commitment(self.commitment_scheme.as_ref())
+                        .unwrap();
+                    let spends_bid = spent_utxos.iter().any(|utxo| {
+                        matches!(
+                            &utxo.content,
+                            Content::Custom(BitNamesOutput::Bid { key: bid_key, .. })
+                                if *bid_key == key
+                        )
+                    });
+                    match self.key_to_auction.get(txn, &key)? {
+                        Some(auction)
+                            if spends_bid
+                                || self.params.auction_phase(&auction, block_height)
+                                    != AuctionPhase::Ended =>
+                        {
+                            self.validate_auction_reveal(
+                                txn,
+                                key,
+                                commitment,
+                                &auction,
+                                block_height,
+                                transaction,
+                                spent_utxos,
+                            )?;
+                        }
+                        _ => self.validate_commitment_reveal(
+                            txn,
                             key,
                             salt,
                             commitment,
-                        })?;
+                            &spent_commitments,
+                            block_height,
+                        )?,
                     }
-                    if self.key_to_value.get(txn, &key)?.is_some() {
-                        let commitment_height = self.get_commitment_height(txn, &commitment)?;
-                        let prev_commitment_height = self.get_key_height(txn, &key)?;
-                        if prev_commitment_height < commitment_height {
-                            Err(BitNamesError::KeyAlreadyRegistered {
-                                key,
-                                prev_commitment_height,
-                                commitment_height,
-                            })?;
+                    // Batch reveals share a commitment, but each of them has
+                    // to open it to a different key.
+                    if !revealed_keys.insert(key) {
+                        Err(BitNamesError::DuplicateReveal { key, commitment })?;
+                    }
+                    revealed_commitments.insert(commitment);
+                }
+                Content::Custom(BitNamesOutput::Bid { key, .. }) => {
+                    if self.params.auction_bid_window == 0 {
+                        Err(BitNamesError::AuctionsDisabled { key: *key })?;
+                    }
+                    if self.params.is_reserved(key) || self.burned_keys.get(txn, key)?.is_some() {
+                        Err(BitNamesError::NameReserved { key: *key })?;
+                    }
+                    if self.key_to_value.get(txn, key)?.is_some() {
+                        Err(BitNamesError::BidForRegisteredKey { key: *key })?;
+                    }
+                    // Bids either join an auction that is still taking bids
+                    // or open a new one.
+                    if let Some(auction) = self.key_to_auction.get(txn, key)? {
+                        if self.params.auction_phase(&auction, block_height)
+                            == AuctionPhase::Revealing
+                        {
+                            Err(BitNamesError::BidWindowClosed { key: *key })?;
                         }
                     }
                 }
                 Content::Custom(BitNamesOutput::KeyValue { key, .. }) => {
-                    if !spent_keys.contains(&key) {
-                        Err(BitNamesError::InvalidKey { key })?;
+                    if !spent_keys.contains(key) {
+                        Err(BitNamesError::InvalidKey { key: *key })?;
+                    }
+                }
+                Content::Custom(BitNamesOutput::Delegation { key, .. }) => {
+                    if !spent_keys.contains(key) {
+                        Err(BitNamesError::InvalidKey { key: *key })?;
+                    }
+                }
+                Content::Custom(BitNamesOutput::Commitment { commitment, fee }) => {
+                    let min = self.params.min_commitment_fee;
+                    if *fee < min {
+                        Err(BitNamesError::CommitmentFeeTooLow {
+                            commitment: *commitment,
+                            fee: *fee,
+                            min,
+                        })?;
+                    }
+                }
+                Content::Custom(BitNamesOutput::Transfer { key, value }) => {
+                    let Some(holder) = spent_utxos.iter().find(|utxo| {
+                        matches!(&utxo.content, Content::Custom(spent) if spent.key() == Some(*key))
+                    }) else {
+                        return Err(BitNamesError::InvalidKey { key: *key }.into());
+                    };
+                    if holder.address == output.address {
+                        Err(BitNamesError::SelfTransfer { key: *key })?;
+                    }
+                    let current = self.key_to_value.get(txn, key)?.flatten();
+                    if current != *value {
+                        Err(BitNamesError::TransferValueMismatch {
+                            key: *key,
+                            current,
+                            value: *value,
+                        })?;
+                    }
+                }
+                Content::Custom(BitNamesOutput::BurnReserve { key, amount }) => {
+                    let min = self.params.min_burn_reserve;
+                    if *amount < min {
+                        Err(BitNamesError::BurnTooLow {
+                            key: *key,
+                            amount: *amount,
+                            min,
+                        })?;
+                    }
+                    if self.params.is_reserved(key) || self.burned_keys.get(txn, key)?.is_some() {
+                        Err(BitNamesError::NameReserved { key: *key })?;
+                    }
+                    if self.key_to_value.get(txn, key)?.is_some() {
+                        Err(BitNamesError::BurnOfRegisteredKey { key: *key })?;
+                    }
+                }
+                Content::Custom(BitNamesOutput::Renewal { key }) => {
+                    if !spent_keys.contains(key) {
+                        Err(BitNamesError::InvalidKey { key: *key })?;
+                    }
+                    // An expired registration has nothing left to renew.
+                    self.get_key_height(txn, key)?;
+                }
+                Content::Custom(BitNamesOutput::ContextBinding(context)) => {
+                    let expected =
+                        block_context(block_height, &self.block_id(txn, block_height - 1)?);
+                    if *context != expected {
+                        Err(BitNamesError::ContextMismatch {
+                            expected,
+                            found: *context,
+                        })?;
+                    }
+                }
+                Content::Custom(BitNamesOutput::WeightedValues { key, values }) => {
+                    if !spent_keys.contains(key) {
+                        Err(BitNamesError::InvalidKey { key: *key })?;
+                    }
+                    let max = self.params.max_value_weight;
+                    if let Some((_, weight)) = values
+                        .iter()
+                        .find(|(_, weight)| *weight == 0 || *weight > max)
+                    {
+                        Err(BitNamesError::InvalidWeight {
+                            key: *key,
+                            weight: *weight,
+                            max,
+                        })?;
                     }
                 }
                 _ => {}
             }
+            // Two outputs carrying the key would leave it ambiguous which of
+            // them holds it, and which value or delegate wins.
+            let value_key = match &output.content {
+                Content::Custom(output) => output.key(),
+                _ => None,
+            };
+            if let Some(key) = value_key {
+                if !value_keys.insert(key) {
+                    Err(BitNamesError::DuplicateKeyInTransaction { key })?;
+                }
+            }
+        }
+        // A transaction with transfers, e.g. a swap between two owners, has
+        // to carry on every key it spends, so that no party's name is
+        // dropped while the others move.
+        let transfers = transaction.outputs.iter().any(|output| {
+            matches!(
+                output.content,
+                Content::Custom(BitNamesOutput::Transfer { .. })
+            )
+        });
+        if transfers {
+            let carried_keys: HashSet<Key> = transaction
+                .outputs
+                .iter()
+                .filter_map(|output| match &output.content {
+                    Content::Custom(output) => output.key(),
+                    _ => None,
+                })
+                .collect();
+            if let Some(key) = spent_keys.difference(&carried_keys).next() {
+                Err(BitNamesError::TransferDropsKey { key: *key })?;
+            }
+        }
+        // Revealed commitments can't be reclaimed, their fee has to go to
+        // the block along with any deposits.
+        let commitment_fees: u64 = spent_utxos
+            .iter()
+            .filter_map(|utxo| match &utxo.content {
+                Content::Custom(
+                    BitNamesOutput::Commitment {
+                        commitment,
+                        fee: amount,
+                    }
+                    | BitNamesOutput::Bid {
+                        commitment, amount, ..
+                    },
+                ) if revealed_commitments.contains(commitment) => Some(*amount),
+                _ => None,
+            })
+            .sum();
+        let required =
+            revealed_keys.len() as u64 * self.params.registration_deposit + commitment_fees;
+        if required > 0 {
+            let value_in: u64 = spent_utxos.iter().map(|utxo| utxo.get_value()).sum();
+            let value_out: u64 = transaction
+                .outputs
+                .iter()
+                .map(|output| output.get_value())
+                .sum();
+            let provided = value_in.saturating_sub(value_out);
+            if provided < required {
+                Err(BitNamesError::InsufficientDeposit { required, provided })?;
+            }
         }
         Ok(())
     }
 
-    fn get_commitment_height(&self, txn: &RoTxn, commitment: &Commitment) -> Result<u32, Error> {
-        Ok(self.commitment_to_height.get(txn, commitment)?.ok_or(
-            BitNamesError::CommitmentNotFound {
-                commitment: *commitment,
-            },
-        )?)
+    /// A reveal outside of an auction has to open a commitment it spends
+    /// within its reveal window, and can only take a key from a later
+    /// commitment.
+    fn validate_commitment_reveal(
+        &self,
+        txn: &RoTxn,
+        key: Key,
+        salt: u64,
+        commitment: Commitment,
+        spent_commitments: &HashSet<Commitment>,
+        block_height: u32,
+    ) -> Result<(), Error> {
+        if !spent_commitments.contains(&commitment) {
+            Err(BitNamesError::InvalidNameCommitment {
+                key,
+                salt,
+                commitment,
+            })?;
+        }
+        // A commitment made again, e.g. by reusing a salt, can only be
+        // revealed to the keys it was revealed to before.
+        if let Some(recorded) = self.commitment_to_key.get(txn, &commitment)? {
+            if !recorded.contains(&key) {
+                Err(BitNamesError::CommitmentKeyMismatch {
+                    commitment,
+                    key,
+                    recorded,
+                })?;
+            }
+        }
+        // Only reveals are held to the reveal window. Spent commitments that
+        // aren't revealed by the transaction are reclaims, e.g. by the loser
+        // of a contested name, and since they can no longer win anything
+        // they aren't subject to it.
+        let height = self.get_commitment_height(txn, &commitment)?;
+        let age = commitment_age(commitment, height, block_height)?;
+        if age < self.params.commitment_min_age {
+            Err(BitNamesError::RevealTooEarly {
+                commitment,
+                early_by: self.params.commitment_min_age - age,
+            })?;
+        }
+        if age > self.params.commitment_max_age {
+            Err(BitNamesError::RevealTooLate {
+                commitment,
+                late_by: age - self.params.commitment_max_age,
+            })?;
+        }
+        if self.key_to_value.get(txn, &key)?.is_some() {
+            let prev_commitment_height = self.get_key_height(txn, &key)?;
+            if prev_commitment_height < height {
+                Err(BitNamesError::KeyAlreadyRegistered {
+                    key,
+                    prev_commitment_height,
+                    commitment_height: height,
+                })?;
+            }
+        }
+        Ok(())
     }
 
-    fn get_key_height(&self, txn: &RoTxn, key: &Key) -> Result<u32, Error> {
-        let commitment = self
-            .key_to_commitment
-            .get(txn, key)?
-            .ok_or(BitNamesError::KeyNotFound { key: *key })?;
-        Ok(self
-            .commitment_to_height
-            .get(txn, &commitment)?
-            .ok_or(BitNamesError::CommitmentNotFound { commitment })?)
+    /// A reveal of a key under auction has to open the auction's top bid,
+    /// spent by the same transaction, after bidding has ended.
+    #[allow(clippy::too_many_arguments)]
+    fn validate_auction_reveal(
+        &self,
+        txn: &RoTxn,
+        key: Key,
+        commitment: Commitment,
+        auction: &Auction,
+        block_height: u32,
+        transaction: &Transaction,
+        spent_utxos: &[Output],
+    ) -> Result<(), Error> {
+        let bid = transaction
+            .inputs
+            .iter()
+            .zip(spent_utxos)
+            .find(|(_, utxo)| {
+                matches!(
+                    &utxo.content,
+                    Content::Custom(BitNamesOutput::Bid {
+                        key: bid_key,
+                        commitment: bid_commitment,
+                        ..
+                    }) if *bid_key == key && *bid_commitment == commitment
+                )
+            });
+        let Some((outpoint, _)) = bid else {
+            return Err(BitNamesError::AuctionInProgress { key }.into());
+        };
+        if self.params.auction_phase(auction, block_height) != AuctionPhase::Revealing {
+            Err(BitNamesError::RevealOutsideAuctionWindow { key })?;
+        }
+        if *outpoint != auction.top_bid.0 {
+            Err(BitNamesError::NotTopBid { key })?;
+        }
+        // Registered by a reveal connected in the same block the auction
+        // was opened in.
+        if self.key_to_value.get(txn, &key)?.is_some() {
+            Err(BitNamesError::BidForRegisteredKey { key })?;
+        }
+        Ok(())
+    }
+
+    /// Inline names are required as soon as there are reserved suffixes or
+    /// delegations, since neither can be checked against a key alone. A key
+    /// is the hash of the whole name, so it doesn't tell whether the name is
+    /// under a delegated parent: only requiring names under one would let a
+    /// nameless reveal skip the delegate. Revoking the last delegation lifts
+    /// the requirement again.
+    fn validate_inline_name(
+        &self,
+        txn: &RoTxn,
+        key: &Key,
+        name: Option<&str>,
+    ) -> Result<Option<Name>, Error> {
+        let name = check_inline_name(key, name)?;
+        if self.params.reserved_suffixes.is_empty() && self.key_to_delegate.is_empty(txn)? {
+            return Ok(name);
+        }
+        let name = name.ok_or(BitNamesError::MissingInlineName { key: *key })?;
+        if let Some(suffix) = self.params.reserved_suffix(&name) {
+            Err(BitNamesError::ReservedSuffix {
+                name: name.to_string(),
+                suffix: suffix.into(),
+            })?;
+        }
+        Ok(Some(name))
+    }
+
+    /// Names under a delegated key have to be registered by its delegate,
+    /// the nearest delegated ancestor wins.
+    fn validate_delegation(
+        &self,
+        txn: &RoTxn,
+        name: Option<&Name>,
+        spent_utxos: &[Output],
+    ) -> Result<(), Error> {
+        let mut ancestor = name.and_then(Name::parent);
+        while let Some(parent) = ancestor {
+            if let Some(delegate) = self.key_to_delegate.get(txn, &parent.key())? {
+                if !spent_utxos.iter().any(|utxo| utxo.address == delegate) {
+                    Err(BitNamesError::NotDelegate {
+                        name: name.unwrap().to_string(),
+                        parent: parent.to_string(),
+                    })?;
+                }
+                return Ok(());
+            }
+            ancestor = parent.parent();
+        }
+        Ok(())
+    }
+
+    fn get_commitment_height(&self, txn: &RoTxn, commitment: &Commitment) -> Result<u32, Error> {
+        Ok(self.commitment_to_height.get(txn, commitment)?.ok_or(
+            BitNamesError::CommitmentNotFound {
+                commitment: *commitment,
+            },
+        )?)
+    }
+
+    fn get_key_height(&self, txn: &RoTxn, key: &Key) -> Result<u32, Error> {
+        let commitment = self
+            .key_to_commitment
+            .get(txn, key)?
+            .ok_or(BitNamesError::KeyNotFound { key: *key })?;
+        Ok(self
+            .commitment_to_height
+            .get(txn, &commitment)?
+            .ok_or(BitNamesError::CommitmentNotFound { commitment })?)
+    }
+
+    /// Height the expiry countdown of `commitment`, made at
+    /// `commitment_height`, runs from: its latest renewal if it has one.
+    fn expiry_base(
+        &self,
+        txn: &RoTxn,
+        commitment: &Commitment,
+        commitment_height: u32,
+    ) -> Result<u32, Error> {
+        let renewed = self.commitment_to_renewal.get(txn, commitment)?;
+        Ok(renewed.unwrap_or(commitment_height))
     }
 
     pub fn validate_transaction(&self, transaction: &Transaction) -> Result<u64, Error> {
         let rtxn = self.env.read_txn()?;
-        let (spent_utxos, _) = self.get_utxos(&rtxn, &transaction.inputs);
-        let spent_utxos: Vec<Output> = spent_utxos.into_iter().collect::<Option<Vec<_>>>().unwrap();
+        let spent_utxos = self.get_utxos(&rtxn, &transaction.inputs)?;
         // Will this transaction be valid, if included in next block?
         self.validate_transaction_pure(
             &rtxn,
@@ -197,20 +1188,42 @@ impl BitNamesState {
         Ok(validate_transaction(&spent_utxos, transaction)?)
     }
 
+    /// Connect `body` as the next block. A body without transactions is a
+    /// valid block: it advances `best_block_height` and expires commitments
+    /// that aged out, and leaves everything else untouched.
     pub fn connect_body(&mut self, body: &Body) -> Result<(), Error> {
         let mut wtxn = self.env.write_txn()?;
-        println!(
-            "--- connecting body with merkle_root = {} ---",
-            body.compute_merkle_root()
-        );
-        self.validate_body(self.best_block_height + 1, body)?;
-        self.best_block_height += 1;
+        let height = self.best_block_height + 1;
+        let events = self.apply_body(&mut wtxn, height, body)?;
+        self.bump_generation(&mut wtxn)?;
+        wtxn.commit()?;
+        self.best_block_height = height;
+        for event in events {
+            self.events.emit(event);
+        }
+        Ok(())
+    }
+
+    /// Validate `body` and write everything connecting it at `height` does
+    /// to `wtxn`, including its undo record, returning the events to emit
+    /// once it is committed.
+    fn apply_body(&self, wtxn: &mut RwTxn, height: u32, body: &Body) -> Result<Vec<Event>, Error> {
+        let used_before = self.used_bytes(wtxn)?;
+        self.validate_body_in(wtxn, height, body)?;
+        let mut undo = BlockUndo {
+            txids: body.transactions.iter().map(Transaction::txid).collect(),
+            ..Default::default()
+        };
+        let mut events = vec![];
+        let mut registrations = vec![];
 
         for transaction in &body.transactions {
             for input in &transaction.inputs {
-                self.utxos.delete(&mut wtxn, input)?;
+                undo.utxos.push((*input, self.utxos.get(wtxn, input)?));
+                self.utxos.delete(wtxn, input)?;
             }
             let txid = transaction.txid();
+            let mut refunds = vec![];
             for vout in 0..transaction.outputs.len() {
                 let outpoint = OutPoint::Regular {
                     txid,
@@ -219,55 +1232,890 @@ impl BitNamesState {
                 let output = transaction.outputs[vout].clone();
                 match &output.content {
                     Content::Custom(BitNamesOutput::KeyValue { key, value }) => {
-                        self.key_to_value.put(&mut wtxn, key, value)?;
-                    }
-                    Content::Custom(BitNamesOutput::Reveal { key, salt }) => {
-                        let commitment = blake2b_hmac(key, *salt);
-                        self.key_to_commitment.put(&mut wtxn, key, &commitment)?;
-                        self.commitment_to_key.put(&mut wtxn, &commitment, key)?;
-                        self.key_to_value.put(&mut wtxn, key, &None)?;
-                        println!("key {key} was registered successfuly");
-                    }
-                    Content::Custom(BitNamesOutput::Commitment(commitment)) => {
-                        self.commitment_to_height.put(
-                            &mut wtxn,
+                        self.set_value(wtxn, &mut undo, key, value)?;
+                        if value.is_some() {
+                            self.update_timeline(wtxn, &mut undo, key, |timeline| {
+                                timeline.value_set = Some(height);
+                            })?;
+                            if let Some(deposit) = self.key_to_deposit.get(wtxn, key)? {
+                                undo.key_to_deposit.push((*key, Some(deposit)));
+                                self.key_to_deposit.delete(wtxn, key)?;
+                                refunds.push(deposit);
+                            }
+                        }
+                        events.push(Event::ValueUpdated {
+                            key: *key,
+                            value: *value,
+                            height,
+                        });
+                    }
+                    Content::Custom(BitNamesOutput::Delegation { key, delegate }) => {
+                        undo.key_to_delegate
+                            .push((*key, self.key_to_delegate.get(wtxn, key)?));
+                        match delegate {
+                            Some(delegate) => self.key_to_delegate.put(wtxn, key, delegate)?,
+                            None => {
+                                self.key_to_delegate.delete(wtxn, key)?;
+                            }
+                        }
+                    }
+                    Content::Custom(BitNamesOutput::ContextBinding(_)) => continue,
+                    Content::Custom(BitNamesOutput::BurnReserve { key, .. }) => {
+                        undo.burned_keys
+                            .push((*key, self.burned_keys.get(wtxn, key)?));
+                        self.burned_keys.put(wtxn, key, &height)?;
+                        continue;
+                    }
+                    Content::Custom(BitNamesOutput::Bid { key, amount, .. }) => {
+                        let prev = self.key_to_auction.get(wtxn, key)?;
+                        let auction = match prev {
+                            Some(mut auction)
+                                if self.params.auction_phase(&auction, height)
+                                    == AuctionPhase::Bidding =>
+                            {
+                                if *amount > auction.top_bid.1 {
+                                    auction.top_bid = (outpoint, *amount);
+                                }
+                                auction
+                            }
+                            _ => Auction {
+                                opened: height,
+                                top_bid: (outpoint, *amount),
+                            },
+                        };
+                        undo.key_to_auction.push((*key, prev));
+                        self.key_to_auction.put(wtxn, key, &auction)?;
+                    }
+                    Content::Custom(BitNamesOutput::Transfer { key, .. }) => {
+                        self.update_timeline(wtxn, &mut undo, key, |timeline| {
+                            timeline.transferred = Some(height);
+                        })?;
+                        events.push(Event::NameTransferred {
+                            key: *key,
+                            to: output.address,
+                            height,
+                        });
+                    }
+                    Content::Custom(BitNamesOutput::Renewal { key }) => {
+                        let commitment = self
+                            .key_to_commitment
+                            .get(wtxn, key)?
+                            .ok_or(BitNamesError::KeyNotFound { key: *key })?;
+                        undo.commitment_to_renewal.push((
                             commitment,
-                            &self.best_block_height,
-                        )?;
+                            self.commitment_to_renewal.get(wtxn, &commitment)?,
+                        ));
+                        self.commitment_to_renewal.put(wtxn, &commitment, &height)?;
+                    }
+                    Content::Custom(BitNamesOutput::WeightedValues { key, values }) => {
+                        undo.key_to_weighted_values
+                            .push((*key, self.key_to_weighted_values.get(wtxn, key)?));
+                        self.key_to_weighted_values.put(wtxn, key, values)?;
+                        self.update_timeline(wtxn, &mut undo, key, |timeline| {
+                            timeline.value_set = Some(height);
+                        })?;
+                    }
+                    Content::Custom(reveal @ BitNamesOutput::Reveal { key, value, .. }) => {
+                        let commitment = reveal
+                            .revealed_commitment(self.commitment_scheme.as_ref())
+                            .unwrap();
+                        undo.key_to_commitment
+                            .push((*key, self.key_to_commitment.get(wtxn, key)?));
+                        self.key_to_commitment.put(wtxn, key, &commitment)?;
+                        let prev_keys = self.commitment_to_key.get(wtxn, &commitment)?;
+                        let mut keys = prev_keys.clone().unwrap_or_default();
+                        keys.push(*key);
+                        undo.commitment_to_key.push((commitment, prev_keys));
+                        self.commitment_to_key.put(wtxn, &commitment, &keys)?;
+                        self.set_value(wtxn, &mut undo, key, value)?;
+                        // A won bid never entered `commitment_to_height`, so
+                        // it is recorded as committed now and from here on
+                        // ages like any revealed commitment.
+                        if self.commitment_to_height.get(wtxn, &commitment)?.is_none() {
+                            if let Some(auction) = self.key_to_auction.get(wtxn, key)? {
+                                undo.commitment_to_height.push((commitment, None));
+                                self.commitment_to_height.put(wtxn, &commitment, &height)?;
+                                undo.commitment_to_outpoint.push((
+                                    commitment,
+                                    self.commitment_to_outpoint.get(wtxn, &commitment)?,
+                                ));
+                                self.commitment_to_outpoint.put(
+                                    wtxn,
+                                    &commitment,
+                                    &auction.top_bid.0,
+                                )?;
+                            }
+                        }
+                        let committed = self.commitment_to_height.get(wtxn, &commitment)?;
+                        undo.key_to_timeline
+                            .push((*key, self.key_to_timeline.get(wtxn, key)?));
+                        let timeline = NameTimeline {
+                            committed,
+                            revealed: Some(height),
+                            value_set: value.map(|_| height),
+                            ..Default::default()
+                        };
+                        self.key_to_timeline.put(wtxn, key, &timeline)?;
+                        if self.params.registration_deposit > 0 {
+                            let deposit = RegistrationDeposit {
+                                height,
+                                amount: self.params.registration_deposit,
+                                refund_address: output.address,
+                            };
+                            let prev_deposit = self.key_to_deposit.get(wtxn, key)?;
+                            undo.key_to_deposit.push((*key, prev_deposit));
+                            // The first value is set right away, so the
+                            // deposit goes straight back.
+                            if value.is_some() {
+                                self.key_to_deposit.delete(wtxn, key)?;
+                                refunds.push(deposit);
+                            } else {
+                                self.key_to_deposit.put(wtxn, key, &deposit)?;
+                            }
+                        }
+                        registrations.push(*key);
+                        events.push(Event::NameRegistered { key: *key, height });
+                        if value.is_some() {
+                            events.push(Event::ValueUpdated {
+                                key: *key,
+                                value: *value,
+                                height,
+                            });
+                        }
+                    }
+                    Content::Custom(BitNamesOutput::Commitment { commitment, .. }) => {
+                        undo.commitment_to_height.push((
+                            *commitment,
+                            self.commitment_to_height.get(wtxn, commitment)?,
+                        ));
+                        self.commitment_to_height.put(wtxn, commitment, &height)?;
+                        undo.commitment_to_outpoint.push((
+                            *commitment,
+                            self.commitment_to_outpoint.get(wtxn, commitment)?,
+                        ));
                         self.commitment_to_outpoint
-                            .put(&mut wtxn, commitment, &outpoint)?;
+                            .put(wtxn, commitment, &outpoint)?;
+                        events.push(Event::NameCommitted {
+                            commitment: *commitment,
+                            height,
+                        });
                     }
                     _ => {}
                 }
-                self.utxos.put(&mut wtxn, &outpoint, &output)?;
+                undo.utxos
+                    .push((outpoint, self.utxos.get(wtxn, &outpoint)?));
+                self.utxos.put(wtxn, &outpoint, &output)?;
+            }
+            for output in &transaction.outputs {
+                let Content::Custom(bitnames_output) = &output.content else {
+                    continue;
+                };
+                let Some(key) = bitnames_output.key() else {
+                    continue;
+                };
+                let prev_owners = self.ownership_log.get(wtxn, &key)?;
+                let mut owners = prev_owners.clone().unwrap_or_default();
+                match owners.last_mut() {
+                    Some((_, owner)) if *owner == output.address => continue,
+                    // Passed on again within the block it was received in.
+                    Some((owner_height, owner)) if *owner_height == height => {
+                        *owner = output.address;
+                    }
+                    _ => owners.push((height, output.address)),
+                }
+                undo.ownership_log.push((key, prev_owners));
+                self.ownership_log.put(wtxn, &key, &owners)?;
             }
+            // Refunds are paid out as extra outputs after the transaction's
+            // own, so they get outpoints no other output can have.
+            for (index, deposit) in refunds.into_iter().enumerate() {
+                let outpoint = OutPoint::Regular {
+                    txid,
+                    vout: (transaction.outputs.len() + index) as u32,
+                };
+                let output = Output {
+                    address: deposit.refund_address,
+                    content: Content::Value(deposit.amount),
+                };
+                undo.utxos
+                    .push((outpoint, self.utxos.get(wtxn, &outpoint)?));
+                self.utxos.put(wtxn, &outpoint, &output)?;
+            }
+        }
+        let mut forfeited_deposits = vec![];
+        for item in self.key_to_deposit.iter(wtxn)? {
+            let (key, deposit) = item?;
+            if height.saturating_sub(deposit.height) > self.params.deposit_grace_period {
+                forfeited_deposits.push((key, deposit));
+            }
+        }
+        for (key, deposit) in forfeited_deposits {
+            undo.key_to_deposit.push((key, Some(deposit)));
+            self.key_to_deposit.delete(wtxn, &key)?;
         }
         let mut expired_commitments: Vec<Commitment> = vec![];
-        for item in self.commitment_to_height.iter(&wtxn)? {
-            let (commitment, height) = item?;
-            if self.best_block_height - height > COMMITMENT_MAX_AGE {
+        for item in self.commitment_to_height.iter(wtxn)? {
+            let (commitment, commitment_height) = item?;
+            let base = self.expiry_base(wtxn, &commitment, commitment_height)?;
+            if commitment_age(commitment, base, height)? > self.params.commitment_max_age {
                 expired_commitments.push(commitment);
             }
         }
         for commitment in &expired_commitments {
-            if let Some(key) = self.commitment_to_key.get(&wtxn, commitment)? {
-                self.key_to_commitment.delete(&mut wtxn, &key)?;
-                self.commitment_to_key.delete(&mut wtxn, commitment)?;
+            if let Some(keys) = self.commitment_to_key.get(wtxn, commitment)? {
+                for key in &keys {
+                    let key_commitment = self.key_to_commitment.get(wtxn, key)?;
+                    // The key may have been won since by an older commitment.
+                    if key_commitment != Some(*commitment) {
+                        continue;
+                    }
+                    undo.key_to_commitment.push((*key, key_commitment));
+                    self.key_to_commitment.delete(wtxn, key)?;
+                }
+                undo.commitment_to_key.push((*commitment, Some(keys)));
+                self.commitment_to_key.delete(wtxn, commitment)?;
             }
-            let outpoint = self.commitment_to_outpoint.get(&wtxn, commitment)?.ok_or(
+            let outpoint = self.commitment_to_outpoint.get(wtxn, commitment)?.ok_or(
                 BitNamesError::CommitmentNotFound {
                     commitment: *commitment,
                 },
             )?;
-            self.utxos.delete(&mut wtxn, &outpoint)?;
-            self.commitment_to_height.delete(&mut wtxn, commitment)?;
-            self.commitment_to_outpoint.delete(&mut wtxn, commitment)?;
+            undo.utxos
+                .push((outpoint, self.utxos.get(wtxn, &outpoint)?));
+            self.utxos.delete(wtxn, &outpoint)?;
+            undo.commitment_to_height.push((
+                *commitment,
+                self.commitment_to_height.get(wtxn, commitment)?,
+            ));
+            self.commitment_to_height.delete(wtxn, commitment)?;
+            if let Some(renewed) = self.commitment_to_renewal.get(wtxn, commitment)? {
+                undo.commitment_to_renewal
+                    .push((*commitment, Some(renewed)));
+                self.commitment_to_renewal.delete(wtxn, commitment)?;
+            }
+            undo.commitment_to_outpoint
+                .push((*commitment, Some(outpoint)));
+            self.commitment_to_outpoint.delete(wtxn, commitment)?;
+            events.push(Event::CommitmentExpired {
+                commitment: *commitment,
+                height,
+            });
+        }
+        // Only ever written by this block, so disconnecting it just deletes
+        // the entry rather than needing an undo record.
+        if !registrations.is_empty() {
+            self.height_to_registrations
+                .put(wtxn, &BEU32::new(height), &registrations)?;
+        }
+        let parent = self.block_id(wtxn, height - 1)?;
+        self.height_to_block_id
+            .put(wtxn, &BEU32::new(height), &block_id(&parent, &undo.txids))?;
+        self.undo.put(wtxn, &BEU32::new(height), &undo)?;
+        if let Some(pruned) = height.checked_sub(self.params.max_reorg_depth) {
+            self.undo.delete(wtxn, &BEU32::new(pruned))?;
+        }
+        self.meta.put(wtxn, TIP_HEIGHT, &height)?;
+        let growth = self.used_bytes(wtxn)? as i64 - used_before as i64;
+        self.height_to_growth
+            .put(wtxn, &BEU32::new(height), &growth)?;
+        Ok(events)
+    }
+
+    /// What connecting `body` as the next block would change, worked out by
+    /// applying it in a write transaction that is then thrown away.
+    pub fn preview_diff(&self, body: &Body) -> Result<StateDiff, Error> {
+        let mut wtxn = self.env.write_txn()?;
+        let height = self.best_block_height + 1;
+        self.apply_body(&mut wtxn, height, body)?;
+        // The undo record has the prior contents of everything the body
+        // touched, and the transaction the contents after it.
+        let undo = self
+            .undo
+            .get(&wtxn, &BEU32::new(height))?
+            .ok_or(BitNamesError::UndoNotFound { height })?;
+        let mut diff = StateDiff::default();
+        for (outpoint, before) in first_touches(&undo.utxos) {
+            let after = self.utxos.get(&wtxn, outpoint)?;
+            match (before, after) {
+                (None, Some(after)) => diff.utxos_added.push((*outpoint, after)),
+                (Some(before), None) => diff.utxos_removed.push((*outpoint, before.clone())),
+                _ => {}
+            }
+        }
+        for (key, before) in first_touches(&undo.key_to_value) {
+            let after = self.key_to_value.get(&wtxn, key)?;
+            match (before, after) {
+                (None, Some(after)) => diff.names_added.push((*key, after)),
+                (Some(_), None) => diff.names_removed.push(*key),
+                (Some(before), Some(after)) if *before != after => {
+                    diff.values_changed.push((*key, *before, after));
+                }
+                _ => {}
+            }
+        }
+        for (commitment, before) in first_touches(&undo.commitment_to_height) {
+            let after = self.commitment_to_height.get(&wtxn, commitment)?;
+            match (before, after) {
+                (None, Some(_)) => diff.commitments_added.push(*commitment),
+                (Some(_), None) => diff.commitments_removed.push(*commitment),
+                _ => {}
+            }
+        }
+        // Dropping the transaction discards the body's writes.
+        drop(wtxn);
+        Ok(diff)
+    }
+
+    /// Set the value of `key`, keeping `value_to_keys` in step.
+    fn set_value(
+        &self,
+        wtxn: &mut RwTxn,
+        undo: &mut BlockUndo,
+        key: &Key,
+        value: &Option<Value>,
+    ) -> Result<(), Error> {
+        let prev = self.key_to_value.get(wtxn, key)?;
+        undo.key_to_value.push((*key, prev));
+        self.key_to_value.put(wtxn, key, value)?;
+        let prev = prev.flatten();
+        if prev == *value {
+            return Ok(());
+        }
+        if let Some(prev) = prev {
+            let prev_keys = self.value_to_keys.get(wtxn, &prev)?;
+            let mut keys = prev_keys.clone().unwrap_or_default();
+            keys.retain(|other| other != key);
+            undo.value_to_keys.push((prev, prev_keys));
+            if keys.is_empty() {
+                self.value_to_keys.delete(wtxn, &prev)?;
+            } else {
+                self.value_to_keys.put(wtxn, &prev, &keys)?;
+            }
+        }
+        if let Some(value) = value {
+            let prev_keys = self.value_to_keys.get(wtxn, value)?;
+            let mut keys = prev_keys.clone().unwrap_or_default();
+            keys.push(*key);
+            undo.value_to_keys.push((*value, prev_keys));
+            self.value_to_keys.put(wtxn, value, &keys)?;
+        }
+        Ok(())
+    }
+
+    fn update_timeline(
+        &self,
+        wtxn: &mut RwTxn,
+        undo: &mut BlockUndo,
+        key: &Key,
+        update: impl FnOnce(&mut NameTimeline),
+    ) -> Result<(), Error> {
+        let prev = self.key_to_timeline.get(wtxn, key)?;
+        let mut timeline = prev.unwrap_or_default();
+        update(&mut timeline);
+        undo.key_to_timeline.push((*key, prev));
+        self.key_to_timeline.put(wtxn, key, &timeline)?;
+        Ok(())
+    }
+
+    /// Replay a chain from genesis on a fresh state in `env` and return the
+    /// resulting state root. `env` has to be empty, so a replay can never
+    /// overwrite a node's state. Each block's deposits are connected before
+    /// its body, since nothing else can fund the first transactions. Fails
+    /// on the first invalid body with its index in `blocks`.
+    pub fn verify_chain(
+        env: &heed::Env,
+        params: BitNamesParams,
+        blocks: &[(HashMap<OutPoint, Output>, Body)],
+    ) -> Result<Hash, Error> {
+        let mut state = Self::new(env, params)?;
+        if !state.is_empty()? {
+            Err(BitNamesError::ChainEnvNotEmpty)?;
+        }
+        for (index, (deposits, body)) in blocks.iter().enumerate() {
+            state.connect_deposits(deposits)?;
+            state
+                .connect_body(body)
+                .map_err(|err| BitNamesError::InvalidChainBody {
+                    index,
+                    source: Box::new(err),
+                })?;
+        }
+        state.compute_state_root()
+    }
+
+    /// Whether every database is empty, as they are at genesis.
+    fn is_empty(&self) -> Result<bool, Error> {
+        let rtxn = self.env.read_txn()?;
+        for (_, db) in self.raw_databases() {
+            if !db.is_empty(&rtxn)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Connect `bodies` in order in a single write transaction, which is
+    /// much faster than connecting them one by one when syncing. Each body
+    /// is validated against the state left by the ones before it and gets
+    /// its own undo record, expiry sweep and generation bump, so the result
+    /// is the same as connecting them one at a time. Nothing is committed if
+    /// any body is invalid.
+    ///
+    /// The sweep can't be put off to the end of the batch: an expired
+    /// commitment would stay spendable by the rest of it, and the undo
+    /// record of the last body would hold every expiry of the batch.
+    pub fn connect_bodies(&mut self, bodies: &[Body]) -> Result<(), Error> {
+        self.connect_bodies_with_progress(bodies, |_, _| {})
+    }
+
+    /// `connect_bodies`, calling `progress` with the number applied so far
+    /// and the total after each body. They are all in the one write
+    /// transaction, so if any body fails nothing is committed, however long
+    /// the batch.
+    pub fn connect_bodies_with_progress(
+        &mut self,
+        bodies: &[Body],
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(), Error> {
+        let mut wtxn = self.env.write_txn()?;
+        let mut events = vec![];
+        for (index, body) in bodies.iter().enumerate() {
+            let height = self.best_block_height + 1 + index as u32;
+            events.extend(self.apply_body(&mut wtxn, height, body)?);
+            self.bump_generation(&mut wtxn)?;
+            progress(index + 1, bodies.len());
+        }
+        wtxn.commit()?;
+        self.best_block_height += bodies.len() as u32;
+        for event in events {
+            self.events.emit(event);
+        }
+        Ok(())
+    }
+
+    /// Undo the tip block using its persisted undo record, which only exists
+    /// for the last `max_reorg_depth` blocks. Works across restarts, the log
+    /// and the tip height are both written in the same transaction as the
+    /// block.
+    pub fn disconnect_body(&mut self, body: &Body) -> Result<(), Error> {
+        let height = self.best_block_height;
+        let mut wtxn = self.env.write_txn()?;
+        let undo = self
+            .undo
+            .get(&wtxn, &BEU32::new(height))?
+            .ok_or(BitNamesError::UndoNotFound { height })?;
+        let txids: Vec<_> = body.transactions.iter().map(Transaction::txid).collect();
+        if txids != undo.txids {
+            Err(BitNamesError::NotTipBody { height })?;
         }
+        restore(&mut wtxn, &self.key_to_value, &undo.key_to_value)?;
+        restore(
+            &mut wtxn,
+            &self.key_to_weighted_values,
+            &undo.key_to_weighted_values,
+        )?;
+        restore(
+            &mut wtxn,
+            &self.commitment_to_height,
+            &undo.commitment_to_height,
+        )?;
+        restore(
+            &mut wtxn,
+            &self.commitment_to_renewal,
+            &undo.commitment_to_renewal,
+        )?;
+        restore(
+            &mut wtxn,
+            &self.commitment_to_outpoint,
+            &undo.commitment_to_outpoint,
+        )?;
+        restore(&mut wtxn, &self.key_to_commitment, &undo.key_to_commitment)?;
+        restore(&mut wtxn, &self.commitment_to_key, &undo.commitment_to_key)?;
+        restore(&mut wtxn, &self.key_to_deposit, &undo.key_to_deposit)?;
+        restore(&mut wtxn, &self.ownership_log, &undo.ownership_log)?;
+        restore(&mut wtxn, &self.key_to_delegate, &undo.key_to_delegate)?;
+        restore(&mut wtxn, &self.key_to_timeline, &undo.key_to_timeline)?;
+        restore(&mut wtxn, &self.value_to_keys, &undo.value_to_keys)?;
+        restore(&mut wtxn, &self.burned_keys, &undo.burned_keys)?;
+        restore(&mut wtxn, &self.key_to_auction, &undo.key_to_auction)?;
+        restore(&mut wtxn, &self.utxos, &undo.utxos)?;
+        self.height_to_registrations
+            .delete(&mut wtxn, &BEU32::new(height))?;
+        self.height_to_growth
+            .delete(&mut wtxn, &BEU32::new(height))?;
+        self.height_to_block_id
+            .delete(&mut wtxn, &BEU32::new(height))?;
+        self.undo.delete(&mut wtxn, &BEU32::new(height))?;
+        self.meta.put(&mut wtxn, TIP_HEIGHT, &(height - 1))?;
+        self.bump_generation(&mut wtxn)?;
         wtxn.commit()?;
+        self.best_block_height = height - 1;
+        Ok(())
+    }
+
+    /// Cross-check the databases against each other and against
+    /// `best_block_height`, failing on the first inconsistency found.
+    pub fn check_invariants(&self) -> Result<(), Error> {
+        let rtxn = self.env.read_txn()?;
+        let violation = |reason: String| BitNamesError::InvariantViolation { reason };
+        let tip = self.meta.get(&rtxn, TIP_HEIGHT)?.unwrap_or(0);
+        if tip != self.best_block_height {
+            Err(violation(format!(
+                "stored tip height {tip} differs from best block height {}",
+                self.best_block_height
+            )))?;
+        }
+        let block_ids = self.height_to_block_id.len(&rtxn)?;
+        if block_ids != self.best_block_height as u64 {
+            Err(violation(format!(
+                "{block_ids} block ids recorded for best block height {}",
+                self.best_block_height
+            )))?;
+        }
+        for item in self.commitment_to_height.iter(&rtxn)? {
+            let (commitment, height) = item?;
+            if height > self.best_block_height {
+                Err(violation(format!(
+                    "commitment {commitment} is at height {height} above best block height {}",
+                    self.best_block_height
+                )))?;
+            }
+            let base = self.expiry_base(&rtxn, &commitment, height)?;
+            if self.best_block_height - base > self.params.commitment_max_age {
+                Err(violation(format!(
+                    "commitment {commitment} counting from height {base} should have expired"
+                )))?;
+            }
+            if self
+                .commitment_to_outpoint
+                .get(&rtxn, &commitment)?
+                .is_none()
+            {
+                Err(violation(format!(
+                    "commitment {commitment} has no outpoint"
+                )))?;
+            }
+        }
+        for item in self.commitment_to_outpoint.iter(&rtxn)? {
+            let (commitment, _) = item?;
+            if self.commitment_to_height.get(&rtxn, &commitment)?.is_none() {
+                Err(violation(format!("commitment {commitment} has no height")))?;
+            }
+        }
+        for item in self.commitment_to_renewal.iter(&rtxn)? {
+            let (commitment, _) = item?;
+            if self.commitment_to_height.get(&rtxn, &commitment)?.is_none() {
+                Err(violation(format!(
+                    "renewed commitment {commitment} has no height"
+                )))?;
+            }
+        }
+        for item in self.key_to_commitment.iter(&rtxn)? {
+            let (key, commitment) = item?;
+            let keys = self.commitment_to_key.get(&rtxn, &commitment)?;
+            if !keys.unwrap_or_default().contains(&key) {
+                Err(violation(format!(
+                    "commitment {commitment} doesn't map back to key {key}"
+                )))?;
+            }
+            if self.key_to_value.get(&rtxn, &key)?.is_none() {
+                Err(violation(format!(
+                    "key {key} has a commitment but isn't registered"
+                )))?;
+            }
+        }
+        for item in self.utxos.iter(&rtxn)? {
+            let (outpoint, output) = item?;
+            let Some(key) = (match &output.content {
+                Content::Custom(output) => output.key(),
+                _ => None,
+            }) else {
+                continue;
+            };
+            if self.key_to_value.get(&rtxn, &key)?.is_none() {
+                Err(violation(format!(
+                    "utxo {outpoint:?} carries key {key} which isn't registered"
+                )))?;
+            }
+        }
+        // Disconnecting doesn't bring back pruned records, so the retained
+        // range can be shorter than `max_reorg_depth` or even empty, but when
+        // there is one it ends at the tip and has no gaps.
+        let mut expected = self.best_block_height;
+        for item in self.undo.rev_iter(&rtxn)? {
+            let (height, _) = item?;
+            let height = height.get();
+            if height != expected {
+                Err(violation(format!(
+                    "undo record at height {height}, expected one at height {expected}"
+                )))?;
+            }
+            if self.best_block_height - height >= self.params.max_reorg_depth {
+                Err(violation(format!(
+                    "undo record at height {height} should have been pruned"
+                )))?;
+            }
+            expected = expected.saturating_sub(1);
+        }
         Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub names: u64,
+    pub commitments: u64,
+    pub utxos: u64,
+}
+
+/// Changes a body makes to the state, see `BitNamesState::preview_diff`.
+#[derive(Debug, Clone, Default)]
+pub struct StateDiff {
+    pub utxos_added: Vec<(OutPoint, Output)>,
+    /// Spent utxos and expired commitments, with their contents.
+    pub utxos_removed: Vec<(OutPoint, Output)>,
+    /// Newly registered names, with their value.
+    pub names_added: Vec<(Key, Option<Value>)>,
+    pub names_removed: Vec<Key>,
+    /// Names whose value changed, from the old value to the new one.
+    pub values_changed: Vec<(Key, Option<Value>, Option<Value>)>,
+    pub commitments_added: Vec<Commitment>,
+    /// Commitments that expired.
+    pub commitments_removed: Vec<Commitment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SquatFinding {
+    pub address: Address,
+    /// Names held by `address` that have no value.
+    pub names: Vec<Key>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AddressProof {
+    pub address: Address,
+    pub root: Hash,
+    pub utxos: Vec<(OutPoint, Output, Vec<MerkleStep>)>,
+}
+
+impl AddressProof {
+    /// Check that every utxo in the proof is paid to `address` and included
+    /// in `root`. This proves inclusion only, an address can always leave
+    /// utxos out. Paths deeper than any real tree are rejected before
+    /// anything is hashed.
+    pub fn verify(&self, root: &Hash) -> Result<bool, ProofError> {
+        for (_, _, path) in &self.utxos {
+            check_proof_depth(path)?;
+        }
+        Ok(self.root == *root
+            && self.utxos.iter().all(|(outpoint, output, path)| {
+                output.address == self.address
+                    && merkle_root_from_path(utxo_leaf(outpoint, output), path) == *root
+            }))
+    }
+}
+
+fn utxo_leaf(outpoint: &OutPoint, output: &Output) -> Hash {
+    hash(&(outpoint, output))
+}
+
+/// Value of a key, `None` for names without one, and its path to
+/// `BitNamesState::name_root`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyProof {
+    pub value: Option<Value>,
+    pub path: Vec<MerkleStep>,
+}
+
+/// Whether `proof` shows `key` registered with `proof.value` under `root`.
+/// Paths deeper than any real tree don't verify.
+pub fn verify_key_proof(root: &Hash, key: &Key, proof: &KeyProof) -> bool {
+    check_proof_depth(&proof.path).is_ok()
+        && merkle_root_from_path(name_leaf(key, &proof.value), &proof.path) == *root
+}
+
+fn name_leaf(key: &Key, value: &Option<Value>) -> Hash {
+    hash(&(key, value))
+}
+
+/// Query-only view of a `BitNamesState`, with no way to connect or
+/// disconnect bodies.
+pub struct ReadOnlyState {
+    state: BitNamesState,
+}
+
+impl ReadOnlyState {
+    pub fn get_value(&self, key: &Key) -> Result<Option<Option<Value>>, Error> {
+        self.state.get_value(key)
+    }
+
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Result<Option<Output>, Error> {
+        self.state.get_utxo(outpoint)
+    }
+
+    pub fn get_commitment_outpoint(
+        &self,
+        commitment: &Commitment,
+    ) -> Result<Option<OutPoint>, Error> {
+        self.state.get_commitment_outpoint(commitment)
+    }
+
+    pub fn iter_names(&self) -> Result<Vec<(Key, Option<Value>)>, Error> {
+        self.state.iter_names()
+    }
+
+    pub fn stats(&self) -> Result<Stats, Error> {
+        self.state.stats()
+    }
+}
+
+/// The first undo entry of every key, which holds its contents from before
+/// the block, in the order the keys were first touched.
+fn first_touches<K: Eq + std::hash::Hash, V>(entries: &[(K, Option<V>)]) -> Vec<&(K, Option<V>)> {
+    let mut seen = HashSet::new();
+    entries.iter().filter(|(key, _)| seen.insert(key)).collect()
+}
+
+/// Put back the prior entries recorded in an undo list, latest first, so
+/// that every key ends up with the value it had before the block.
+fn restore<'a, KC, DC>(
+    txn: &mut RwTxn,
+    db: &Database<KC, DC>,
+    entries: &'a [(KC::EItem, Option<DC::EItem>)],
+) -> Result<(), Error>
+where
+    KC: BytesEncode<'a>,
+    DC: BytesEncode<'a>,
+    KC::EItem: Sized,
+    DC::EItem: Sized,
+{
+    for (key, value) in entries.iter().rev() {
+        match value {
+            Some(value) => db.put(txn, key, value)?,
+            None => {
+                db.delete(txn, key)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Cheap pre-check for relays that don't hold the utxo set: verifies
+/// signatures and the shape of BitNames outputs, but nothing that depends on
+/// state such as commitment ages or spent keys.
+pub fn verify_transaction_stateless(transaction: &AuthorizedTransaction) -> Result<(), Error> {
+    let inputs = transaction.transaction.inputs.len();
+    let authorizations = transaction.authorizations.len();
+    if inputs != authorizations {
+        Err(BitNamesError::AuthorizationCountMismatch {
+            inputs,
+            authorizations,
+        })?;
+    }
+    for output in &transaction.transaction.outputs {
+        match &output.content {
+            Content::Custom(BitNamesOutput::Commitment { commitment, .. }) => {
+                if Hash::from(*commitment) == Hash::default() {
+                    Err(BitNamesError::ZeroCommitment)?;
+                }
+            }
+            Content::Custom(BitNamesOutput::Reveal { key, name, .. }) => {
+                check_inline_name(key, name.as_deref())?;
+            }
+            _ => {}
+        }
+    }
+    verify_authorizations(&Body::new(vec![transaction.clone()], vec![]))?;
+    Ok(())
+}
+
+/// Blocks between a commitment and `height`. A commitment above `height`
+/// means the state is inconsistent with the body being applied, e.g. a
+/// replayed body, and is an error rather than an underflow.
+fn commitment_age(
+    commitment: Commitment,
+    commitment_height: u32,
+    height: u32,
+) -> Result<u32, BitNamesError> {
+    height
+        .checked_sub(commitment_height)
+        .ok_or(BitNamesError::CommitmentFromFuture {
+            commitment,
+            commitment_height,
+            height,
+        })
+}
+
+/// Id of a block with transactions `txids` on top of the block with id
+/// `parent`. Chaining in the parent makes it identify the whole branch, not
+/// just the block's own contents.
+pub fn block_id(parent: &Hash, txids: &[sdk_types::Txid]) -> Hash {
+    hash(&("bitnames-block", parent, txids))
+}
+
+/// Context a transaction included at `height`, on top of the block with id
+/// `parent`, can bind itself to, so that it can't be moved into a block at
+/// another height or onto another branch.
+pub fn block_context(height: u32, parent: &Hash) -> Hash {
+    hash(&("bitnames-context", height, parent))
+}
+
+/// Checks that only need the body itself: one authorization per input, and
+/// no transaction included, outpoint spent or commitment made twice.
+fn check_body_structure(body: &Body) -> Result<(), BitNamesError> {
+    // Caught by the input check too, unless the transaction has no inputs,
+    // but reported on its own so the cause is clear.
+    let mut txids = HashSet::new();
+    for transaction in &body.transactions {
+        let txid = transaction.txid();
+        if !txids.insert(txid) {
+            return Err(BitNamesError::DuplicateTransaction { txid });
+        }
+    }
+    let inputs = body.inputs();
+    if inputs.len() != body.authorizations.len() {
+        return Err(BitNamesError::AuthorizationCountMismatch {
+            inputs: inputs.len(),
+            authorizations: body.authorizations.len(),
+        });
+    }
+    let mut spent = HashSet::new();
+    for input in inputs {
+        if !spent.insert(input) {
+            return Err(BitNamesError::DuplicateInput { outpoint: input });
+        }
+    }
+    let mut commitments = HashSet::new();
+    for transaction in &body.transactions {
+        for output in &transaction.outputs {
+            if let Content::Custom(BitNamesOutput::Commitment { commitment, .. }) = &output.content
+            {
+                if !commitments.insert(*commitment) {
+                    return Err(BitNamesError::DuplicateCommitment {
+                        commitment: *commitment,
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse an inline name, checking that it is the name behind `key`.
+fn check_inline_name(key: &Key, name: Option<&str>) -> Result<Option<Name>, BitNamesError> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    let parsed = Name::parse(name)?;
+    if parsed.key() != *key {
+        return Err(BitNamesError::InlineNameMismatch {
+            name: name.into(),
+            key: *key,
+        });
+    }
+    Ok(Some(parsed))
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("authorization error")]
@@ -278,9 +2126,12 @@ pub enum Error {
     BitNames(#[from] BitNamesError),
     #[error("heed error")]
     Heed(#[from] heed::Error),
+    #[error("config error")]
+    Config(#[from] ConfigError),
+    #[error("codec error")]
+    Codec(#[from] bincode::Error),
 }
 
-const COMMITMENT_MAX_AGE: u32 = 1;
 #[derive(Debug, thiserror::Error)]
 pub enum BitNamesError {
     #[error("invalid name commitment")]
@@ -304,6 +2155,2426 @@ pub enum BitNamesError {
         commitment: Commitment,
         late_by: u32,
     },
-    #[error("invalid key {key}")]
-    InvalidKey { key: Key },
+    #[error("commitment {commitment} is early by {early_by}")]
+    RevealTooEarly {
+        commitment: Commitment,
+        early_by: u32,
+    },
+    #[error("weight {weight} for key {key} isn't in 1..={max}")]
+    InvalidWeight { key: Key, weight: u16, max: u16 },
+    #[error("key {key} is revealed against commitment {commitment} more than once")]
+    DuplicateReveal { key: Key, commitment: Commitment },
+    #[error(
+        "reveals require deposits and commitment fees of {required} but only {provided} was left over"
+    )]
+    InsufficientDeposit { required: u64, provided: u64 },
+    #[error("coinbase of {coinbase} exceeds fees of {fee} net of deposits")]
+    CoinbaseClaimsDeposits { coinbase: u64, fee: u64 },
+    #[error("transaction {txid:?} is included more than once")]
+    DuplicateTransaction { txid: sdk_types::Txid },
+    #[error(
+        "transaction is bound to context {} but included in {}",
+        hex::encode(found),
+        hex::encode(expected)
+    )]
+    ContextMismatch { expected: Hash, found: Hash },
+    #[error("{name} can only be registered by the delegate of {parent}")]
+    NotDelegate { name: String, parent: String },
+    #[error("output {vout} is out of canonical order")]
+    NonCanonicalOutputOrder { vout: u32 },
+    #[error("commitment {commitment} at height {commitment_height} is above height {height}")]
+    CommitmentFromFuture {
+        commitment: Commitment,
+        commitment_height: u32,
+        height: u32,
+    },
+    #[error("outpoint {outpoint:?} is spent more than once")]
+    DuplicateInput { outpoint: OutPoint },
+    #[error("commitment {commitment} is made more than once")]
+    DuplicateCommitment { commitment: Commitment },
+    #[error("utxo {outpoint:?} not found")]
+    UtxoNotFound { outpoint: OutPoint },
+    #[error("key {key} belongs to a reserved name")]
+    NameReserved { key: Key },
+    #[error("invalid proof")]
+    Proof(#[from] ProofError),
+    #[error("snapshot has format version {found}, expected {expected}")]
+    SnapshotVersionMismatch { expected: u8, found: u8 },
+    #[error("snapshot has database {found} where {expected} was expected")]
+    SnapshotDatabaseMismatch { expected: String, found: String },
+    #[error("control proof for key {key} has an invalid signature")]
+    InvalidControlProof { key: Key },
+    #[error("control proof for key {key} isn't signed by its owner")]
+    NotController { key: Key },
+    #[error("key {key} is set by more than one output of the transaction")]
+    DuplicateKeyInTransaction { key: Key },
+    #[error("commitment {commitment} locks fee {fee} below the minimum of {min}")]
+    CommitmentFeeTooLow {
+        commitment: Commitment,
+        fee: u64,
+        min: u64,
+    },
+    #[error("burn reserving key {key} burns {amount}, below the minimum of {min}")]
+    BurnTooLow { key: Key, amount: u64, min: u64 },
+    #[error("key {key} is registered and can't be burn reserved")]
+    BurnOfRegisteredKey { key: Key },
+    #[error("commitment {commitment} was already revealed to {recorded:?}, not key {key}")]
+    CommitmentKeyMismatch {
+        commitment: Commitment,
+        key: Key,
+        recorded: Vec<Key>,
+    },
+    #[error("bid for key {key} but auctions are disabled")]
+    AuctionsDisabled { key: Key },
+    #[error("key {key} is registered and can't be bid for")]
+    BidForRegisteredKey { key: Key },
+    #[error("auction of key {key} no longer takes bids")]
+    BidWindowClosed { key: Key },
+    #[error("bid {outpoint:?} can't be spent while its auction takes bids")]
+    BidLocked { outpoint: OutPoint },
+    #[error("key {key} is revealed outside of its auction's reveal window")]
+    RevealOutsideAuctionWindow { key: Key },
+    #[error("reveal of key {key} doesn't open the top bid of its auction")]
+    NotTopBid { key: Key },
+    #[error("key {key} is under auction and can only be registered by its top bid")]
+    AuctionInProgress { key: Key },
+    #[error("body {index} of the chain is invalid")]
+    InvalidChainBody { index: usize, source: Box<Error> },
+    #[error("chains can only be replayed into an empty environment")]
+    ChainEnvNotEmpty,
+    #[error("no block id recorded for height {height}")]
+    BlockIdNotFound { height: u32 },
+    #[error("transaction transfers names but drops key {key}")]
+    TransferDropsKey { key: Key },
+    #[error("key {key} is transferred to the address already holding it")]
+    SelfTransfer { key: Key },
+    #[error("transfer of key {key} carries value {value:?} but its value is {current:?}")]
+    TransferValueMismatch {
+        key: Key,
+        current: Option<Value>,
+        value: Option<Value>,
+    },
+    #[error("invalid key {key}")]
+    InvalidKey { key: Key },
+    #[error("invalid name")]
+    InvalidName(#[from] NameError),
+    #[error("inline name {name} doesn't hash to key {key}")]
+    InlineNameMismatch { name: String, key: Key },
+    #[error("reveal for key {key} must carry its name inline")]
+    MissingInlineName { key: Key },
+    #[error("name {name} has reserved suffix {suffix}")]
+    ReservedSuffix { name: String, suffix: String },
+    #[error("transaction has {inputs} inputs but {authorizations} authorizations")]
+    AuthorizationCountMismatch {
+        inputs: usize,
+        authorizations: usize,
+    },
+    #[error("commitment is all zeroes")]
+    ZeroCommitment,
+    #[error("no undo record for block {height}")]
+    UndoNotFound { height: u32 },
+    #[error("body doesn't match block {height} at the tip")]
+    NotTipBody { height: u32 },
+    #[error("invariant violated: {reason}")]
+    InvariantViolation { reason: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use std::time::Instant;
+
+    fn params(commitment_max_age: u32) -> BitNamesParams {
+        BitNamesParams {
+            commitment_max_age,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn disconnects_from_persisted_undo_log_after_restart() {
+        let params = BitNamesParams {
+            max_reorg_depth: 2,
+            ..Default::default()
+        };
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, params.clone());
+        let owner = chain.addresses[0];
+        let key = key_of("restart.com");
+        let commitment = chain.commit(owner, "restart.com", 1).unwrap();
+        let reveal = chain.reveal_tx(commitment, owner, "restart.com", 1);
+        let reveal = chain.connect(vec![reveal]).unwrap();
+        let empty = chain.connect(vec![]).unwrap();
+
+        chain.state = BitNamesState::new(&env, params.clone()).unwrap();
+        assert_eq!(chain.height(), 3);
+        chain.state.check_invariants().unwrap();
+        chain.state.disconnect_body(&empty).unwrap();
+        chain.state.disconnect_body(&reveal).unwrap();
+        assert_eq!(chain.state.get_value(&key).unwrap(), None);
+
+        chain.state = BitNamesState::new(&env, params).unwrap();
+        assert_eq!(chain.height(), 1);
+        chain.state.check_invariants().unwrap();
+        // Block 1's record was pruned when block 3 was connected.
+        let commit_body = Body::new(vec![], vec![]);
+        assert!(matches!(
+            chain.state.disconnect_body(&commit_body),
+            Err(Error::BitNames(BitNamesError::UndoNotFound { height: 1 }))
+        ));
+    }
+
+    #[test]
+    fn verify_chain_replays_to_incremental_root() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let key = key_of("replay.com");
+        let mut blocks = vec![];
+        let commit = chain.commit_tx(owner, "replay.com", 1);
+        let funding = commit.transaction.inputs[0];
+        let deposits = HashMap::from([(funding, chain.state.get_utxo(&funding).unwrap().unwrap())]);
+        let commitment = OutPoint::Regular {
+            txid: commit.transaction.txid(),
+            vout: 0,
+        };
+        blocks.push((deposits, chain.connect(vec![commit]).unwrap()));
+        let reveal = chain.reveal_tx(commitment, owner, "replay.com", 1);
+        let name = OutPoint::Regular {
+            txid: reveal.transaction.txid(),
+            vout: 0,
+        };
+        blocks.push((HashMap::new(), chain.connect(vec![reveal]).unwrap()));
+        let value = Some(hash(&"replayed").into());
+        let set_value = chain.spend_tx(name, owner, BitNamesOutput::KeyValue { key, value });
+        blocks.push((HashMap::new(), chain.connect(vec![set_value]).unwrap()));
+        let root = chain.state.compute_state_root().unwrap();
+
+        let (env, _dir) = temp_env();
+        let replayed = BitNamesState::verify_chain(&env, BitNamesParams::default(), &blocks);
+        assert_eq!(replayed.unwrap(), root);
+
+        // Replaying into the live environment would wipe it.
+        let env = chain.state.env.clone();
+        assert!(matches!(
+            BitNamesState::verify_chain(&env, BitNamesParams::default(), &blocks),
+            Err(Error::BitNames(BitNamesError::ChainEnvNotEmpty))
+        ));
+        assert_eq!(chain.state.compute_state_root().unwrap(), root);
+        assert_eq!(chain.state.get_value(&key).unwrap(), Some(value));
+
+        // The first invalid body is reported with its index.
+        let (env, _dir) = temp_env();
+        blocks.swap(1, 2);
+        assert!(matches!(
+            BitNamesState::verify_chain(&env, BitNamesParams::default(), &blocks),
+            Err(Error::BitNames(BitNamesError::InvalidChainBody {
+                index: 1,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn transfers_name_without_value() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let key = key_of("novalue.com");
+        let name = chain.register(alice, "novalue.com", 1).unwrap();
+        let value = Some(hash(&"claimed").into());
+        let mismatched = chain.spend_tx(name, bob, BitNamesOutput::Transfer { key, value });
+        assert!(matches!(
+            chain.state.validate_transaction(&mismatched.transaction),
+            Err(Error::BitNames(BitNamesError::TransferValueMismatch {
+                current: None,
+                ..
+            }))
+        ));
+        let transfer = chain.spend_tx(name, bob, BitNamesOutput::Transfer { key, value: None });
+        chain.connect(vec![transfer]).unwrap();
+        assert_eq!(chain.state.owner_of(&key).unwrap(), Some(bob));
+        assert_eq!(chain.state.get_value(&key).unwrap(), Some(None));
+    }
+
+    #[test]
+    fn renewal_just_before_expiry_restarts_countdown() {
+        let mut chain = TestChain::new(params(2));
+        let owner = chain.addresses[0];
+        let key = key_of("renew.com");
+        let commitment = blake2b_hmac(&key, 1);
+        // Committed at 1, revealed at 2 and due to be swept at 4.
+        let name = chain.register(owner, "renew.com", 1).unwrap();
+        chain.advance(1);
+        let renewal = chain.spend_tx(name, owner, BitNamesOutput::Renewal { key });
+        let body = chain.connect(vec![renewal]).unwrap();
+        assert_eq!(chain.height(), 3);
+
+        let rtxn = chain.state.env.read_txn().unwrap();
+        let committed = chain.state.commitment_to_height.get(&rtxn, &commitment);
+        assert_eq!(committed.unwrap(), Some(1));
+        let renewed = chain.state.commitment_to_renewal.get(&rtxn, &commitment);
+        assert_eq!(renewed.unwrap(), Some(3));
+        drop(rtxn);
+
+        // Disconnecting the renewal puts the old countdown back.
+        chain.state.disconnect_body(&body).unwrap();
+        assert!(chain.state.expiring_within(1).unwrap().is_empty());
+        assert_eq!(
+            chain.state.expiring_within(2).unwrap(),
+            vec![(commitment, 2)]
+        );
+        chain.state.connect_body(&body).unwrap();
+
+        chain.advance(2);
+        assert_eq!(
+            chain.state.expiring_within(1).unwrap(),
+            vec![(commitment, 1)]
+        );
+        assert!(chain
+            .state
+            .commitment_outpoint_for_key(&key)
+            .unwrap()
+            .is_some());
+        chain.advance(1);
+        assert_eq!(chain.height(), 6);
+        assert!(chain
+            .state
+            .commitment_outpoint_for_key(&key)
+            .unwrap()
+            .is_none());
+        let rtxn = chain.state.env.read_txn().unwrap();
+        let renewed = chain.state.commitment_to_renewal.get(&rtxn, &commitment);
+        assert_eq!(renewed.unwrap(), None);
+        drop(rtxn);
+        chain.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn renewal_of_unregistered_key_is_rejected() {
+        let mut chain = TestChain::new(params(2));
+        let owner = chain.addresses[0];
+        let funding = chain.deposit(owner, 1);
+        let key = key_of("unregistered.com");
+        let renewal = chain.spend_tx(funding, owner, BitNamesOutput::Renewal { key });
+        assert!(matches!(
+            chain.state.validate_transaction(&renewal.transaction),
+            Err(Error::BitNames(BitNamesError::InvalidKey { key: invalid })) if invalid == key
+        ));
+        assert!(chain.connect(vec![renewal]).is_err());
+        assert_eq!(chain.height(), 0);
+    }
+
+    #[test]
+    fn renewal_doesnt_win_contested_name() {
+        let mut chain = TestChain::new(params(10));
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let key = key_of("contested.com");
+        let alice_commitment = chain.commit(alice, "contested.com", 1).unwrap();
+        let bob_commitment = chain.commit(bob, "contested.com", 2).unwrap();
+        let name = chain
+            .reveal(alice_commitment, alice, "contested.com", 1)
+            .unwrap();
+        let renewal = chain.spend_tx(name, alice, BitNamesOutput::Renewal { key });
+        chain.connect(vec![renewal]).unwrap();
+
+        // Alice's commitment still dates from height 1, before Bob's.
+        let reveal = chain.reveal_tx(bob_commitment, bob, "contested.com", 2);
+        assert!(matches!(
+            chain.state.validate_transaction(&reveal.transaction),
+            Err(Error::BitNames(BitNamesError::KeyAlreadyRegistered {
+                prev_commitment_height: 1,
+                commitment_height: 2,
+                ..
+            }))
+        ));
+        assert!(chain.connect(vec![reveal]).is_err());
+        assert_eq!(chain.state.owner_of(&key).unwrap(), Some(alice));
+    }
+
+    #[test]
+    fn events_are_emitted_only_once_committed() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let events = chain.state.subscribe();
+        let commit = chain.commit_tx(owner, "events.com", 1);
+        let commitment = blake2b_hmac(&key_of("events.com"), 1);
+        let body = Body::new(vec![commit.clone()], vec![]);
+
+        chain.state.preview_diff(&body).unwrap();
+        assert!(events.try_recv().is_err());
+        // Connecting the commitment twice fails on the duplicate.
+        assert!(chain.connect(vec![commit.clone(), commit.clone()]).is_err());
+        assert!(events.try_recv().is_err());
+
+        chain.connect(vec![commit]).unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::NameCommitted {
+                commitment,
+                height: 1
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn db_stats_cover_every_state_database() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        chain.register(chain.addresses[0], "stats.com", 1).unwrap();
+        let stats = chain.state.db_stats().unwrap();
+        assert_eq!(stats.len(), chain.state.raw_databases().len());
+        assert_eq!(stats["key_to_value"].entries, 1);
+        assert_eq!(stats["undo"].entries, 2);
+    }
+
+    #[test]
+    fn loser_of_contested_name_reclaims_commitment() {
+        let mut chain = TestChain::new(params(10));
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let key = key_of("reclaim.com");
+        let alice_commitment = chain.commit(alice, "reclaim.com", 1).unwrap();
+        let bob_commitment = chain.commit(bob, "reclaim.com", 2).unwrap();
+        chain
+            .reveal(alice_commitment, alice, "reclaim.com", 1)
+            .unwrap();
+        let reveal = chain.reveal_tx(bob_commitment, bob, "reclaim.com", 2);
+        assert!(matches!(
+            chain.state.validate_transaction(&reveal.transaction),
+            Err(Error::BitNames(BitNamesError::KeyAlreadyRegistered { .. }))
+        ));
+
+        // Bob can't win the name any more, but gets his commitment back.
+        let reclaim = chain.sign(
+            vec![bob_commitment],
+            vec![Output {
+                address: bob,
+                content: Content::Value(0),
+            }],
+        );
+        let reclaim_txid = reclaim.transaction.txid();
+        chain.connect(vec![reclaim]).unwrap();
+        assert!(chain.state.get_utxo(&bob_commitment).unwrap().is_none());
+        let reclaimed = OutPoint::Regular {
+            txid: reclaim_txid,
+            vout: 0,
+        };
+        assert!(chain.state.get_utxo(&reclaimed).unwrap().is_some());
+        assert_eq!(chain.state.owner_of(&key).unwrap(), Some(alice));
+    }
+
+    #[test]
+    fn timeline_counts_only_transfer_outputs_as_transfers() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob, carol) = (chain.addresses[0], chain.addresses[1], chain.addresses[2]);
+        let key = key_of("timeline.com");
+        let name = chain.register(alice, "timeline.com", 1).unwrap();
+        let events = chain.state.subscribe();
+
+        // Paying a value update to someone else moves the name, but isn't a
+        // transfer, and emits no `NameTransferred` either.
+        let value = Some(hash(&"moved").into());
+        let update = chain.spend_tx(name, bob, BitNamesOutput::KeyValue { key, value });
+        let name = OutPoint::Regular {
+            txid: update.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![update]).unwrap();
+        assert_eq!(chain.state.owner_of(&key).unwrap(), Some(bob));
+        let timeline = chain.state.name_timeline(&key).unwrap().unwrap();
+        assert_eq!(timeline.value_set, Some(3));
+        assert_eq!(timeline.transferred, None);
+        assert!(events
+            .try_iter()
+            .all(|event| !matches!(event, Event::NameTransferred { .. })));
+
+        let transfer = chain.spend_tx(name, carol, BitNamesOutput::Transfer { key, value });
+        chain.connect(vec![transfer]).unwrap();
+        let timeline = chain.state.name_timeline(&key).unwrap().unwrap();
+        assert_eq!(timeline.transferred, Some(4));
+        assert!(events.try_iter().any(|event| event
+            == Event::NameTransferred {
+                key,
+                to: carol,
+                height: 4
+            }));
+    }
+
+    #[test]
+    fn rejects_key_carried_by_two_outputs() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let key = key_of("twice.com");
+        let name = chain.register(owner, "twice.com", 1).unwrap();
+        let value = Some(hash(&"value").into());
+        let pairs = [
+            (
+                BitNamesOutput::KeyValue { key, value },
+                BitNamesOutput::WeightedValues {
+                    key,
+                    values: vec![(hash(&"weighted").into(), 1)],
+                },
+            ),
+            (
+                BitNamesOutput::Renewal { key },
+                BitNamesOutput::KeyValue { key, value },
+            ),
+            (
+                BitNamesOutput::KeyValue { key, value },
+                BitNamesOutput::KeyValue { key, value: None },
+            ),
+        ];
+        for (first, second) in pairs {
+            let transaction = chain.sign(
+                vec![name],
+                vec![custom(owner, first), custom(owner, second)],
+            );
+            assert!(matches!(
+                chain.state.validate_transaction(&transaction.transaction),
+                Err(Error::BitNames(BitNamesError::DuplicateKeyInTransaction { key: k })) if k == key
+            ));
+        }
+    }
+
+    #[test]
+    fn context_binding_is_tied_to_the_branch() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let bind = |chain: &mut TestChain, context| {
+            let funding = chain.deposit(owner, 1);
+            chain.sign(
+                vec![funding],
+                vec![custom(owner, BitNamesOutput::ContextBinding(context))],
+            )
+        };
+
+        // Two different blocks at height 1 give the block after them
+        // different contexts.
+        let commit_a = chain.commit_tx(owner, "a.com", 1);
+        let block_a = chain.connect(vec![commit_a]).unwrap();
+        let context_a = chain.state.next_block_context().unwrap();
+        chain.state.disconnect_body(&block_a).unwrap();
+        let commit_b = chain.commit_tx(owner, "b.com", 1);
+        chain.connect(vec![commit_b]).unwrap();
+        let context_b = chain.state.next_block_context().unwrap();
+        assert_ne!(context_a, context_b);
+
+        let bound_to_a = bind(&mut chain, context_a);
+        assert!(matches!(
+            chain.state.validate_transaction(&bound_to_a.transaction),
+            Err(Error::BitNames(BitNamesError::ContextMismatch { expected, found }))
+                if expected == context_b && found == context_a
+        ));
+        let bound_to_b = bind(&mut chain, context_b);
+        chain.connect(vec![bound_to_b]).unwrap();
+        chain.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn empty_body_only_advances_height_and_sweeps() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let commitment_outpoint = chain.commit(chain.addresses[0], "empty.com", 1).unwrap();
+        let commitment = blake2b_hmac(&key_of("empty.com"), 1);
+        let root = chain.state.compute_state_root().unwrap();
+
+        // Nothing is due at height 2.
+        let diff = chain
+            .state
+            .preview_diff(&Body::new(vec![], vec![]))
+            .unwrap();
+        assert!(diff.utxos_added.is_empty() && diff.utxos_removed.is_empty());
+        assert!(diff.commitments_removed.is_empty());
+        chain.advance(1);
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.state.compute_state_root().unwrap(), root);
+
+        // At height 3 the commitment aged out and is swept, along with its
+        // utxo, and nothing else.
+        let diff = chain
+            .state
+            .preview_diff(&Body::new(vec![], vec![]))
+            .unwrap();
+        assert_eq!(diff.commitments_removed, vec![commitment]);
+        assert_eq!(diff.utxos_removed.len(), 1);
+        assert_eq!(diff.utxos_removed[0].0, commitment_outpoint);
+        assert!(diff.utxos_added.is_empty() && diff.commitments_added.is_empty());
+        assert!(diff.names_added.is_empty() && diff.names_removed.is_empty());
+        assert!(diff.values_changed.is_empty());
+        chain.advance(1);
+        assert_eq!(chain.height(), 3);
+        assert!(chain
+            .state
+            .get_utxo(&commitment_outpoint)
+            .unwrap()
+            .is_none());
+        chain.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn rejects_body_including_transaction_twice() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let commit = chain.commit_tx(chain.addresses[0], "twice.com", 1);
+        let txid = commit.transaction.txid();
+        let root = chain.state.compute_state_root().unwrap();
+        assert!(matches!(
+            chain.connect(vec![commit.clone(), commit]),
+            Err(Error::BitNames(BitNamesError::DuplicateTransaction { txid: duplicate }))
+                if duplicate == txid
+        ));
+        assert_eq!(chain.height(), 0);
+        assert_eq!(chain.state.compute_state_root().unwrap(), root);
+    }
+
+    #[test]
+    fn state_root_survives_disconnect_and_reconnect() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        let key = key_of("root.com");
+        let name = chain.register(owner, "root.com", 1).unwrap();
+        // Funded by a deposit, which disconnecting the body leaves alone.
+        let commit = chain.commit_tx(owner, "other.com", 2);
+        let before = chain.state.compute_state_root().unwrap();
+
+        let value = Some(hash(&"rooted").into());
+        let set_value = chain.spend_tx(name, owner, BitNamesOutput::KeyValue { key, value });
+        let body = chain.connect(vec![set_value, commit]).unwrap();
+        let after = chain.state.compute_state_root().unwrap();
+        assert_ne!(after, before);
+
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(chain.state.compute_state_root().unwrap(), before);
+        chain.state.connect_body(&body).unwrap();
+        assert_eq!(chain.state.compute_state_root().unwrap(), after);
+        chain.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn genesis_commitment_expires_at_documented_height() {
+        // With the default max age of 1, a commitment made in block 1 can
+        // be revealed in block 2 and is swept when block 3 is connected.
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let commitment_outpoint = chain.commit(owner, "early.com", 1).unwrap();
+        let commitment = blake2b_hmac(&key_of("early.com"), 1);
+        assert_eq!(
+            chain.state.expiring_within(2).unwrap(),
+            vec![(commitment, 2)]
+        );
+        let reveal = chain.reveal_tx(commitment_outpoint, owner, "early.com", 1);
+        chain
+            .state
+            .validate_transaction(&reveal.transaction)
+            .unwrap();
+
+        chain.advance(1);
+        assert!(matches!(
+            chain.state.validate_transaction(&reveal.transaction),
+            Err(Error::BitNames(BitNamesError::RevealTooLate {
+                late_by: 1,
+                ..
+            }))
+        ));
+        assert!(chain.connect(vec![reveal]).is_err());
+        assert!(chain
+            .state
+            .get_utxo(&commitment_outpoint)
+            .unwrap()
+            .is_some());
+        chain.advance(1);
+        assert_eq!(chain.height(), 3);
+        assert!(chain
+            .state
+            .get_utxo(&commitment_outpoint)
+            .unwrap()
+            .is_none());
+        assert!(chain.state.expiring_within(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expiring_within_orders_commitments_by_blocks_left() {
+        let mut chain = TestChain::new(params(3));
+        let owner = chain.addresses[0];
+        let commitments: Vec<Commitment> = ["first.com", "second.com", "third.com"]
+            .into_iter()
+            .map(|name| {
+                chain.commit(owner, name, 1).unwrap();
+                blake2b_hmac(&key_of(name), 1)
+            })
+            .collect();
+        // Committed at 1, 2 and 3 and swept at 5, 6 and 7.
+        assert!(chain.state.expiring_within(1).unwrap().is_empty());
+        assert_eq!(
+            chain.state.expiring_within(3).unwrap(),
+            vec![(commitments[0], 2), (commitments[1], 3)]
+        );
+        assert_eq!(
+            chain.state.expiring_within(10).unwrap(),
+            vec![
+                (commitments[0], 2),
+                (commitments[1], 3),
+                (commitments[2], 4)
+            ]
+        );
+        chain.advance(2);
+        assert_eq!(
+            chain.state.expiring_within(1).unwrap(),
+            vec![(commitments[1], 1)]
+        );
+    }
+
+    #[test]
+    fn auction_goes_to_highest_of_three_bids() {
+        let mut chain = TestChain::new(BitNamesParams {
+            auction_bid_window: 3,
+            auction_reveal_window: 2,
+            ..params(10)
+        });
+        let (alice, bob, carol) = (chain.addresses[0], chain.addresses[1], chain.addresses[2]);
+        let key = key_of("auction.com");
+        let bid = |chain: &mut TestChain, bidder, salt, amount| {
+            let funding = chain.deposit(bidder, amount);
+            let commitment = blake2b_hmac(&key, salt);
+            let transaction = chain.sign(
+                vec![funding],
+                vec![custom(
+                    bidder,
+                    BitNamesOutput::Bid {
+                        key,
+                        commitment,
+                        amount,
+                    },
+                )],
+            );
+            let outpoint = OutPoint::Regular {
+                txid: transaction.transaction.txid(),
+                vout: 0,
+            };
+            (transaction, outpoint)
+        };
+
+        // Alice's bid at 1 opens the auction, which takes bids until 3.
+        let (alice_bid, alice_outpoint) = bid(&mut chain, alice, 1, 5);
+        chain.connect(vec![alice_bid]).unwrap();
+        let (bob_bid, bob_outpoint) = bid(&mut chain, bob, 2, 9);
+        let (carol_bid, carol_outpoint) = bid(&mut chain, carol, 3, 7);
+        chain.connect(vec![bob_bid, carol_bid]).unwrap();
+        let withdrawal = chain.sign(
+            vec![bob_outpoint],
+            vec![Output {
+                address: bob,
+                content: Content::Value(9),
+            }],
+        );
+        assert!(matches!(
+            chain.state.validate_transaction(&withdrawal.transaction),
+            Err(Error::BitNames(BitNamesError::BidLocked { .. }))
+        ));
+        let bob_reveal = chain.reveal_tx(bob_outpoint, bob, "auction.com", 2);
+        assert!(matches!(
+            chain.state.validate_transaction(&bob_reveal.transaction),
+            Err(Error::BitNames(
+                BitNamesError::RevealOutsideAuctionWindow { .. }
+            ))
+        ));
+        chain.advance(1);
+
+        // Revealing from 4, where only the top bid can register the key.
+        let carol_reveal = chain.reveal_tx(carol_outpoint, carol, "auction.com", 3);
+        assert!(matches!(
+            chain.state.validate_transaction(&carol_reveal.transaction),
+            Err(Error::BitNames(BitNamesError::NotTopBid { .. }))
+        ));
+        let root = chain.state.compute_state_root().unwrap();
+        let won = chain.connect(vec![bob_reveal.clone()]).unwrap();
+        assert_eq!(chain.state.owner_of(&key).unwrap(), Some(bob));
+        chain.state.check_invariants().unwrap();
+
+        // Undoing the win leaves the auction open to the same reveal.
+        chain.state.disconnect_body(&won).unwrap();
+        assert_eq!(chain.state.compute_state_root().unwrap(), root);
+        assert_eq!(chain.state.owner_of(&key).unwrap(), None);
+        chain.connect(vec![bob_reveal]).unwrap();
+        assert_eq!(chain.state.owner_of(&key).unwrap(), Some(bob));
+
+        // The losing bids are reclaimed once bidding is over.
+        let reclaim = chain.sign(
+            vec![alice_outpoint, carol_outpoint],
+            vec![
+                Output {
+                    address: alice,
+                    content: Content::Value(5),
+                },
+                Output {
+                    address: carol,
+                    content: Content::Value(7),
+                },
+            ],
+        );
+        chain.connect(vec![reclaim]).unwrap();
+        assert_eq!(chain.state.owner_of(&key).unwrap(), Some(bob));
+        chain.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn generation_is_shared_by_handles_on_one_env() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let other = BitNamesState::new(&env, BitNamesParams::default()).unwrap();
+        let start = other.current_generation().unwrap();
+        let body = chain.connect(vec![]).unwrap();
+        assert_eq!(other.current_generation().unwrap(), start + 1);
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(other.current_generation().unwrap(), start + 2);
+        assert_eq!(
+            chain.state.current_generation().unwrap(),
+            other.current_generation().unwrap()
+        );
+    }
+
+    #[test]
+    fn importing_a_snapshot_bumps_the_generation() {
+        let mut source = TestChain::new(BitNamesParams::default());
+        source.connect(vec![]).unwrap();
+        let mut snapshot = vec![];
+        source.state.export_snapshot(&mut snapshot).unwrap();
+
+        let mut chain = TestChain::new(BitNamesParams::default());
+        for _ in 0..3 {
+            chain.connect(vec![]).unwrap();
+        }
+        let before = chain.state.current_generation().unwrap();
+        chain.state.import_snapshot(&mut &snapshot[..]).unwrap();
+        assert_eq!(chain.state.current_generation().unwrap(), before + 1);
+    }
+
+    #[test]
+    fn connect_bodies_bumps_the_generation_per_body() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let before = chain.state.current_generation().unwrap();
+        let bodies: Vec<Body> = (0..3).map(|_| Body::new(vec![], vec![])).collect();
+        chain.state.connect_bodies(&bodies).unwrap();
+        assert_eq!(chain.state.current_generation().unwrap(), before + 3);
+        for body in bodies.iter().rev() {
+            chain.state.disconnect_body(body).unwrap();
+        }
+        assert_eq!(chain.state.current_generation().unwrap(), before + 6);
+    }
+
+    fn nameless_reveal_tx(
+        chain: &TestChain,
+        commitment: OutPoint,
+        owner: Address,
+        name: &str,
+        salt: u64,
+    ) -> AuthorizedTransaction {
+        chain.sign(
+            vec![commitment],
+            vec![custom(
+                owner,
+                BitNamesOutput::Reveal {
+                    salt,
+                    key: key_of(name),
+                    name: None,
+                    path: vec![],
+                    value: None,
+                },
+            )],
+        )
+    }
+
+    #[test]
+    fn only_delegate_registers_under_delegated_name() {
+        let mut chain = TestChain::new(params(10));
+        let (alice, bob, carol) = (chain.addresses[0], chain.addresses[1], chain.addresses[2]);
+        let myorg = chain.register(alice, "myorg", 1).unwrap();
+        let delegate = chain.spend_tx(
+            myorg,
+            alice,
+            BitNamesOutput::Delegation {
+                key: key_of("myorg"),
+                delegate: Some(bob),
+            },
+        );
+        chain.connect(vec![delegate]).unwrap();
+
+        let commitment = chain.commit(carol, "team.myorg", 2).unwrap();
+        let reveal = chain.reveal_tx(commitment, carol, "team.myorg", 2);
+        assert!(matches!(
+            chain.connect(vec![reveal]),
+            Err(Error::BitNames(BitNamesError::NotDelegate { .. }))
+        ));
+        let commitment = chain.commit(bob, "team.myorg", 3).unwrap();
+        chain.reveal(commitment, bob, "team.myorg", 3).unwrap();
+        assert_eq!(
+            chain.state.get_value(&key_of("team.myorg")).unwrap(),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn delegation_requires_inline_names_until_revoked() {
+        let mut chain = TestChain::new(params(10));
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let commitment = chain.commit(alice, "plain.com", 1).unwrap();
+        let reveal = nameless_reveal_tx(&chain, commitment, alice, "plain.com", 1);
+        chain.connect(vec![reveal]).unwrap();
+
+        let myorg = chain.register(alice, "myorg", 2).unwrap();
+        let delegate = chain.spend_tx(
+            myorg,
+            alice,
+            BitNamesOutput::Delegation {
+                key: key_of("myorg"),
+                delegate: Some(bob),
+            },
+        );
+        let txid = delegate.transaction.txid();
+        chain.connect(vec![delegate]).unwrap();
+        // Neither key can be told apart from one under `myorg`.
+        for (name, salt) in [("team.myorg", 3), ("other.com", 4)] {
+            let commitment = chain.commit(bob, name, salt).unwrap();
+            let reveal = nameless_reveal_tx(&chain, commitment, bob, name, salt);
+            assert!(
+                matches!(
+                    chain.connect(vec![reveal]),
+                    Err(Error::BitNames(BitNamesError::MissingInlineName { key }))
+                        if key == key_of(name)
+                ),
+                "{name}"
+            );
+        }
+
+        let revoke = chain.spend_tx(
+            OutPoint::Regular { txid, vout: 0 },
+            alice,
+            BitNamesOutput::Delegation {
+                key: key_of("myorg"),
+                delegate: None,
+            },
+        );
+        chain.connect(vec![revoke]).unwrap();
+        let commitment = chain.commit(alice, "other.com", 5).unwrap();
+        let reveal = nameless_reveal_tx(&chain, commitment, alice, "other.com", 5);
+        chain.connect(vec![reveal]).unwrap();
+        assert_eq!(
+            chain.state.get_value(&key_of("other.com")).unwrap(),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn reserved_suffix_only_blocks_whole_labels() {
+        let mut chain = TestChain::new(BitNamesParams {
+            reserved_suffixes: vec!["com".into()],
+            commitment_max_age: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        chain.register(owner, "telecom", 1).unwrap();
+        assert!(matches!(
+            chain.register(owner, "example.com", 2),
+            Err(Error::BitNames(BitNamesError::ReservedSuffix { suffix, .. })) if suffix == "com"
+        ));
+    }
+
+    /// A body committing to a fresh name at every height from the chain's
+    /// next one, the same for every chain built with the same calls.
+    fn commit_bodies(chain: &mut TestChain, count: u64) -> Vec<Body> {
+        let owner = chain.addresses[0];
+        (0..count)
+            .map(|index| {
+                let commit = chain.commit_tx(owner, &format!("bulk{index}.com"), index);
+                Body::new(vec![commit], vec![])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn connect_bodies_matches_sequential_connection() {
+        let mut sequential = TestChain::new(BitNamesParams::default());
+        let mut bulk = TestChain::new(BitNamesParams::default());
+        let bodies = commit_bodies(&mut sequential, 20);
+        assert_eq!(commit_bodies(&mut bulk, 20).len(), bodies.len());
+        for body in &bodies {
+            sequential.state.connect_body(body).unwrap();
+        }
+        bulk.state.connect_bodies(&bodies).unwrap();
+        assert_eq!(bulk.height(), sequential.height());
+        assert_eq!(
+            bulk.state.compute_state_root().unwrap(),
+            sequential.state.compute_state_root().unwrap()
+        );
+        bulk.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn connect_bodies_sweeps_before_later_bodies_spend() {
+        let mut chain = TestChain::new(params(1));
+        let owner = chain.addresses[0];
+        // Made at height 1, so block 3 sweeps it.
+        let commitment = chain.commit(owner, "swept.com", 1).unwrap();
+        let reclaim = chain.sign(
+            vec![commitment],
+            vec![Output {
+                address: owner,
+                content: Content::Value(0),
+            }],
+        );
+        let bodies = vec![
+            Body::new(vec![], vec![]),
+            Body::new(vec![], vec![]),
+            Body::new(vec![reclaim], vec![]),
+        ];
+        assert!(chain.state.connect_bodies(&bodies).is_err());
+        assert_eq!(chain.height(), 1);
+        assert!(chain.state.get_utxo(&commitment).unwrap().is_some());
+
+        chain.state.connect_bodies(&bodies[..2]).unwrap();
+        assert!(chain.state.get_utxo(&commitment).unwrap().is_none());
+        assert!(chain.state.connect_body(&bodies[2]).is_err());
+    }
+
+    /// Not a real benchmark harness, the crate is a binary, but enough to
+    /// compare bulk and per-body connection. Run with
+    /// `--release -- --ignored bench`.
+    #[test]
+    #[ignore]
+    fn bench_connect_bodies() {
+        let mut sequential = TestChain::new(BitNamesParams::default());
+        let mut bulk = TestChain::new(BitNamesParams::default());
+        let bodies = commit_bodies(&mut sequential, 500);
+        commit_bodies(&mut bulk, 500);
+
+        let start = Instant::now();
+        for body in &bodies {
+            sequential.state.connect_body(body).unwrap();
+        }
+        println!("connect_body: {:?}", start.elapsed());
+        let start = Instant::now();
+        bulk.state.connect_bodies(&bodies).unwrap();
+        println!("connect_bodies: {:?}", start.elapsed());
+        assert_eq!(
+            bulk.state.compute_state_root().unwrap(),
+            sequential.state.compute_state_root().unwrap()
+        );
+    }
+
+    /// How much of validating a 200 transaction body goes to computing
+    /// txids, which would be all a cached unsigned transaction hash saves.
+    /// Run with `--release -- --ignored bench`.
+    #[test]
+    #[ignore]
+    fn bench_txid_share_of_validation() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let transactions = (0..200)
+            .map(|index| chain.commit_tx(owner, &format!("bench{index}.com"), index))
+            .collect();
+        let body = Body::new(transactions, vec![]);
+
+        let start = Instant::now();
+        chain.state.validate_body(1, &body).unwrap();
+        println!("validate_body: {:?}", start.elapsed());
+        let start = Instant::now();
+        let txids: Vec<_> = body.transactions.iter().map(Transaction::txid).collect();
+        println!("{} txids: {:?}", txids.len(), start.elapsed());
+    }
+
+    #[test]
+    fn progress_fires_once_per_body() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let bodies = commit_bodies(&mut chain, 5);
+        let mut reports = vec![];
+        chain
+            .state
+            .connect_bodies_with_progress(&bodies, |connected, total| {
+                reports.push((connected, total))
+            })
+            .unwrap();
+        assert_eq!(
+            reports,
+            (1..=5).map(|connected| (connected, 5)).collect::<Vec<_>>()
+        );
+        assert_eq!(chain.height(), 5);
+    }
+
+    #[test]
+    fn bad_body_mid_batch_leaves_state_root_unchanged() {
+        // Shorter than the batch, nothing has to be disconnected.
+        let params = BitNamesParams {
+            max_reorg_depth: 2,
+            ..Default::default()
+        };
+        let mut chain = TestChain::new(params);
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let mut bodies = commit_bodies(&mut chain, 4);
+        // Both valid on their own, but the second spends what the first did.
+        let funding = chain.deposit(alice, 1);
+        let pay = |to| Output {
+            address: to,
+            content: Content::Value(1),
+        };
+        let spend = chain.sign(vec![funding], vec![pay(alice)]);
+        let double_spend = chain.sign(vec![funding], vec![pay(bob)]);
+        bodies[1] = Body::new(vec![spend], vec![]);
+        bodies.insert(3, Body::new(vec![double_spend], vec![]));
+        let root = chain.state.compute_state_root().unwrap();
+        let generation = chain.state.current_generation().unwrap();
+
+        let mut reports = vec![];
+        assert!(chain
+            .state
+            .connect_bodies_with_progress(&bodies, |connected, _| reports.push(connected))
+            .is_err());
+        assert_eq!(reports, vec![1, 2, 3]);
+        assert_eq!(chain.height(), 0);
+        assert_eq!(chain.state.compute_state_root().unwrap(), root);
+        assert_eq!(chain.state.current_generation().unwrap(), generation);
+        chain.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn default_max_dbs_leaves_headroom() {
+        let chain = TestChain::new(BitNamesParams::default());
+        // The nameserver's records are the only database outside the state.
+        let used = chain.state.raw_databases().len() + 1;
+        assert!(used < crate::storage::OpenOptions::default().max_dbs as usize);
+    }
+
+    #[test]
+    fn batch_commitment_reveals_every_key_with_its_path() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        let names = ["one.com", "two.com", "three.com"];
+        let keys: Vec<Key> = names.iter().map(|name| key_of(name)).collect();
+        let leaves: Vec<Hash> = keys.iter().copied().map(Hash::from).collect();
+        let funding = chain.deposit(owner, 1);
+        let commit = chain.sign(
+            vec![funding],
+            vec![custom(
+                owner,
+                BitNamesOutput::Commitment {
+                    commitment: blake2b_batch_commit(&keys, 7),
+                    fee: 0,
+                },
+            )],
+        );
+        let commitment = OutPoint::Regular {
+            txid: commit.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![commit]).unwrap();
+
+        let reveal = |path: &dyn Fn(usize) -> Vec<MerkleStep>| {
+            let outputs = names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| {
+                    custom(
+                        owner,
+                        BitNamesOutput::Reveal {
+                            salt: 7,
+                            key: keys[index],
+                            name: Some((*name).into()),
+                            path: path(index),
+                            value: None,
+                        },
+                    )
+                })
+                .collect();
+            chain.sign(vec![commitment], outputs)
+        };
+        // Every key with the path of the next one: none lead to the root.
+        let swapped = reveal(&|index| merkle_path(&leaves, (index + 1) % leaves.len()));
+        let valid = reveal(&|index| merkle_path(&leaves, index));
+        assert!(matches!(
+            chain.connect(vec![swapped]),
+            Err(Error::BitNames(BitNamesError::InvalidNameCommitment { .. }))
+        ));
+        chain.connect(vec![valid]).unwrap();
+        for key in &keys {
+            assert_eq!(chain.state.get_value(key).unwrap(), Some(None));
+        }
+    }
+
+    #[test]
+    fn key_proofs_check_against_name_root() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        for (salt, name) in ["a.com", "b.com", "c.com"].iter().enumerate() {
+            chain.register(owner, name, salt as u64).unwrap();
+        }
+        let root = chain.state.name_root().unwrap();
+        let key = key_of("b.com");
+        let proof = chain.state.prove_key(&key).unwrap();
+        assert!(verify_key_proof(&root, &key, &proof));
+        assert!(!verify_key_proof(&root, &key_of("a.com"), &proof));
+    }
+
+    #[test]
+    fn summarizes_only_valid_bodies() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let commit = chain.commit_tx(alice, "summary.com", 1);
+        let funding = chain.deposit(alice, 10);
+        let pay = |to| Output {
+            address: to,
+            content: Content::Value(7),
+        };
+        let payment = chain.sign(vec![funding], vec![pay(bob)]);
+        let double_spend = chain.sign(vec![funding], vec![pay(alice)]);
+        let confirmed = chain.sign(vec![funding], vec![pay(bob)]);
+        let body = Body::new(vec![commit, payment], vec![]);
+        assert_eq!(
+            chain.state.summarize_body(&body).unwrap(),
+            BodySummary {
+                transactions: 2,
+                inputs: 2,
+                outputs: 2,
+                commitments: 1,
+                value_transfers: 1,
+                value_out: 7,
+                fee: Some(4),
+                ..Default::default()
+            }
+        );
+
+        let body = Body::new(vec![double_spend], vec![]);
+        assert_eq!(body.summary().value_out, 7);
+        chain.connect(vec![confirmed]).unwrap();
+        assert!(chain.state.summarize_body(&body).is_err());
+    }
+
+    #[test]
+    fn stateless_verification_checks_shape_and_signatures() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let commit = chain.commit_tx(owner, "relay.com", 1);
+        verify_transaction_stateless(&commit).unwrap();
+
+        let unsigned = AuthorizedTransaction {
+            transaction: commit.transaction.clone(),
+            authorizations: vec![],
+        };
+        assert!(matches!(
+            verify_transaction_stateless(&unsigned),
+            Err(Error::BitNames(BitNamesError::AuthorizationCountMismatch {
+                inputs: 1,
+                authorizations: 0
+            }))
+        ));
+        let funding = chain.deposit(owner, 1);
+        let zero = chain.sign(
+            vec![funding],
+            vec![custom(
+                owner,
+                BitNamesOutput::Commitment {
+                    commitment: Hash::default().into(),
+                    fee: 0,
+                },
+            )],
+        );
+        assert!(matches!(
+            verify_transaction_stateless(&zero),
+            Err(Error::BitNames(BitNamesError::ZeroCommitment))
+        ));
+        let mut forged = chain.commit_tx(owner, "forged.com", 1);
+        forged.authorizations = commit.authorizations.clone();
+        assert!(matches!(
+            verify_transaction_stateless(&forged),
+            Err(Error::Authorization(_))
+        ));
+    }
+
+    #[test]
+    fn epochs_follow_epoch_length() {
+        let mut chain = TestChain::new(BitNamesParams {
+            epoch_length: 3,
+            ..Default::default()
+        });
+        assert_eq!(
+            [0, 2, 3, 5, 6].map(|height| chain.state.epoch_of(height)),
+            [0, 0, 1, 1, 2]
+        );
+        assert_eq!(chain.state.current_epoch(), 0);
+        for _ in 0..3 {
+            chain.connect(vec![]).unwrap();
+        }
+        assert_eq!(chain.state.current_epoch(), 1);
+
+        let (env, _dir) = temp_env();
+        let params = BitNamesParams {
+            epoch_length: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            BitNamesState::new(&env, params),
+            Err(Error::Config(ConfigError::ZeroEpochLength))
+        ));
+    }
+
+    #[test]
+    fn commitment_outpoint_found_for_revealed_key_only() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        let commitment = chain.commit(owner, "outpoint.com", 1).unwrap();
+        let key = key_of("outpoint.com");
+        assert_eq!(chain.state.commitment_outpoint_for_key(&key).unwrap(), None);
+        chain.reveal(commitment, owner, "outpoint.com", 1).unwrap();
+        assert_eq!(
+            chain.state.commitment_outpoint_for_key(&key).unwrap(),
+            Some(commitment)
+        );
+        assert_eq!(
+            chain
+                .state
+                .commitment_outpoint_for_key(&key_of("never.com"))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn body_inputs_expose_double_spends() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let first = chain.commit_tx(owner, "first.com", 1);
+        let second = chain.commit_tx(owner, "second.com", 2);
+        let inputs: Vec<OutPoint> = [&first, &second]
+            .iter()
+            .flat_map(|transaction| transaction.transaction.inputs.clone())
+            .collect();
+        let body = Body::new(vec![first, second], vec![]);
+        assert_eq!(body.inputs(), inputs);
+        assert_eq!(body.spent_outpoints().len(), 2);
+
+        let double_spend = Body::new(
+            vec![
+                chain.sign(vec![inputs[0]], vec![]),
+                chain.sign(vec![inputs[0]], vec![]),
+            ],
+            vec![],
+        );
+        assert_eq!(double_spend.inputs().len(), 2);
+        assert_eq!(double_spend.spent_outpoints().len(), 1);
+        assert!(chain.state.validate_body(1, &double_spend).is_err());
+    }
+
+    #[test]
+    fn read_only_handle_sees_committed_writes() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, BitNamesParams::default());
+        let reader = BitNamesState::read_only(&env).unwrap();
+        let key = key_of("reader.com");
+        assert_eq!(reader.get_value(&key).unwrap(), None);
+        assert_eq!(reader.stats().unwrap(), Stats::default());
+
+        let owner = chain.addresses[0];
+        chain.register(owner, "reader.com", 1).unwrap();
+        assert_eq!(reader.get_value(&key).unwrap(), Some(None));
+        assert_eq!(reader.iter_names().unwrap(), vec![(key, None)]);
+        assert_eq!(reader.stats().unwrap(), chain.state.stats().unwrap());
+        assert_eq!(reader.stats().unwrap().names, 1);
+        assert_eq!(reader.get_value(&key_of("other.com")).unwrap(), None);
+    }
+
+    #[test]
+    fn squatting_report_flags_addresses_over_threshold() {
+        let mut chain = TestChain::new(BitNamesParams {
+            squatting_threshold: 1,
+            ..params(10)
+        });
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let squatted = chain.register(alice, "squat1.com", 1).unwrap();
+        chain.register(alice, "squat2.com", 2).unwrap();
+        chain.register(bob, "single.com", 3).unwrap();
+        let report = chain.state.squatting_report().unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].address, alice);
+        let flagged: HashSet<Key> = report[0].names.iter().copied().collect();
+        assert_eq!(
+            flagged,
+            HashSet::from([key_of("squat1.com"), key_of("squat2.com")])
+        );
+
+        let key = key_of("squat1.com");
+        let value = Some(hash(&"used").into());
+        let set_value = chain.spend_tx(squatted, alice, BitNamesOutput::KeyValue { key, value });
+        chain.connect(vec![set_value]).unwrap();
+        assert!(chain.state.squatting_report().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recent_registrations_are_newest_first_and_undone_on_disconnect() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        chain.register(owner, "older.com", 1).unwrap();
+        let commitment = chain.commit(owner, "newer.com", 2).unwrap();
+        let reveal = chain.reveal_tx(commitment, owner, "newer.com", 2);
+        let body = chain.connect(vec![reveal]).unwrap();
+        assert_eq!(
+            chain.state.recent_registrations(10).unwrap(),
+            vec![(key_of("newer.com"), 4), (key_of("older.com"), 2)]
+        );
+        assert_eq!(
+            chain.state.recent_registrations(1).unwrap(),
+            vec![(key_of("newer.com"), 4)]
+        );
+        assert!(chain.state.recent_registrations(0).unwrap().is_empty());
+
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(
+            chain.state.recent_registrations(10).unwrap(),
+            vec![(key_of("older.com"), 2)]
+        );
+    }
+
+    #[test]
+    fn reveals_wait_for_commitment_min_age() {
+        let mut chain = TestChain::new(BitNamesParams {
+            commitment_min_age: 2,
+            commitment_max_age: 5,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        let commitment = chain.commit(owner, "patient.com", 1).unwrap();
+        assert!(matches!(
+            chain.reveal(commitment, owner, "patient.com", 1),
+            Err(Error::BitNames(BitNamesError::RevealTooEarly {
+                early_by: 1,
+                ..
+            }))
+        ));
+        chain.advance(1);
+        chain.reveal(commitment, owner, "patient.com", 1).unwrap();
+        assert_eq!(
+            chain.state.get_owner(&key_of("patient.com")).unwrap(),
+            Some(owner)
+        );
+    }
+
+    #[test]
+    fn rejects_weights_outside_bounds() {
+        let mut chain = TestChain::new(BitNamesParams {
+            max_value_weight: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        let key = key_of("bounded.com");
+        let name = chain.register(owner, "bounded.com", 1).unwrap();
+        let value: Value = hash(&"value").into();
+        for weight in [0, 11] {
+            let transaction = chain.spend_tx(
+                name,
+                owner,
+                BitNamesOutput::WeightedValues {
+                    key,
+                    values: vec![(hash(&"other").into(), 1), (value, weight)],
+                },
+            );
+            assert!(matches!(
+                chain.state.validate_transaction(&transaction.transaction),
+                Err(Error::BitNames(BitNamesError::InvalidWeight { weight: w, max: 10, .. })) if w == weight
+            ));
+        }
+        let transaction = chain.spend_tx(
+            name,
+            owner,
+            BitNamesOutput::WeightedValues {
+                key,
+                values: vec![(value, 10)],
+            },
+        );
+        chain.connect(vec![transaction]).unwrap();
+        assert_eq!(
+            chain.state.get_weighted_values(&key).unwrap(),
+            Some(vec![(value, 10)])
+        );
+    }
+
+    #[test]
+    fn invariants_require_contiguous_undo_log_at_tip() {
+        let mut chain = TestChain::new(BitNamesParams {
+            max_reorg_depth: 3,
+            ..Default::default()
+        });
+        chain.advance(4);
+        chain.state.check_invariants().unwrap();
+        let reason = |state: &BitNamesState| match state.check_invariants() {
+            Err(Error::BitNames(BitNamesError::InvariantViolation { reason })) => reason,
+            other => panic!("{other:?}"),
+        };
+
+        let mut wtxn = chain.state.env.write_txn().unwrap();
+        let record = chain
+            .state
+            .undo
+            .get(&wtxn, &BEU32::new(3))
+            .unwrap()
+            .unwrap();
+        chain.state.undo.delete(&mut wtxn, &BEU32::new(3)).unwrap();
+        wtxn.commit().unwrap();
+        assert_eq!(
+            reason(&chain.state),
+            "undo record at height 2, expected one at height 3"
+        );
+
+        let mut wtxn = chain.state.env.write_txn().unwrap();
+        chain
+            .state
+            .undo
+            .put(&mut wtxn, &BEU32::new(3), &record)
+            .unwrap();
+        chain
+            .state
+            .undo
+            .put(&mut wtxn, &BEU32::new(1), &record)
+            .unwrap();
+        wtxn.commit().unwrap();
+        assert_eq!(
+            reason(&chain.state),
+            "undo record at height 1 should have been pruned"
+        );
+    }
+
+    #[test]
+    fn address_proofs_verify_against_utxo_root() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let alice_utxos = HashSet::from([chain.deposit(alice, 1), chain.deposit(alice, 2)]);
+        chain.deposit(bob, 3);
+        let root = chain.state.utxo_root().unwrap();
+
+        let proof = chain.state.address_proof(&alice).unwrap();
+        assert_eq!(proof.root, root);
+        assert_eq!(
+            proof
+                .utxos
+                .iter()
+                .map(|(outpoint, _, _)| *outpoint)
+                .collect::<HashSet<_>>(),
+            alice_utxos
+        );
+        assert!(proof.verify(&root).unwrap());
+        assert!(!proof.verify(&hash(&"other root")).unwrap());
+
+        let mut inflated = proof.clone();
+        inflated.utxos[0].1.content = Content::Value(100);
+        assert!(!inflated.verify(&root).unwrap());
+        let mut stolen = chain.state.address_proof(&bob).unwrap();
+        stolen.address = alice;
+        assert!(!stolen.verify(&root).unwrap());
+
+        chain.deposit(bob, 4);
+        assert!(!proof.verify(&chain.state.utxo_root().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn rejects_revealing_a_key_twice() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        let commitment = chain.commit(owner, "twice.org", 1).unwrap();
+        let reveal = chain.reveal_tx(commitment, owner, "twice.org", 1);
+        let output = reveal.transaction.outputs[0].clone();
+        let transaction = chain.sign(vec![commitment], vec![output.clone(), output]);
+        assert!(matches!(
+            chain.state.validate_transaction(&transaction.transaction),
+            Err(Error::BitNames(BitNamesError::DuplicateReveal { key, .. }))
+                if key == key_of("twice.org")
+        ));
+        chain.connect(vec![reveal]).unwrap();
+        assert_eq!(
+            chain.state.get_owner(&key_of("twice.org")).unwrap(),
+            Some(owner)
+        );
+    }
+
+    #[test]
+    fn registration_deposit_is_refunded_on_first_value() {
+        let mut chain = TestChain::new(BitNamesParams {
+            registration_deposit: 5,
+            deposit_grace_period: 2,
+            commitment_max_age: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        let paid = |chain: &mut TestChain, name: &str| {
+            let commitment = chain.commit(owner, name, 1).unwrap();
+            let reveal = chain.reveal_tx(commitment, owner, name, 1);
+            assert!(matches!(
+                chain.state.validate_transaction(&reveal.transaction),
+                Err(Error::BitNames(BitNamesError::InsufficientDeposit {
+                    required: 5,
+                    provided: 0
+                }))
+            ));
+            let funding = chain.deposit(owner, 5);
+            let reveal = chain.sign(vec![commitment, funding], reveal.transaction.outputs);
+            let txid = reveal.transaction.txid();
+            chain.connect(vec![reveal]).unwrap();
+            OutPoint::Regular { txid, vout: 0 }
+        };
+
+        let deposit = |state: &BitNamesState, key: &Key| {
+            let rtxn = state.env.read_txn().unwrap();
+            state.key_to_deposit.get(&rtxn, key).unwrap()
+        };
+
+        let used = paid(&mut chain, "used.com");
+        let key = key_of("used.com");
+        assert!(deposit(&chain.state, &key).is_some());
+        let set_value = chain.spend_tx(
+            used,
+            owner,
+            BitNamesOutput::KeyValue {
+                key,
+                value: Some(hash(&"value").into()),
+            },
+        );
+        let txid = set_value.transaction.txid();
+        chain.connect(vec![set_value]).unwrap();
+        let refund = chain
+            .state
+            .get_utxo(&OutPoint::Regular { txid, vout: 1 })
+            .unwrap()
+            .unwrap();
+        assert_eq!(refund.address, owner);
+        assert!(matches!(refund.content, Content::Value(5)));
+        assert!(deposit(&chain.state, &key).is_none());
+
+        paid(&mut chain, "idle.com");
+        let key = key_of("idle.com");
+        chain.advance(2);
+        assert!(deposit(&chain.state, &key).is_some());
+        chain.advance(1);
+        assert!(deposit(&chain.state, &key).is_none());
+    }
+
+    #[test]
+    fn structural_checks_run_before_signatures() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let (a, b) = (chain.deposit(alice, 1), chain.deposit(bob, 1));
+        let pay = |to: Address| Output {
+            address: to,
+            content: Content::Value(1),
+        };
+        let height = chain.height() + 1;
+        let transactions = vec![
+            chain.sign(vec![a], vec![pay(bob)]),
+            chain.sign(vec![b], vec![pay(alice)]),
+        ];
+        let body = Body::new(transactions.clone(), vec![]);
+        chain.state.validate_body(height, &body).unwrap();
+
+        let mut forged = Body::new(transactions, vec![]);
+        forged.authorizations.swap(0, 1);
+        assert!(matches!(
+            chain.state.validate_body(height, &forged),
+            Err(Error::Authorization(_))
+        ));
+
+        // Forged signatures too, but the double spend is reported first.
+        let mut double_spend = Body::new(
+            vec![
+                chain.sign(vec![a], vec![pay(bob)]),
+                chain.sign(vec![a], vec![pay(alice)]),
+            ],
+            vec![],
+        );
+        double_spend.authorizations.swap(0, 1);
+        assert!(matches!(
+            chain.state.validate_body(height, &double_spend),
+            Err(Error::BitNames(BitNamesError::DuplicateInput { outpoint })) if outpoint == a
+        ));
+
+        forged.authorizations.pop();
+        assert!(matches!(
+            chain.state.validate_body(height, &forged),
+            Err(Error::BitNames(BitNamesError::AuthorizationCountMismatch {
+                inputs: 2,
+                authorizations: 1
+            }))
+        ));
+    }
+
+    #[test]
+    fn lists_keys_registered_without_value() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        assert!(chain.state.registered_without_value().unwrap().is_empty());
+        let valued = chain.register(owner, "valued.com", 1).unwrap();
+        chain.register(owner, "bare.com", 2).unwrap();
+        let set_value = chain.spend_tx(
+            valued,
+            owner,
+            BitNamesOutput::KeyValue {
+                key: key_of("valued.com"),
+                value: Some(hash(&"value").into()),
+            },
+        );
+        chain.connect(vec![set_value]).unwrap();
+        assert_eq!(
+            chain.state.registered_without_value().unwrap(),
+            vec![key_of("bare.com")]
+        );
+    }
+
+    #[test]
+    fn canonical_output_order_is_enforced_when_enabled() {
+        let strict = BitNamesParams {
+            canonical_output_order: true,
+            ..Default::default()
+        };
+        for params in [BitNamesParams::default(), strict] {
+            let enforced = params.canonical_output_order;
+            let mut chain = TestChain::new(params);
+            let owner = chain.addresses[0];
+            let pay = |value| Output {
+                address: owner,
+                content: Content::Value(value),
+            };
+            let funding = chain.deposit(owner, 3);
+            let sorted = chain.sign(vec![funding], vec![pay(1), pay(2)]);
+            chain
+                .state
+                .validate_transaction(&sorted.transaction)
+                .unwrap();
+            let unsorted = chain.sign(vec![funding], vec![pay(2), pay(1)]);
+            let result = chain.state.validate_transaction(&unsorted.transaction);
+            if enforced {
+                assert!(matches!(
+                    result,
+                    Err(Error::BitNames(BitNamesError::NonCanonicalOutputOrder {
+                        vout: 1
+                    }))
+                ));
+            } else {
+                result.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn transactions_see_only_their_own_spent_utxos() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let key = key_of("own.com");
+        let name = chain.register(owner, "own.com", 1).unwrap();
+        let funding = chain.deposit(owner, 1);
+        let other_funding = chain.deposit(owner, 1);
+        let pay = chain.sign(
+            vec![funding],
+            vec![Output {
+                address: owner,
+                content: Content::Value(1),
+            }],
+        );
+        let set_value = |holder| {
+            chain.spend_tx(
+                holder,
+                owner,
+                BitNamesOutput::KeyValue {
+                    key,
+                    value: Some(hash(&"value").into()),
+                },
+            )
+        };
+        let height = chain.height() + 1;
+        let body = Body::new(vec![pay.clone(), set_value(name)], vec![]);
+        chain.state.validate_body(height, &body).unwrap();
+
+        // The name is spent, but by the other transaction.
+        let body = Body::new(vec![set_value(name), set_value(other_funding)], vec![]);
+        assert!(matches!(
+            chain.state.validate_body(height, &body),
+            Err(Error::BitNames(BitNamesError::InvalidKey { key: k })) if k == key
+        ));
+        let body = Body::new(vec![pay, set_value(name)], vec![]);
+        chain.state.connect_body(&body).unwrap();
+    }
+
+    #[test]
+    fn reveals_at_heights_below_the_commitment_are_rejected() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        chain.advance(2);
+        let commitment = chain.commit(owner, "future.com", 1).unwrap();
+        let committed_at = chain.height();
+        let reveal = chain.reveal_tx(commitment, owner, "future.com", 1);
+        let body = Body::new(vec![reveal], vec![]);
+        assert!(matches!(
+            chain.state.validate_body(committed_at - 1, &body),
+            Err(Error::BitNames(BitNamesError::CommitmentFromFuture {
+                commitment_height,
+                height,
+                ..
+            })) if commitment_height == committed_at && height == committed_at - 1
+        ));
+        chain.state.validate_body(committed_at + 1, &body).unwrap();
+    }
+
+    #[test]
+    fn timeline_records_commit_reveal_and_value_heights() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        let key = key_of("life.com");
+        assert_eq!(chain.state.name_timeline(&key).unwrap(), None);
+        let commitment = chain.commit(owner, "life.com", 1).unwrap();
+        assert_eq!(chain.state.name_timeline(&key).unwrap(), None);
+        chain.advance(1);
+        let name = chain.reveal(commitment, owner, "life.com", 1).unwrap();
+        let revealed = NameTimeline {
+            committed: Some(1),
+            revealed: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(chain.state.name_timeline(&key).unwrap(), Some(revealed));
+
+        let set_value = chain.spend_tx(
+            name,
+            owner,
+            BitNamesOutput::KeyValue {
+                key,
+                value: Some(hash(&"value").into()),
+            },
+        );
+        let body = chain.connect(vec![set_value]).unwrap();
+        assert_eq!(
+            chain.state.name_timeline(&key).unwrap(),
+            Some(NameTimeline {
+                value_set: Some(4),
+                ..revealed
+            })
+        );
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(chain.state.name_timeline(&key).unwrap(), Some(revealed));
+    }
+
+    #[test]
+    fn reveal_window_follows_commitment_max_age() {
+        let mut chain = TestChain::new(params(3));
+        let owner = chain.addresses[0];
+        let commitment = chain.commit(owner, "window.com", 1).unwrap();
+        let reveal = chain.reveal_tx(commitment, owner, "window.com", 1);
+        chain.advance(2);
+        chain
+            .state
+            .validate_transaction(&reveal.transaction)
+            .unwrap();
+        chain.advance(1);
+        assert!(matches!(
+            chain.state.validate_transaction(&reveal.transaction),
+            Err(Error::BitNames(BitNamesError::RevealTooLate {
+                late_by: 1,
+                ..
+            }))
+        ));
+        chain.advance(1);
+        assert!(chain.state.get_utxo(&commitment).unwrap().is_none());
+    }
+
+    #[test]
+    fn spending_missing_utxos_is_an_error() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let funding = chain.deposit(owner, 1);
+        let pay = |chain: &TestChain, value| {
+            chain.sign(
+                vec![funding],
+                vec![Output {
+                    address: owner,
+                    content: Content::Value(value),
+                }],
+            )
+        };
+        let first = pay(&chain, 1);
+        let second = pay(&chain, 0);
+        chain.connect(vec![first]).unwrap();
+        assert!(matches!(
+            chain.state.validate_transaction(&second.transaction),
+            Err(Error::BitNames(BitNamesError::UtxoNotFound { outpoint })) if outpoint == funding
+        ));
+        let body = Body::new(vec![second], vec![]);
+        assert!(matches!(
+            chain.state.validate_body(chain.height() + 1, &body),
+            Err(Error::BitNames(BitNamesError::UtxoNotFound { outpoint })) if outpoint == funding
+        ));
+        assert!(matches!(
+            chain.state.connect_body(&body),
+            Err(Error::BitNames(BitNamesError::UtxoNotFound { .. }))
+        ));
+    }
+
+    #[test]
+    fn reserved_names_cant_be_revealed() {
+        let mut chain = TestChain::new(BitNamesParams {
+            reserved_names: vec!["Root.example.".into()],
+            commitment_max_age: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        let commitment = chain.commit(owner, "root.example", 1).unwrap();
+        assert!(matches!(
+            chain.reveal(commitment, owner, "root.example", 1),
+            Err(Error::BitNames(BitNamesError::NameReserved { key }))
+                if key == key_of("root.example")
+        ));
+        assert_eq!(
+            chain.state.get_owner(&key_of("root.example")).unwrap(),
+            None
+        );
+        chain.register(owner, "leaf.root.example", 2).unwrap();
+    }
+
+    #[test]
+    fn keys_are_indexed_by_value() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let shared: Value = hash(&"shared").into();
+        let set = |chain: &TestChain, holder, name: &str, value| {
+            chain.spend_tx(
+                holder,
+                owner,
+                BitNamesOutput::KeyValue {
+                    key: key_of(name),
+                    value: Some(value),
+                },
+            )
+        };
+        let first = chain.register(owner, "first.com", 1).unwrap();
+        let second = chain.register(owner, "second.com", 2).unwrap();
+        let set_first = set(&chain, first, "first.com", shared);
+        chain.connect(vec![set_first]).unwrap();
+        let set_second = set(&chain, second, "second.com", shared);
+        let second = OutPoint::Regular {
+            txid: set_second.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![set_second]).unwrap();
+        let both = vec![key_of("first.com"), key_of("second.com")];
+        assert_eq!(chain.state.get_keys_for_value(&shared).unwrap(), both);
+
+        let other: Value = hash(&"other").into();
+        let change = set(&chain, second, "second.com", other);
+        let body = chain.connect(vec![change]).unwrap();
+        assert_eq!(
+            chain.state.get_keys_for_value(&shared).unwrap(),
+            vec![key_of("first.com")]
+        );
+        assert_eq!(
+            chain.state.get_keys_for_value(&other).unwrap(),
+            vec![key_of("second.com")]
+        );
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(chain.state.get_keys_for_value(&shared).unwrap(), both);
+        assert!(chain.state.get_keys_for_value(&other).unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshots_round_trip_and_check_their_version() {
+        let mut source = TestChain::new(BitNamesParams::default());
+        let owner = source.addresses[0];
+        source.register(owner, "snap.com", 1).unwrap();
+        source.deposit(owner, 5);
+        let mut snapshot = vec![];
+        source.state.export_snapshot(&mut snapshot).unwrap();
+
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let mut outdated = snapshot.clone();
+        outdated[0] = SNAPSHOT_VERSION - 1;
+        assert!(matches!(
+            chain.state.import_snapshot(&mut &outdated[..]),
+            Err(Error::BitNames(BitNamesError::SnapshotVersionMismatch {
+                expected: SNAPSHOT_VERSION,
+                found,
+            })) if found == SNAPSHOT_VERSION - 1
+        ));
+        chain.state.import_snapshot(&mut &snapshot[..]).unwrap();
+        assert_eq!(chain.height(), source.height());
+        assert_eq!(
+            chain.state.compute_state_root().unwrap(),
+            source.state.compute_state_root().unwrap()
+        );
+        assert_eq!(
+            chain.state.get_owner(&key_of("snap.com")).unwrap(),
+            Some(owner)
+        );
+        chain.state.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn oversized_proofs_are_rejected() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        chain.register(owner, "deep.com", 1).unwrap();
+        let key = key_of("deep.com");
+        let root = chain.state.name_root().unwrap();
+        let mut proof = chain.state.prove_key(&key).unwrap();
+        assert!(verify_key_proof(&root, &key, &proof));
+        proof.path = vec![MerkleStep::Left(hash(&"padding")); MAX_PROOF_DEPTH + 1];
+        assert!(!verify_key_proof(&root, &key, &proof));
+
+        let root = chain.state.utxo_root().unwrap();
+        let mut proof = chain.state.address_proof(&owner).unwrap();
+        assert!(proof.verify(&root).unwrap());
+        proof.utxos[0].2 = vec![MerkleStep::Right(hash(&"padding")); MAX_PROOF_DEPTH + 1];
+        assert!(matches!(
+            proof.verify(&root),
+            Err(ProofError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn swaps_have_to_carry_on_every_spent_key() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let (a, b) = (key_of("a.com"), key_of("b.com"));
+        let a_name = chain.register(alice, "a.com", 1).unwrap();
+        let b_name = chain.register(bob, "b.com", 2).unwrap();
+        let transfer = |key, to| custom(to, BitNamesOutput::Transfer { key, value: None });
+
+        let dropping = chain.sign(vec![a_name, b_name], vec![transfer(a, bob)]);
+        assert!(matches!(
+            chain.state.validate_transaction(&dropping.transaction),
+            Err(Error::BitNames(BitNamesError::TransferDropsKey { key })) if key == b
+        ));
+        let to_self = chain.sign(vec![a_name], vec![transfer(a, alice)]);
+        assert!(matches!(
+            chain.state.validate_transaction(&to_self.transaction),
+            Err(Error::BitNames(BitNamesError::SelfTransfer { key })) if key == a
+        ));
+
+        let swap = chain.sign(
+            vec![a_name, b_name],
+            vec![transfer(a, bob), transfer(b, alice)],
+        );
+        chain.connect(vec![swap]).unwrap();
+        assert_eq!(chain.state.owner_of(&a).unwrap(), Some(bob));
+        assert_eq!(chain.state.owner_of(&b).unwrap(), Some(alice));
+    }
+
+    #[test]
+    fn commitments_lock_the_minimum_fee_for_their_reveal() {
+        let mut chain = TestChain::new(BitNamesParams {
+            min_commitment_fee: 3,
+            commitment_max_age: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        let cheap = chain.commit_tx(owner, "locked.com", 1);
+        assert!(matches!(
+            chain.state.validate_transaction(&cheap.transaction),
+            Err(Error::BitNames(BitNamesError::CommitmentFeeTooLow {
+                fee: 0,
+                min: 3,
+                ..
+            }))
+        ));
+
+        let funding = chain.deposit(owner, 3);
+        let commit = chain.sign(
+            vec![funding],
+            vec![custom(
+                owner,
+                BitNamesOutput::Commitment {
+                    commitment: blake2b_hmac(&key_of("locked.com"), 1),
+                    fee: 3,
+                },
+            )],
+        );
+        let commitment = OutPoint::Regular {
+            txid: commit.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![commit]).unwrap();
+
+        // Taking the locked fee back as change leaves the reveal unpaid.
+        let reveal = chain.reveal_tx(commitment, owner, "locked.com", 1);
+        let mut outputs = reveal.transaction.outputs.clone();
+        outputs.push(Output {
+            address: owner,
+            content: Content::Value(3),
+        });
+        let reclaiming = chain.sign(vec![commitment], outputs);
+        assert!(matches!(
+            chain.state.validate_transaction(&reclaiming.transaction),
+            Err(Error::BitNames(BitNamesError::InsufficientDeposit {
+                required: 3,
+                provided: 0
+            }))
+        ));
+        assert_eq!(
+            chain
+                .state
+                .validate_transaction(&reveal.transaction)
+                .unwrap(),
+            3
+        );
+        chain.connect(vec![reveal]).unwrap();
+    }
+
+    #[test]
+    fn growth_history_tracks_connected_blocks() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        assert!(chain.state.growth_history(10).unwrap().is_empty());
+        chain.register(owner, "growth.com", 1).unwrap();
+        chain.advance(2);
+        let heights = |history: Vec<(u32, i64)>| -> Vec<u32> {
+            history.into_iter().map(|(height, _)| height).collect()
+        };
+        assert_eq!(
+            heights(chain.state.growth_history(10).unwrap()),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(heights(chain.state.growth_history(2).unwrap()), [3, 4]);
+        // The first block creates pages in databases that were empty.
+        assert!(chain.state.growth_history(10).unwrap()[0].1 > 0);
+
+        let body = chain.connect(vec![]).unwrap();
+        chain.state.disconnect_body(&body).unwrap();
+        assert_eq!(heights(chain.state.growth_history(1).unwrap()), [4]);
+    }
+
+    #[test]
+    fn burned_keys_are_reserved_for_good() {
+        let mut chain = TestChain::new(BitNamesParams {
+            min_burn_reserve: 5,
+            commitment_max_age: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        let key = key_of("burnt.com");
+        let burn = |chain: &mut TestChain, key, amount| {
+            let funding = chain.deposit(owner, amount);
+            chain.sign(
+                vec![funding],
+                vec![custom(owner, BitNamesOutput::BurnReserve { key, amount })],
+            )
+        };
+        let cheap = burn(&mut chain, key, 4);
+        assert!(matches!(
+            chain.state.validate_transaction(&cheap.transaction),
+            Err(Error::BitNames(BitNamesError::BurnTooLow {
+                amount: 4,
+                min: 5,
+                ..
+            }))
+        ));
+        chain.register(owner, "taken.com", 1).unwrap();
+        let registered = burn(&mut chain, key_of("taken.com"), 5);
+        assert!(matches!(
+            chain.state.validate_transaction(&registered.transaction),
+            Err(Error::BitNames(BitNamesError::BurnOfRegisteredKey { .. }))
+        ));
+
+        let commitment = chain.commit(owner, "burnt.com", 1).unwrap();
+        let burnt = burn(&mut chain, key, 5);
+        let burn_outpoint = OutPoint::Regular {
+            txid: burnt.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![burnt]).unwrap();
+        // Burnt value is gone rather than held by a utxo.
+        assert!(chain.state.get_utxo(&burn_outpoint).unwrap().is_none());
+        let again = burn(&mut chain, key, 5);
+        assert!(matches!(
+            chain.state.validate_transaction(&again.transaction),
+            Err(Error::BitNames(BitNamesError::NameReserved { .. }))
+        ));
+        assert!(matches!(
+            chain.reveal(commitment, owner, "burnt.com", 1),
+            Err(Error::BitNames(BitNamesError::NameReserved { key: k })) if k == key
+        ));
+    }
+
+    #[test]
+    fn preview_diff_leaves_the_state_untouched() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        let key = key_of("preview.com");
+        let commitment = chain.commit(owner, "preview.com", 1).unwrap();
+        let reveal = chain.reveal_tx(commitment, owner, "preview.com", 1);
+        let name = OutPoint::Regular {
+            txid: reveal.transaction.txid(),
+            vout: 0,
+        };
+        let body = Body::new(vec![reveal], vec![]);
+        let root = chain.state.compute_state_root().unwrap();
+
+        let diff = chain.state.preview_diff(&body).unwrap();
+        assert_eq!(diff.names_added, vec![(key, None)]);
+        assert_eq!(
+            diff.utxos_added
+                .iter()
+                .map(|(outpoint, _)| *outpoint)
+                .collect::<Vec<_>>(),
+            vec![name]
+        );
+        assert_eq!(
+            diff.utxos_removed
+                .iter()
+                .map(|(outpoint, _)| *outpoint)
+                .collect::<Vec<_>>(),
+            vec![commitment]
+        );
+        assert_eq!(chain.state.compute_state_root().unwrap(), root);
+        assert_eq!(chain.state.get_owner(&key).unwrap(), None);
+
+        chain.state.connect_body(&body).unwrap();
+        assert_eq!(chain.state.get_owner(&key).unwrap(), Some(owner));
+        assert!(matches!(
+            chain.state.preview_diff(&body),
+            Err(Error::BitNames(BitNamesError::UtxoNotFound { .. }))
+        ));
+    }
+
+    /// Commits to the plain hash of key and salt, standing in for a scheme
+    /// other than the default.
+    struct PlainHash;
+
+    impl CommitmentScheme for PlainHash {
+        fn commit(&self, key: &Key, salt: u64) -> Commitment {
+            hash(&(key, salt)).into()
+        }
+    }
+
+    #[test]
+    fn reveals_are_checked_with_the_configured_scheme() {
+        let (env, dir) = temp_env();
+        let mut chain = TestChain::open(&env, dir, params(10));
+        chain.state = BitNamesState::new(&env, params(10))
+            .unwrap()
+            .with_commitment_scheme(Box::new(PlainHash));
+        let owner = chain.addresses[0];
+        let key = key_of("scheme.com");
+
+        let default_commitment = chain.commit(owner, "scheme.com", 1).unwrap();
+        assert!(matches!(
+            chain.reveal(default_commitment, owner, "scheme.com", 1),
+            Err(Error::BitNames(BitNamesError::InvalidNameCommitment { .. }))
+        ));
+
+        let funding = chain.deposit(owner, 1);
+        let commit = chain.sign(
+            vec![funding],
+            vec![custom(
+                owner,
+                BitNamesOutput::Commitment {
+                    commitment: PlainHash.commit(&key, 2),
+                    fee: 0,
+                },
+            )],
+        );
+        let commitment = OutPoint::Regular {
+            txid: commit.transaction.txid(),
+            vout: 0,
+        };
+        chain.connect(vec![commit]).unwrap();
+        chain.reveal(commitment, owner, "scheme.com", 2).unwrap();
+        assert_eq!(chain.state.get_owner(&key).unwrap(), Some(owner));
+    }
+
+    #[test]
+    fn reused_commitments_only_reveal_their_recorded_keys() {
+        let mut chain = TestChain::new(params(10));
+        let owner = chain.addresses[0];
+        let names = ["kept.com", "other.com"];
+        let keys: Vec<Key> = names.iter().map(|name| key_of(name)).collect();
+        let leaves: Vec<Hash> = keys.iter().copied().map(Hash::from).collect();
+        let batch = blake2b_batch_commit(&keys, 7);
+        let commit = |chain: &mut TestChain| {
+            let funding = chain.deposit(owner, 1);
+            let commit = chain.sign(
+                vec![funding],
+                vec![custom(
+                    owner,
+                    BitNamesOutput::Commitment {
+                        commitment: batch,
+                        fee: 0,
+                    },
+                )],
+            );
+            let txid = commit.transaction.txid();
+            chain.connect(vec![commit]).unwrap();
+            OutPoint::Regular { txid, vout: 0 }
+        };
+        let reveal = |chain: &TestChain, commitment, index: usize| {
+            chain.sign(
+                vec![commitment],
+                vec![custom(
+                    owner,
+                    BitNamesOutput::Reveal {
+                        salt: 7,
+                        key: keys[index],
+                        name: Some(names[index].into()),
+                        path: merkle_path(&leaves, index),
+                        value: None,
+                    },
+                )],
+            )
+        };
+
+        let first = commit(&mut chain);
+        let kept = reveal(&chain, first, 0);
+        chain.connect(vec![kept]).unwrap();
+
+        // The same commitment made again can't be opened to the key the
+        // first reveal left out.
+        let again = commit(&mut chain);
+        let other = reveal(&chain, again, 1);
+        assert!(matches!(
+            chain.state.validate_transaction(&other.transaction),
+            Err(Error::BitNames(BitNamesError::CommitmentKeyMismatch {
+                commitment,
+                key,
+                recorded,
+            })) if commitment == batch && key == keys[1] && recorded == vec![keys[0]]
+        ));
+        assert_eq!(chain.state.get_owner(&keys[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn commitment_outpoint_is_dropped_when_it_expires() {
+        let mut chain = TestChain::new(params(2));
+        let owner = chain.addresses[0];
+        let commitment = blake2b_hmac(&key_of("lapsed.com"), 1);
+        // Committed at 1 and due to be swept at 4.
+        let outpoint = chain.commit(owner, "lapsed.com", 1).unwrap();
+        assert_eq!(
+            chain.state.get_commitment_outpoint(&commitment).unwrap(),
+            Some(outpoint)
+        );
+        assert_eq!(
+            chain
+                .state
+                .get_commitment_outpoint(&blake2b_hmac(&key_of("lapsed.com"), 2))
+                .unwrap(),
+            None
+        );
+
+        chain.advance(2);
+        assert_eq!(
+            chain.state.get_commitment_outpoint(&commitment).unwrap(),
+            Some(outpoint)
+        );
+        let sweep = chain.connect(vec![]).unwrap();
+        assert_eq!(chain.height(), 4);
+        assert_eq!(
+            chain.state.get_commitment_outpoint(&commitment).unwrap(),
+            None
+        );
+        chain.state.disconnect_body(&sweep).unwrap();
+        assert_eq!(
+            chain.state.get_commitment_outpoint(&commitment).unwrap(),
+            Some(outpoint)
+        );
+    }
+
+    #[test]
+    fn reveal_with_value_sets_it_and_refunds_the_deposit() {
+        let mut chain = TestChain::new(BitNamesParams {
+            registration_deposit: 5,
+            commitment_max_age: 10,
+            ..Default::default()
+        });
+        let owner = chain.addresses[0];
+        let key = key_of("instant.com");
+        let value: Value = hash(&"value").into();
+        let commitment = chain.commit(owner, "instant.com", 1).unwrap();
+        let outputs = vec![custom(
+            owner,
+            BitNamesOutput::Reveal {
+                salt: 1,
+                key,
+                name: Some("instant.com".into()),
+                path: vec![],
+                value: Some(value),
+            },
+        )];
+
+        // Setting a value doesn't waive the deposit, it only cuts it short.
+        let unfunded = chain.sign(vec![commitment], outputs.clone());
+        assert!(matches!(
+            chain.state.validate_transaction(&unfunded.transaction),
+            Err(Error::BitNames(BitNamesError::InsufficientDeposit {
+                required: 5,
+                provided: 0
+            }))
+        ));
+
+        let events = chain.state.subscribe();
+        let funding = chain.deposit(owner, 5);
+        let reveal = chain.sign(vec![commitment, funding], outputs);
+        let txid = reveal.transaction.txid();
+        chain.connect(vec![reveal]).unwrap();
+        assert_eq!(chain.state.get_value(&key).unwrap(), Some(Some(value)));
+        let refund = chain
+            .state
+            .get_utxo(&OutPoint::Regular { txid, vout: 1 })
+            .unwrap()
+            .unwrap();
+        assert_eq!(refund.address, owner);
+        assert!(matches!(refund.content, Content::Value(5)));
+        let rtxn = chain.state.env.read_txn().unwrap();
+        assert!(chain
+            .state
+            .key_to_deposit
+            .get(&rtxn, &key)
+            .unwrap()
+            .is_none());
+        drop(rtxn);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::NameRegistered { key, height: 2 }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::ValueUpdated {
+                key,
+                value: Some(value),
+                height: 2
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn key_proofs_cover_the_current_value_only() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let owner = chain.addresses[0];
+        let key = key_of("proven.com");
+        chain.register(owner, "other.com", 1).unwrap();
+        let name = chain.register(owner, "proven.com", 2).unwrap();
+        assert!(matches!(
+            chain.state.prove_key(&key_of("absent.com")),
+            Err(Error::BitNames(BitNamesError::KeyNotFound { .. }))
+        ));
+
+        let root = chain.state.name_root().unwrap();
+        let proof = chain.state.prove_key(&key).unwrap();
+        assert_eq!(proof.value, None);
+        assert!(verify_key_proof(&root, &key, &proof));
+        let value: Value = hash(&"value").into();
+        let tampered = KeyProof {
+            value: Some(value),
+            ..proof.clone()
+        };
+        assert!(!verify_key_proof(&root, &key, &tampered));
+
+        let set_value = chain.spend_tx(
+            name,
+            owner,
+            BitNamesOutput::KeyValue {
+                key,
+                value: Some(value),
+            },
+        );
+        chain.connect(vec![set_value]).unwrap();
+        let root = chain.state.name_root().unwrap();
+        assert!(!verify_key_proof(&root, &key, &proof));
+        let proof = chain.state.prove_key(&key).unwrap();
+        assert_eq!(proof.value, Some(value));
+        assert!(verify_key_proof(&root, &key, &proof));
+    }
 }
@@ -0,0 +1,241 @@
+use crate::authorization::address_from_pubkey;
+use crate::hashes::Key;
+use crate::types::*;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use ed25519_dalek::{PublicKey, SecretKey};
+use rand::Rng;
+use sdk_authorization_ed25519_dalek::Keypair;
+use sdk_types::{GetValue as _, Txid};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// On-disk keystore: secret keys encrypted with AES-256-GCM under a key
+/// derived from the passphrase with scrypt.
+#[derive(Serialize, Deserialize)]
+struct Keystore {
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+pub fn save_keypairs(
+    path: &Path,
+    passphrase: &str,
+    keypairs: &HashMap<Address, Keypair>,
+) -> Result<(), WalletError> {
+    let secrets: Vec<[u8; 32]> = keypairs
+        .values()
+        .map(|keypair| keypair.secret.to_bytes())
+        .collect();
+    let plaintext = bincode::serialize(&secrets)?;
+    let mut rng = rand::thread_rng();
+    let salt: [u8; 16] = rng.gen();
+    let nonce: [u8; 12] = rng.gen();
+    let cipher = cipher(passphrase, &salt)?;
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| WalletError::Encryption)?;
+    let keystore = Keystore {
+        salt,
+        nonce,
+        ciphertext,
+    };
+    std::fs::write(path, bincode::serialize(&keystore)?)?;
+    Ok(())
+}
+
+/// Load a keystore written by `save_keypairs`, keyed by the same addresses
+/// `authorize_transaction` looks keypairs up by.
+pub fn load_keypairs(
+    path: &Path,
+    passphrase: &str,
+) -> Result<HashMap<Address, Keypair>, WalletError> {
+    let keystore: Keystore = bincode::deserialize(&std::fs::read(path)?)?;
+    let cipher = cipher(passphrase, &keystore.salt)?;
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&keystore.nonce),
+            keystore.ciphertext.as_slice(),
+        )
+        .map_err(|_| WalletError::WrongPassphrase)?;
+    let secrets: Vec<[u8; 32]> = bincode::deserialize(&plaintext)?;
+    secrets
+        .iter()
+        .map(|secret| {
+            let secret =
+                SecretKey::from_bytes(secret).map_err(|_| WalletError::InvalidSecretKey)?;
+            let public = PublicKey::from(&secret);
+            Ok((address_from_pubkey(&public), Keypair { secret, public }))
+        })
+        .collect()
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, WalletError> {
+    let mut key = [0; 32];
+    scrypt::scrypt(
+        passphrase.as_bytes(),
+        salt,
+        &scrypt::Params::recommended(),
+        &mut key,
+    )
+    .map_err(|_| WalletError::Encryption)?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+/// What a wallet knows about its own addresses, built up from confirmed
+/// transactions alone so it can be kept without a `BitNamesState`.
+#[derive(Debug, Default)]
+pub struct WalletState {
+    pub addresses: HashSet<Address>,
+    pub utxos: HashMap<OutPoint, Output>,
+    /// Keys held by one of `utxos`.
+    pub names: HashSet<Key>,
+    /// Height of the last transaction applied.
+    pub height: u32,
+}
+
+impl WalletState {
+    pub fn new(addresses: HashSet<Address>) -> Self {
+        Self {
+            addresses,
+            ..Default::default()
+        }
+    }
+
+    /// Apply a transaction confirmed at `height`. Inputs spending outputs
+    /// the wallet doesn't know about are ignored.
+    pub fn apply(&mut self, transaction: &Transaction, txid: Txid, height: u32) {
+        for input in &transaction.inputs {
+            let Some(output) = self.utxos.remove(input) else {
+                continue;
+            };
+            if let Some(key) = output_key(&output) {
+                self.names.remove(&key);
+            }
+        }
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            if !self.addresses.contains(&output.address) {
+                continue;
+            }
+            if let Content::Custom(
+                BitNamesOutput::ContextBinding(_) | BitNamesOutput::BurnReserve { .. },
+            ) = output.content
+            {
+                continue;
+            }
+            if let Some(key) = output_key(output) {
+                self.names.insert(key);
+            }
+            let outpoint = OutPoint::Regular {
+                txid,
+                vout: vout as u32,
+            };
+            self.utxos.insert(outpoint, output.clone());
+        }
+        self.height = height;
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.utxos.values().map(|output| output.get_value()).sum()
+    }
+}
+
+fn output_key(output: &Output) -> Option<Key> {
+    match &output.content {
+        Content::Custom(output) => output.key(),
+        Content::Value(_) => None,
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WalletError {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("malformed keystore")]
+    Format(#[from] bincode::Error),
+    #[error("wrong passphrase or corrupted keystore")]
+    WrongPassphrase,
+    #[error("keystore contains an invalid secret key")]
+    InvalidSecretKey,
+    #[error("failed to encrypt keystore")]
+    Encryption,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::BitNamesParams;
+    use crate::testing::*;
+
+    #[test]
+    fn keystore_round_trips_under_its_passphrase_only() {
+        let chain = TestChain::new(BitNamesParams::default());
+        let dir = TempDir::new();
+        let path = dir.0.join("keystore");
+        save_keypairs(&path, "correct horse", &chain.keypairs).unwrap();
+
+        let loaded = load_keypairs(&path, "correct horse").unwrap();
+        assert_eq!(loaded.len(), chain.keypairs.len());
+        for (address, keypair) in &chain.keypairs {
+            assert_eq!(loaded[address].to_bytes(), keypair.to_bytes());
+        }
+        assert!(matches!(
+            load_keypairs(&path, "battery staple"),
+            Err(WalletError::WrongPassphrase)
+        ));
+        let keystore = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &keystore[..10]).unwrap();
+        assert!(matches!(
+            load_keypairs(&path, "correct horse"),
+            Err(WalletError::Format(_))
+        ));
+    }
+
+    /// Connect `transaction` and apply it to `wallet`, returning its vout 0.
+    fn apply(
+        wallet: &mut WalletState,
+        chain: &mut TestChain,
+        transaction: AuthorizedTransaction,
+    ) -> OutPoint {
+        let txid = transaction.transaction.txid();
+        chain.connect(vec![transaction.clone()]).unwrap();
+        wallet.apply(&transaction.transaction, txid, chain.height());
+        OutPoint::Regular { txid, vout: 0 }
+    }
+
+    #[test]
+    fn wallet_state_follows_its_own_outputs() {
+        let mut chain = TestChain::new(BitNamesParams::default());
+        let (alice, bob) = (chain.addresses[0], chain.addresses[1]);
+        let key = key_of("held.com");
+        let mut wallet = WalletState::new(HashSet::from([alice]));
+        // The deposit funding the commitment isn't known, so spending it is
+        // ignored.
+        let commit = chain.commit_tx(alice, "held.com", 1);
+        let commitment = apply(&mut wallet, &mut chain, commit);
+        assert_eq!(wallet.utxos.len(), 1);
+        let reveal = chain.reveal_tx(commitment, alice, "held.com", 1);
+        let name = apply(&mut wallet, &mut chain, reveal);
+        assert_eq!(wallet.names, HashSet::from([key]));
+        assert_eq!(wallet.utxos.keys().collect::<Vec<_>>(), vec![&name]);
+        assert_eq!(wallet.height, chain.height());
+
+        let funding = chain.deposit(bob, 7);
+        let payment = chain.sign(
+            vec![funding],
+            vec![Output {
+                address: alice,
+                content: Content::Value(7),
+            }],
+        );
+        apply(&mut wallet, &mut chain, payment);
+        assert_eq!(wallet.balance(), 7);
+
+        let transfer = chain.spend_tx(name, bob, BitNamesOutput::Transfer { key, value: None });
+        apply(&mut wallet, &mut chain, transfer);
+        assert!(wallet.names.is_empty());
+        assert_eq!(wallet.utxos.len(), 1);
+    }
+}